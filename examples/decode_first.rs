@@ -19,15 +19,14 @@ fn main() {
     // Compute and print counts and next_code according to RFC algorithm for fixed table
     let mut counts = [0usize; 16];
     // Build lengths array for fixed table
-    let mut lengths: Vec<u8> = Vec::new();
-    lengths.resize(288, 0u8);
-    for i in 0..=143 { lengths[i] = 8; }
-    for i in 144..=255 { lengths[i] = 9; }
-    for i in 256..=279 { lengths[i] = 7; }
-    for i in 280..=287 { lengths[i] = 8; }
+    let mut lengths: Vec<u8> = vec![0u8; 288];
+    lengths[0..=143].fill(8);
+    lengths[144..=255].fill(9);
+    lengths[256..=279].fill(7);
+    lengths[280..=287].fill(8);
     for &l in lengths.iter() { counts[l as usize] += 1; }
     println!("counts[bits 1..=9]:");
-    for bits in 1..=9 { println!("counts[{}] = {}", bits, counts[bits]); }
+    for (bits, count) in counts.iter().enumerate().take(10).skip(1) { println!("counts[{}] = {}", bits, count); }
     let mut next_code = [0u32; 16];
     let mut code = 0u32;
     for bits in 1..=9 {
@@ -38,8 +37,7 @@ fn main() {
 
     // compute canonical codes for each symbol like HuffmanTable::from_lengths
     let max_bits = 9usize;
-    let mut codes_arr: Vec<u32> = Vec::new();
-    codes_arr.resize(max_bits + 1, 0u32);
+    let mut codes_arr: Vec<u32> = vec![0u32; max_bits + 1];
     let mut code = 0u32;
     for bits in 1..=max_bits { code = (code + counts[bits - 1] as u32) << 1; codes_arr[bits] = code; }
     // for a symbol, compute code
@@ -73,8 +71,7 @@ fn main() {
     let table_size = 1<<max_bits;
     let mut table2: Vec<u32> = Vec::new();
     table2.resize(table_size, 0xffffffffu32);
-    let mut codes_arr2: Vec<u32> = Vec::new();
-    codes_arr2.resize(max_bits + 1, 0u32);
+    let mut codes_arr2: Vec<u32> = vec![0u32; max_bits + 1];
     // recompute initial codes
     code = 0u32;
     for bits in 1..=max_bits { code = (code + counts[bits - 1] as u32) << 1; codes_arr2[bits] = code; }
@@ -91,9 +88,9 @@ fn main() {
         };
         let start = (rev as usize) << (max_bits - len_usz);
         let end = start + (1usize << (max_bits - len_usz));
-        for idx in start..end {
-            if table2[idx] != 0xffffffff { println!("collision assigning sym {} at idx {}, currently has sym {}", sym, idx, table2[idx] >> 8); }
-            table2[idx] = ((sym as u32) << 8) | (len as u32);
+        for (idx, entry) in table2.iter_mut().enumerate().take(end).skip(start) {
+            if *entry != 0xffffffff { println!("collision assigning sym {} at idx {}, currently has sym {}", sym, idx, *entry >> 8); }
+            *entry = ((sym as u32) << 8) | (len as u32);
         }
     }
 
@@ -0,0 +1,179 @@
+// Checksums used by the container formats wrapped around DEFLATE streams, shared by the
+// encoders and the decoders' trailer checks.
+
+/// Largest prime smaller than 65536 (RFC 1950, section 8.2)
+const ADLER_MOD: u32 = 65521;
+
+/// Largest number of bytes that can be summed before `b` may overflow a u32:
+/// 255 * n * (n + 1) / 2 + (n + 1) * (ADLER_MOD - 1) <= 2^32 - 1
+const ADLER_NMAX: usize = 5552;
+
+/// Running Adler-32 checksum as used by the zlib trailer.
+#[derive(Debug, Clone)]
+pub struct Adler32 {
+    a: u32,
+    b: u32,
+}
+
+impl Default for Adler32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Adler32 {
+    /// Checksum of no data (1).
+    pub fn new() -> Self {
+        Adler32 { a: 1, b: 0 }
+    }
+
+    /// Add `data` to the checksum.
+    pub fn update(&mut self, data: &[u8]) {
+        let mut a = self.a;
+        let mut b = self.b;
+        // Sum up to NMAX bytes at a time and only then reduce modulo ADLER_MOD
+        for chunk in data.chunks(ADLER_NMAX) {
+            let mut blocks = chunk.chunks_exact(8);
+            for blk in &mut blocks {
+                a += blk[0] as u32; b += a;
+                a += blk[1] as u32; b += a;
+                a += blk[2] as u32; b += a;
+                a += blk[3] as u32; b += a;
+                a += blk[4] as u32; b += a;
+                a += blk[5] as u32; b += a;
+                a += blk[6] as u32; b += a;
+                a += blk[7] as u32; b += a;
+            }
+            for &byte in blocks.remainder() {
+                a += byte as u32;
+                b += a;
+            }
+            a %= ADLER_MOD;
+            b %= ADLER_MOD;
+        }
+        self.a = a;
+        self.b = b;
+    }
+
+    /// Checksum of the data so far.
+    pub fn finish(&self) -> u32 {
+        (self.b << 16) | self.a
+    }
+}
+
+/// Adler-32 of the concatenation of two pieces of data, from their checksums `adler1` and
+/// `adler2` and the length of the second, as zlib's `adler32_combine`.
+pub fn adler32_combine(adler1: u32, adler2: u32, len2: u64) -> u32 {
+    let m = ADLER_MOD as u64;
+    let rem = len2 % m;
+    let (a1, b1) = ((adler1 & 0xffff) as u64, (adler1 >> 16) as u64);
+    let (a2, b2) = ((adler2 & 0xffff) as u64, (adler2 >> 16) as u64);
+    // The first piece's sum a1 is added into b once for each byte of the second, and the
+    // initial 1 of the second checksum is counted twice in a and len2 times in b
+    let a = (a1 + a2 + m - 1) % m;
+    let b = (b1 + b2 + rem * a1 + m - rem) % m;
+    (b << 16 | a) as u32
+}
+
+/// Reflected CRC-32 polynomial used by gzip, ZIP and PNG (ISO 3309)
+const CRC32_POLY: u32 = 0xEDB8_8320;
+
+const fn make_crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { CRC32_POLY ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+static CRC32_TABLE: [u32; 256] = make_crc32_table();
+
+/// Running CRC-32 checksum as used by the gzip trailer.
+#[derive(Debug, Clone)]
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Crc32 {
+    /// Checksum of no data (0).
+    pub fn new() -> Self {
+        Crc32 { crc: 0xFFFF_FFFF }
+    }
+
+    /// Add `data` to the checksum.
+    pub fn update(&mut self, data: &[u8]) {
+        let mut c = self.crc;
+        for &byte in data {
+            c = CRC32_TABLE[((c ^ byte as u32) & 0xff) as usize] ^ (c >> 8);
+        }
+        self.crc = c;
+    }
+
+    /// Checksum of the data so far.
+    pub fn finish(&self) -> u32 {
+        !self.crc
+    }
+}
+
+// Product of two polynomials modulo the CRC polynomial, in the reflected bit order where
+// the top bit is x^0
+const fn multmodp(a: u32, mut b: u32) -> u32 {
+    let mut m = 1u32 << 31;
+    let mut p = 0;
+    loop {
+        if a & m != 0 {
+            p ^= b;
+            if a & (m - 1) == 0 {
+                return p;
+            }
+        }
+        m >>= 1;
+        b = if b & 1 != 0 { (b >> 1) ^ CRC32_POLY } else { b >> 1 };
+    }
+}
+
+// x^(2^k) modulo the CRC polynomial for k = 0..32
+const fn make_x2n_table() -> [u32; 32] {
+    let mut table = [0u32; 32];
+    let mut p = 1u32 << 30; // x^1
+    let mut k = 0;
+    while k < 32 {
+        table[k] = p;
+        p = multmodp(p, p);
+        k += 1;
+    }
+    table
+}
+
+static X2N_TABLE: [u32; 32] = make_x2n_table();
+
+/// CRC-32 of the concatenation of two pieces of data, from their checksums `crc1` and
+/// `crc2` and the length of the second, as zlib's `crc32_combine`. Takes O(log len2) steps.
+pub fn crc32_combine(crc1: u32, crc2: u32, len2: u64) -> u32 {
+    // Shifting crc1 past len2 zero bytes multiplies it by x^(8 * len2)
+    let mut shift = 1u32 << 31; // x^0
+    let mut n = len2;
+    let mut k = 3;
+    while n != 0 {
+        if n & 1 != 0 {
+            shift = multmodp(X2N_TABLE[k & 31], shift);
+        }
+        n >>= 1;
+        k += 1;
+    }
+    multmodp(shift, crc1) ^ crc2
+}
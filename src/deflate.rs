@@ -0,0 +1,148 @@
+#[cfg(feature = "alloc")]
+use alloc::{boxed::Box, vec, vec::Vec};
+
+#[cfg(feature = "alloc")]
+use crate::inflate::StreamState;
+
+pub mod bitwriter;
+pub(crate) mod block;
+pub mod huffman;
+mod lz77;
+#[cfg(feature = "alloc")]
+mod optimal;
+pub mod stream;
+
+#[cfg(feature = "alloc")]
+pub use optimal::{deflate_raw_optimal, deflate_zlib_optimal};
+pub use stream::{Deflater, Flush, DEFAULT_BLOCK_TOKENS, DEFAULT_HASH};
+
+/// How matches are searched for, as zlib's `strategy` parameter: the default suits most
+/// data, the others suit data whose redundancy is mostly not in repeated strings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strategy {
+    /// Matching as the level sets it
+    #[default]
+    Default,
+    /// Drop matches of 5 bytes or less at the lazy levels (4-9), leaving small varying
+    /// values such as filtered image rows or sensor deltas to the Huffman codes
+    Filtered,
+    /// No matches, only Huffman coding of literals
+    HuffmanOnly,
+    /// Only runs of one repeated byte (distance 1), as in bitmaps
+    Rle,
+    /// No dynamic Huffman codes, which saves their header on short data
+    Fixed,
+}
+
+// Matches of the minimum length are not worth it this far back (zlib's TOO_FAR)
+pub(crate) const TOO_FAR: usize = 4096;
+
+/// Match search effort per level, as in zlib's configuration table.
+pub(crate) struct Config {
+    /// Search only a quarter of the chain once the previous match is this long
+    pub good_length: usize,
+    /// Lazy levels: don't look for a better match after one this long.
+    /// Greedy levels: longest match whose positions are all added to the hash chains.
+    pub max_lazy: usize,
+    /// Stop searching once a match this long is found
+    pub nice_length: usize,
+    /// Hash chain links followed per search
+    pub max_chain: usize,
+}
+
+const fn config(good_length: usize, max_lazy: usize, nice_length: usize, max_chain: usize) -> Config {
+    Config { good_length, max_lazy, nice_length, max_chain }
+}
+
+pub(crate) const CONFIGS: [Config; 10] = [
+    config(0, 0, 0, 0), // stored blocks only
+    config(4, 4, 8, 4), // greedy
+    config(4, 5, 16, 8),
+    config(4, 6, 32, 32),
+    config(4, 4, 16, 16), // lazy
+    config(8, 16, 32, 32),
+    config(8, 16, 128, 128),
+    config(8, 32, 128, 256),
+    config(32, 128, 258, 1024),
+    config(32, 258, 258, 4096),
+];
+
+// Levels from here on defer each match by one byte to see if the next one is longer
+pub(crate) const FIRST_LAZY_LEVEL: usize = 4;
+
+/// Compress `data` into a raw DEFLATE (RFC 1951) stream.
+///
+/// `level` follows zlib: 0 writes stored blocks only, 1 is fastest and 9 compresses
+/// best; values above 9 are treated as 9.
+#[cfg(feature = "alloc")]
+pub fn deflate_raw(data: &[u8], level: u8) -> Vec<u8> {
+    deflate_all(Box::new(Deflater::new_raw(level)), data)
+}
+
+/// Compress `data` into a zlib (RFC 1950) stream with a 32 KiB window, readable by `inflate_zlib`.
+#[cfg(feature = "alloc")]
+pub fn deflate_zlib(data: &[u8], level: u8) -> Vec<u8> {
+    deflate_all(Box::new(Deflater::new_zlib(level)), data)
+}
+
+/// Compress `data` into a zlib stream against the preset dictionary `dict`: matches may
+/// refer to its last 32 KiB as if it preceded the data. The header has FDICT set and the
+/// dictionary's Adler-32 as DICTID, so `inflate_zlib_with_dictionary` or zlib given the
+/// same dictionary can decode it.
+#[cfg(feature = "alloc")]
+pub fn deflate_zlib_with_dictionary(data: &[u8], dict: &[u8], level: u8) -> Vec<u8> {
+    let mut deflater = Box::new(Deflater::new_zlib(level));
+    deflater.set_dictionary(dict);
+    deflate_all(deflater, data)
+}
+
+/// Compress `data` into a raw DEFLATE stream as `deflate_raw` does, with a `Strategy`.
+#[cfg(feature = "alloc")]
+pub fn deflate_raw_with_strategy(data: &[u8], level: u8, strategy: Strategy) -> Vec<u8> {
+    let mut deflater = Box::new(Deflater::new_raw(level));
+    deflater.set_strategy(strategy);
+    deflate_all(deflater, data)
+}
+
+/// Compress `data` into a zlib stream as `deflate_zlib` does, with a `Strategy`. The
+/// header's FLEVEL is set as zlib sets it: 0 for `HuffmanOnly`, `Rle` and `Fixed`.
+#[cfg(feature = "alloc")]
+pub fn deflate_zlib_with_strategy(data: &[u8], level: u8, strategy: Strategy) -> Vec<u8> {
+    let mut deflater = Box::new(Deflater::new_zlib(level));
+    deflater.set_strategy(strategy);
+    deflate_all(deflater, data)
+}
+
+// Run a whole input through `deflater`, growing the output as needed
+#[cfg(feature = "alloc")]
+pub(crate) fn deflate_all(mut deflater: Box<Deflater>, data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0; data.len() / 2 + 64];
+    let mut in_pos = 0;
+    let mut out_pos = 0;
+    loop {
+        let status = deflater.compress(&data[in_pos..], &mut out[out_pos..], Flush::Finish);
+        in_pos += status.consumed;
+        out_pos += status.produced;
+        if status.state == StreamState::Done {
+            out.truncate(out_pos);
+            return out;
+        }
+        out.resize(out.len() * 2, 0);
+    }
+}
+
+// CMF and FLG: DEFLATE with the window size as CINFO, FDICT if a preset dictionary is
+// used, FLEVEL from the level and strategy as zlib sets it
+pub(crate) fn zlib_header(level: u8, strategy: Strategy, window: usize, fdict: bool) -> [u8; 2] {
+    let cmf = ((window.trailing_zeros() - 8) << 4) as u8 | 8;
+    let flevel = match (strategy, level) {
+        (Strategy::HuffmanOnly | Strategy::Rle | Strategy::Fixed, _) => 0,
+        (_, 0 | 1) => 0,
+        (_, 2..=5) => 1,
+        (_, 6) => 2,
+        _ => 3,
+    };
+    let flg = flevel << 6 | (fdict as u8) << 5;
+    let fcheck = 31 - ((cmf as u16) << 8 | flg as u16) % 31;
+    [cmf, flg | fcheck as u8]
+}
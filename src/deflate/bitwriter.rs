@@ -0,0 +1,88 @@
+/// LSB-first bit writer into a fixed `N`-byte buffer, the counterpart of `BitReader`.
+///
+/// Bits collect in a 64-bit buffer and are stored four bytes at a time; finished bytes
+/// are taken out with `drain_into`. Writers check `room` first.
+pub struct BitWriter<const N: usize> {
+    buf: [u8; N],
+    start: usize,
+    end: usize,
+    bit_buf: u64,
+    bit_count: u32,
+}
+
+impl<const N: usize> BitWriter<N> {
+    pub const fn new() -> Self {
+        BitWriter { buf: [0; N], start: 0, end: 0, bit_buf: 0, bit_count: 0 }
+    }
+
+    /// Append the low `n` bits of `value` (n at most 32).
+    #[inline]
+    pub fn write_bits(&mut self, value: u32, n: u32) {
+        debug_assert!(n <= 32 && (n == 32 || value >> n == 0));
+        self.bit_buf |= (value as u64) << self.bit_count;
+        self.bit_count += n;
+        if self.bit_count >= 32 {
+            self.buf[self.end..self.end + 4].copy_from_slice(&(self.bit_buf as u32).to_le_bytes());
+            self.end += 4;
+            self.bit_buf >>= 32;
+            self.bit_count -= 32;
+        }
+    }
+
+    /// Pad with zero bits up to the next byte boundary.
+    pub fn align_to_byte(&mut self) {
+        while self.bit_count > 0 {
+            self.buf[self.end] = self.bit_buf as u8;
+            self.end += 1;
+            self.bit_buf >>= 8;
+            self.bit_count = self.bit_count.saturating_sub(8);
+        }
+        self.bit_buf = 0;
+    }
+
+    /// Append whole bytes; the writer must be byte aligned and have room for them.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        debug_assert!(self.bit_count == 0, "write_bytes needs a byte-aligned writer");
+        self.buf[self.end..self.end + bytes.len()].copy_from_slice(bytes);
+        self.end += bytes.len();
+    }
+
+    /// Bytes of bits or whole bytes that can still be written before draining. Four
+    /// bytes are held back for the bits already buffered.
+    pub fn room(&self) -> usize {
+        (N - self.end).saturating_sub(4)
+    }
+
+    /// Bits already used of the current partial byte
+    pub fn bit_offset(&self) -> u32 {
+        self.bit_count % 8
+    }
+
+    /// True when no finished bytes are waiting to be drained
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Move finished bytes to `out`, returning how many were written.
+    pub fn drain_into(&mut self, out: &mut [u8]) -> usize {
+        let n = out.len().min(self.end - self.start);
+        out[..n].copy_from_slice(&self.buf[self.start..self.start + n]);
+        self.start += n;
+        // Keep the free space in one piece at the end
+        if self.start == self.end {
+            self.start = 0;
+            self.end = 0;
+        } else if self.start > 0 {
+            self.buf.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+        n
+    }
+}
+
+impl<const N: usize> Default for BitWriter<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,463 @@
+// Block emission: collects LZ77 tokens with their symbol frequencies and writes them as
+// whichever of a stored, fixed or dynamic Huffman block comes out smallest.
+
+use crate::deflate::bitwriter::BitWriter;
+use crate::deflate::huffman::{build_lengths, codes_from_lengths, rle_code_lengths};
+use crate::deflate::lz77::MIN_MATCH;
+use crate::inflate::huffman::MAX_CODE_BITS;
+use crate::inflate::{CODE_LENGTH_ORDER, DIST_BASE, DIST_EXTRA, LENGTH_BASE, LENGTH_EXTRA};
+
+/// Literal/length symbols a block may use (286 and 287 are reserved)
+pub(crate) const LITLEN_CODES: usize = 286;
+pub(crate) const DIST_CODES: usize = 30;
+const CODELEN_CODES: usize = 19;
+/// Longest code of the code length alphabet (3-bit lengths in the header)
+const MAX_CODELEN_BITS: usize = 7;
+pub(crate) const END_OF_BLOCK: usize = 256;
+/// Largest stored block payload
+const MAX_STORED: usize = 65535;
+
+// Length code (0-based, symbol - 257) of each match length 0..=258
+const fn make_length_codes() -> [u8; 259] {
+    let mut table = [0u8; 259];
+    let mut code = 0;
+    while code < 28 {
+        let mut j = 0;
+        while j < 1 << LENGTH_EXTRA[code] {
+            table[LENGTH_BASE[code] as usize + j] = code as u8;
+            j += 1;
+        }
+        code += 1;
+    }
+    // 258 also fits code 27 plus extra bits 31, but has its own code
+    table[258] = 28;
+    table
+}
+
+// Distance code of distance - 1 for distances up to 256, then of (distance - 1) >> 7
+// at offset 256, as in zlib's _dist_code
+const fn make_dist_codes() -> [u8; 512] {
+    let mut table = [0u8; 512];
+    let mut code = 0;
+    while code < 30 {
+        let mut j = 0;
+        while j < 1 << DIST_EXTRA[code] {
+            let d = DIST_BASE[code] as usize - 1 + j;
+            if d < 256 {
+                table[d] = code as u8;
+            } else {
+                table[256 + (d >> 7)] = code as u8;
+            }
+            j += 1;
+        }
+        code += 1;
+    }
+    table
+}
+
+static LENGTH_CODES: [u8; 259] = make_length_codes();
+static DIST_CODES_TABLE: [u8; 512] = make_dist_codes();
+
+#[inline]
+pub(crate) fn length_code(length: usize) -> usize {
+    LENGTH_CODES[length] as usize
+}
+
+#[inline]
+pub(crate) fn dist_code(distance: usize) -> usize {
+    let d = distance - 1;
+    if d < 256 { DIST_CODES_TABLE[d] as usize } else { DIST_CODES_TABLE[256 + (d >> 7)] as usize }
+}
+
+/// Up to `TOKENS` tokens of the block being collected, with symbol frequencies kept up
+/// to date.
+///
+/// Stored as in zlib's symbol buffer: a distance (0 for a literal) and a literal byte or
+/// match length minus 3, three bytes per token.
+pub(crate) struct Block<const TOKENS: usize> {
+    dist: [u16; TOKENS],
+    lc: [u8; TOKENS],
+    len: usize,
+    litlen_freq: [u32; LITLEN_CODES],
+    dist_freq: [u32; DIST_CODES],
+}
+
+impl<const TOKENS: usize> Block<TOKENS> {
+    pub const fn new() -> Self {
+        Block { dist: [0; TOKENS], lc: [0; TOKENS], len: 0, litlen_freq: EMPTY_LITLEN_FREQ, dist_freq: [0; DIST_CODES] }
+    }
+
+    #[inline]
+    pub fn push_literal(&mut self, byte: u8) {
+        self.dist[self.len] = 0;
+        self.lc[self.len] = byte;
+        self.len += 1;
+        self.litlen_freq[byte as usize] += 1;
+    }
+
+    #[inline]
+    pub fn push_match(&mut self, length: usize, distance: usize) {
+        self.dist[self.len] = distance as u16;
+        self.lc[self.len] = (length - MIN_MATCH) as u8;
+        self.len += 1;
+        self.litlen_freq[257 + length_code(length)] += 1;
+        self.dist_freq[dist_code(distance)] += 1;
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == TOKENS
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.litlen_freq = EMPTY_LITLEN_FREQ;
+        self.dist_freq = [0; DIST_CODES];
+    }
+
+    pub fn tokens(&self) -> Tokens<'_> {
+        Tokens { dist: &self.dist[..self.len], lc: &self.lc[..self.len], litlen_freq: &self.litlen_freq, dist_freq: &self.dist_freq }
+    }
+}
+
+// Literal/length frequencies of an empty block: just the end-of-block code
+const EMPTY_LITLEN_FREQ: [u32; LITLEN_CODES] = {
+    let mut freq = [0; LITLEN_CODES];
+    freq[END_OF_BLOCK] = 1;
+    freq
+};
+
+/// The tokens of one block in the layout of `Block`, with their symbol frequencies
+/// including the end-of-block code.
+pub(crate) struct Tokens<'a> {
+    pub dist: &'a [u16],
+    pub lc: &'a [u8],
+    pub litlen_freq: &'a [u32; LITLEN_CODES],
+    pub dist_freq: &'a [u32; DIST_CODES],
+}
+
+impl Tokens<'_> {
+    /// Size in bits of the block written the cheapest way from a byte boundary, `raw_len`
+    /// being the bytes it covers.
+    #[cfg(feature = "alloc")]
+    pub fn best_bits(&self, raw_len: usize) -> usize {
+        let (fixed_litlen, fixed_dist) = fixed_lengths();
+        let fixed_bits = 3 + self.coded_bits(&fixed_litlen, &fixed_dist);
+        let header = DynamicHeader::build(self.litlen_freq, self.dist_freq);
+        let dynamic_bits = 3 + header.bits() + self.coded_bits(&header.litlen_lens, &header.dist_lens);
+        stored_bits(0, raw_len).min(fixed_bits).min(dynamic_bits)
+    }
+
+    // Extra bits of all lengths and distances, the same whatever the codes
+    fn extra_bits(&self) -> usize {
+        let lengths: usize = LENGTH_EXTRA.iter().zip(&self.litlen_freq[257..]).map(|(&e, &f)| e as usize * f as usize).sum();
+        let dists: usize = DIST_EXTRA.iter().zip(self.dist_freq).map(|(&e, &f)| e as usize * f as usize).sum();
+        lengths + dists
+    }
+
+    // Bits taken by the tokens and end-of-block code under the given code lengths
+    fn coded_bits(&self, litlen_lens: &[u8], dist_lens: &[u8]) -> usize {
+        let litlen: usize = self.litlen_freq.iter().zip(litlen_lens).map(|(&f, &l)| f as usize * l as usize).sum();
+        let dist: usize = self.dist_freq.iter().zip(dist_lens).map(|(&f, &l)| f as usize * l as usize).sum();
+        litlen + dist + self.extra_bits()
+    }
+}
+
+// Room needed to write a dynamic block header in one go: 17 bits of counts, 19 3-bit
+// code length code lengths, and at most one 7-bit code plus 7 extra bits per code length
+const MAX_HEADER_BYTES: usize = (3 + 14 + 19 * 3 + (LITLEN_CODES + DIST_CODES) * 14).div_ceil(8);
+// Room needed to write one token: 15-bit length code, 5 extra bits, 15-bit distance
+// code, 13 extra bits
+const MAX_TOKEN_BYTES: usize = 6;
+// Stored block header after padding: LEN and NLEN
+const STORED_HEADER_BYTES: usize = 1 + 4;
+
+/// Block types a block may be written as.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BlockTypes {
+    Any,
+    Stored,
+    StoredOrFixed,
+}
+
+/// Where the block being written is up to.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Emit {
+    Idle,
+    Header,
+    Tokens { next: usize },
+    Stored { offset: usize, chunk_end: usize, first: bool },
+}
+
+/// Writes a collected block as whichever of a stored, fixed or dynamic Huffman block comes
+/// out smallest, a piece at a time as room in the bit writer allows.
+pub(crate) struct BlockWriter {
+    state: Emit,
+    last: bool,
+    dynamic: bool,
+    header: DynamicHeader,
+    litlen_lens: [u8; 288],
+    litlen_codes: [u16; 288],
+    dist_lens: [u8; DIST_CODES],
+    dist_codes: [u16; DIST_CODES],
+}
+
+impl BlockWriter {
+    pub const fn new() -> Self {
+        BlockWriter {
+            state: Emit::Idle,
+            last: false,
+            dynamic: false,
+            header: DynamicHeader::EMPTY,
+            litlen_lens: [0; 288],
+            litlen_codes: [0; 288],
+            dist_lens: [0; DIST_CODES],
+            dist_codes: [0; DIST_CODES],
+        }
+    }
+
+    /// True while a block is partly written
+    pub fn is_busy(&self) -> bool {
+        self.state != Emit::Idle
+    }
+
+    /// Choose how to write `block`, among `types`. `raw` is the uncompressed bytes its
+    /// tokens cover, if still available, for a stored block. `bit_offset` is the writer's
+    /// position within its current byte.
+    pub fn start(&mut self, block: &Tokens, raw: Option<&[u8]>, last: bool, types: BlockTypes, bit_offset: u32) {
+        self.last = last;
+        let stored_bits = raw.map_or(usize::MAX, |raw| stored_bits(bit_offset as usize, raw.len()));
+        if types == BlockTypes::Stored {
+            debug_assert!(raw.is_some(), "stored blocks need the uncompressed data");
+            self.state = Emit::Stored { offset: 0, chunk_end: 0, first: true };
+            return;
+        }
+        let (fixed_litlen, fixed_dist) = fixed_lengths();
+        let fixed_bits = 3 + block.coded_bits(&fixed_litlen, &fixed_dist);
+        let dynamic_bits = if types == BlockTypes::StoredOrFixed {
+            usize::MAX
+        } else {
+            self.header = DynamicHeader::build(block.litlen_freq, block.dist_freq);
+            3 + self.header.bits() + block.coded_bits(&self.header.litlen_lens, &self.header.dist_lens)
+        };
+
+        if stored_bits <= dynamic_bits.min(fixed_bits) {
+            self.state = Emit::Stored { offset: 0, chunk_end: 0, first: true };
+            return;
+        }
+        self.dynamic = dynamic_bits < fixed_bits;
+        if self.dynamic {
+            self.litlen_lens = [0; 288];
+            self.litlen_lens[..LITLEN_CODES].copy_from_slice(&self.header.litlen_lens);
+            self.dist_lens = self.header.dist_lens;
+        } else {
+            self.litlen_lens = fixed_litlen;
+            self.dist_lens = fixed_dist;
+        }
+        codes_from_lengths(&self.litlen_lens, &mut self.litlen_codes);
+        codes_from_lengths(&self.dist_lens, &mut self.dist_codes);
+        self.state = Emit::Header;
+    }
+
+    /// Write as much of the started block as fits; returns true once it is complete.
+    /// `block` and `raw` must be the same as given to `start`.
+    pub fn resume<const N: usize>(&mut self, block: &Tokens, raw: Option<&[u8]>, bw: &mut BitWriter<N>) -> bool {
+        loop {
+            match self.state {
+                Emit::Idle => return true,
+                Emit::Header => {
+                    if bw.room() < MAX_HEADER_BYTES {
+                        return false;
+                    }
+                    bw.write_bits(self.last as u32, 1);
+                    if self.dynamic {
+                        bw.write_bits(2, 2);
+                        self.header.write(bw);
+                    } else {
+                        bw.write_bits(1, 2);
+                    }
+                    self.state = Emit::Tokens { next: 0 };
+                }
+                Emit::Tokens { mut next } => {
+                    while next < block.dist.len() {
+                        if bw.room() < MAX_TOKEN_BYTES {
+                            self.state = Emit::Tokens { next };
+                            return false;
+                        }
+                        self.write_token(bw, block.dist[next] as usize, block.lc[next]);
+                        next += 1;
+                    }
+                    if bw.room() < MAX_TOKEN_BYTES {
+                        self.state = Emit::Tokens { next };
+                        return false;
+                    }
+                    bw.write_bits(self.litlen_codes[END_OF_BLOCK] as u32, self.litlen_lens[END_OF_BLOCK] as u32);
+                    self.state = Emit::Idle;
+                }
+                Emit::Stored { mut offset, mut chunk_end, mut first } => {
+                    let raw = raw.unwrap_or(&[]);
+                    loop {
+                        if offset == chunk_end {
+                            if !first && offset == raw.len() {
+                                break;
+                            }
+                            if bw.room() < STORED_HEADER_BYTES {
+                                self.state = Emit::Stored { offset, chunk_end, first };
+                                return false;
+                            }
+                            // Split at the 65535-byte limit of a stored block
+                            chunk_end = offset + (raw.len() - offset).min(MAX_STORED);
+                            first = false;
+                            write_stored_header(bw, chunk_end - offset, self.last && chunk_end == raw.len());
+                        }
+                        let n = (chunk_end - offset).min(bw.room());
+                        if n == 0 {
+                            self.state = Emit::Stored { offset, chunk_end, first };
+                            return false;
+                        }
+                        bw.write_bytes(&raw[offset..offset + n]);
+                        offset += n;
+                    }
+                    self.state = Emit::Idle;
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn write_token<const N: usize>(&self, bw: &mut BitWriter<N>, distance: usize, lc: u8) {
+        if distance == 0 {
+            bw.write_bits(self.litlen_codes[lc as usize] as u32, self.litlen_lens[lc as usize] as u32);
+            return;
+        }
+        let length = lc as usize + MIN_MATCH;
+        let code = length_code(length);
+        bw.write_bits(self.litlen_codes[257 + code] as u32, self.litlen_lens[257 + code] as u32);
+        bw.write_bits((length - LENGTH_BASE[code] as usize) as u32, LENGTH_EXTRA[code] as u32);
+        let code = dist_code(distance);
+        bw.write_bits(self.dist_codes[code] as u32, self.dist_lens[code] as u32);
+        bw.write_bits((distance - DIST_BASE[code] as usize) as u32, DIST_EXTRA[code] as u32);
+    }
+}
+
+// Code lengths of the fixed Huffman codes (RFC 1951, section 3.2.6)
+fn fixed_lengths() -> ([u8; 288], [u8; DIST_CODES]) {
+    let mut litlen = [0u8; 288];
+    litlen[0..=143].fill(8);
+    litlen[144..=255].fill(9);
+    litlen[256..=279].fill(7);
+    litlen[280..=287].fill(8);
+    (litlen, [5u8; DIST_CODES])
+}
+
+// Size of `len` bytes written as stored blocks starting `bit_offset` bits into a byte
+fn stored_bits(bit_offset: usize, len: usize) -> usize {
+    let blocks = len.div_ceil(MAX_STORED).max(1);
+    // The first header is padded to a byte boundary, later ones start aligned
+    let first_pad = (8 - (bit_offset + 3) % 8) % 8;
+    blocks * (3 + 32) + first_pad + (blocks - 1) * 5 + len * 8
+}
+
+// Block header of a stored block of `len` bytes, with LEN and NLEN
+fn write_stored_header<const N: usize>(bw: &mut BitWriter<N>, len: usize, last: bool) {
+    bw.write_bits(last as u32, 1);
+    bw.write_bits(0, 2);
+    bw.align_to_byte();
+    let len = len as u16;
+    bw.write_bytes(&len.to_le_bytes());
+    bw.write_bytes(&(!len).to_le_bytes());
+}
+
+/// Write an empty stored block, which a sync or full flush uses to byte-align the stream
+/// (the `00 00 ff ff` marker). Needs `STORED_HEADER_BYTES` of room.
+pub(crate) fn write_empty_stored<const N: usize>(bw: &mut BitWriter<N>, last: bool) -> bool {
+    if bw.room() < STORED_HEADER_BYTES {
+        return false;
+    }
+    write_stored_header(bw, 0, last);
+    true
+}
+
+// Code lengths of a dynamic block and the header describing them
+struct DynamicHeader {
+    litlen_lens: [u8; LITLEN_CODES],
+    dist_lens: [u8; DIST_CODES],
+    hlit: usize,
+    hdist: usize,
+    hclen: usize,
+    codelen_lens: [u8; CODELEN_CODES],
+    // Code length symbols in order, with the value of their extra bits
+    ops: [(u8, u8); LITLEN_CODES + DIST_CODES],
+    num_ops: usize,
+}
+
+impl DynamicHeader {
+    const EMPTY: Self = DynamicHeader {
+        litlen_lens: [0; LITLEN_CODES],
+        dist_lens: [0; DIST_CODES],
+        hlit: 257,
+        hdist: 1,
+        hclen: 4,
+        codelen_lens: [0; CODELEN_CODES],
+        ops: [(0, 0); LITLEN_CODES + DIST_CODES],
+        num_ops: 0,
+    };
+
+    fn build(litlen_freq: &[u32; LITLEN_CODES], dist_freq: &[u32; DIST_CODES]) -> Self {
+        let mut litlen_lens = [0u8; LITLEN_CODES];
+        let mut dist_lens = [0u8; DIST_CODES];
+        build_lengths(litlen_freq, MAX_CODE_BITS, &mut litlen_lens);
+        build_lengths(dist_freq, MAX_CODE_BITS, &mut dist_lens);
+        let hlit = 257.max(LITLEN_CODES - litlen_lens.iter().rev().take_while(|&&l| l == 0).count());
+        let hdist = 1.max(DIST_CODES - dist_lens.iter().rev().take_while(|&&l| l == 0).count());
+
+        // The code lengths as one sequence, run-length encoded across the two tables
+        let mut lens = [0u8; LITLEN_CODES + DIST_CODES];
+        lens[..hlit].copy_from_slice(&litlen_lens[..hlit]);
+        lens[hlit..hlit + hdist].copy_from_slice(&dist_lens[..hdist]);
+        let mut ops = [(0u8, 0u8); LITLEN_CODES + DIST_CODES];
+        let num_ops = rle_code_lengths(&lens[..hlit + hdist], &mut ops);
+        let mut codelen_freq = [0u32; CODELEN_CODES];
+        for &(sym, _) in &ops[..num_ops] {
+            codelen_freq[sym as usize] += 1;
+        }
+        let mut codelen_lens = [0u8; CODELEN_CODES];
+        build_lengths(&codelen_freq, MAX_CODELEN_BITS, &mut codelen_lens);
+        let hclen = 4.max(CODELEN_CODES - CODE_LENGTH_ORDER.iter().rev().take_while(|&&i| codelen_lens[i] == 0).count());
+        DynamicHeader { litlen_lens, dist_lens, hlit, hdist, hclen, codelen_lens, ops, num_ops }
+    }
+
+    // Size of the header after the 3 block header bits
+    fn bits(&self) -> usize {
+        let ops: usize = self.ops[..self.num_ops].iter().map(|&(sym, _)| self.codelen_lens[sym as usize] as usize + op_extra_bits(sym) as usize).sum();
+        5 + 5 + 4 + 3 * self.hclen + ops
+    }
+
+    fn write<const N: usize>(&self, bw: &mut BitWriter<N>) {
+        bw.write_bits((self.hlit - 257) as u32, 5);
+        bw.write_bits((self.hdist - 1) as u32, 5);
+        bw.write_bits((self.hclen - 4) as u32, 4);
+        for &i in CODE_LENGTH_ORDER.iter().take(self.hclen) {
+            bw.write_bits(self.codelen_lens[i] as u32, 3);
+        }
+        let mut codes = [0u16; CODELEN_CODES];
+        codes_from_lengths(&self.codelen_lens, &mut codes);
+        for &(sym, extra) in &self.ops[..self.num_ops] {
+            bw.write_bits(codes[sym as usize] as u32, self.codelen_lens[sym as usize] as u32);
+            bw.write_bits(extra as u32, op_extra_bits(sym));
+        }
+    }
+}
+
+// Extra bits following a code length symbol: repeat counts of 16, 17 and 18
+fn op_extra_bits(sym: u8) -> u32 {
+    match sym {
+        16 => 2,
+        17 => 3,
+        18 => 7,
+        _ => 0,
+    }
+}
@@ -0,0 +1,222 @@
+// Huffman code construction for the encoder: code lengths from symbol frequencies,
+// and canonical codes from code lengths.
+
+use crate::inflate::huffman::{reverse_bits, MAX_CODE_BITS, MAX_SYMBOLS};
+
+/// Compute optimal Huffman code lengths for `freqs` into `lengths` (same length), with no
+/// code longer than `max_bits`.
+///
+/// Symbols with zero frequency get length 0. At least two symbols always get a code so
+/// the result is a complete code, as some decoders insist on that; with a one-symbol
+/// alphabet that symbol gets length 1 whatever its frequency. An unrestricted Huffman
+/// code is built first; only if it is too deep are the lengths recomputed with
+/// package-merge, which gives the cheapest code within the limit.
+///
+/// # Panics
+/// If `lengths` and `freqs` differ in length, `freqs` has more than 288 entries, `max_bits`
+/// is not 1 ..= 15, or more than `2^max_bits` symbols are in use.
+pub fn build_lengths(freqs: &[u32], max_bits: usize, lengths: &mut [u8]) {
+    assert!(freqs.len() <= MAX_SYMBOLS && lengths.len() == freqs.len());
+    assert!((1..=MAX_CODE_BITS).contains(&max_bits));
+    lengths.fill(0);
+
+    // Leaves with nonzero frequency, in increasing order of frequency
+    let mut leaves = [0u16; MAX_SYMBOLS];
+    let mut n = 0usize;
+    for (sym, &f) in freqs.iter().enumerate() {
+        if f > 0 {
+            leaves[n] = sym as u16;
+            n += 1;
+        }
+    }
+    if freqs.len() < 2 {
+        // No second symbol to complete the code with
+        if let Some(len) = lengths.first_mut() {
+            *len = 1;
+        }
+        return;
+    }
+    if n < 2 {
+        // One code of length 1 for each of the (up to) two lowest symbols in use
+        let used = if n == 1 { leaves[0] as usize } else { 0 };
+        lengths[used] = 1;
+        lengths[if used == 0 { 1 } else { 0 }] = 1;
+        return;
+    }
+    assert!(n <= 1 << max_bits, "{} symbols do not fit in {}-bit codes", n, max_bits);
+    let leaves = &mut leaves[..n];
+    leaves.sort_unstable_by_key(|&sym| (freqs[sym as usize], sym));
+
+    if huffman_lengths(freqs, leaves, lengths) > max_bits {
+        lengths.fill(0);
+        package_merge(freqs, leaves, max_bits, lengths);
+    }
+}
+
+// Unrestricted Huffman code for the sorted `leaves`; returns the longest code length.
+fn huffman_lengths(freqs: &[u32], leaves: &[u16], lengths: &mut [u8]) -> usize {
+    let n = leaves.len();
+    // Two-queue construction: leaves come from `leaves`, internal nodes are created in
+    // increasing weight order so they form a second sorted queue at weight[n..].
+    let mut weight = [0u64; 2 * MAX_SYMBOLS];
+    let mut parent = [0u16; 2 * MAX_SYMBOLS];
+    for (i, &sym) in leaves.iter().enumerate() {
+        weight[i] = freqs[sym as usize] as u64;
+    }
+    let (mut next_leaf, mut next_node) = (0usize, n);
+    for node in n..2 * n - 1 {
+        for _ in 0..2 {
+            let take_leaf = next_leaf < n && (next_node >= node || weight[next_leaf] <= weight[next_node]);
+            let child = if take_leaf {
+                next_leaf += 1;
+                next_leaf - 1
+            } else {
+                next_node += 1;
+                next_node - 1
+            };
+            weight[node] += weight[child];
+            parent[child] = node as u16;
+        }
+    }
+
+    // Depths from the root (the last node) down; parents always come after their children
+    let root = 2 * n - 2;
+    let mut depth = [0u16; 2 * MAX_SYMBOLS];
+    for i in (0..root).rev() {
+        depth[i] = depth[parent[i] as usize] + 1;
+    }
+    let mut longest = 0usize;
+    for (i, &sym) in leaves.iter().enumerate() {
+        // Depths past the limit are recomputed, so truncation does not matter
+        lengths[sym as usize] = depth[i].min(u8::MAX as u16) as u8;
+        longest = longest.max(depth[i] as usize);
+    }
+    longest
+}
+
+// Words of a bitmap with one bit per item of a package-merge list (at most 2n - 1 items)
+const LIST_WORDS: usize = (2 * MAX_SYMBOLS).div_ceil(64);
+
+// Length-limited code lengths by package-merge (Larmore and Hirschberg) for the sorted
+// `leaves`. List 1 holds the leaves; list j merges the leaves with packages made of pairs
+// of consecutive items of list j - 1. The cheapest code takes the first 2n - 2 items of the
+// last list, and a symbol's code length is the number of lists whose selected prefix
+// contains its leaf. Leaves stay in order when merged, so the selected leaves of each list
+// are the lightest ones and only their count is needed, found by walking back through
+// bitmaps recording which items were leaves.
+fn package_merge(freqs: &[u32], leaves: &[u16], max_bits: usize, lengths: &mut [u8]) {
+    let n = leaves.len();
+    let mut is_leaf = [[0u64; LIST_WORDS]; MAX_CODE_BITS];
+    let mut prev = [0u64; 2 * MAX_SYMBOLS];
+    let mut cur = [0u64; 2 * MAX_SYMBOLS];
+
+    for (i, &sym) in leaves.iter().enumerate() {
+        prev[i] = freqs[sym as usize] as u64;
+        is_leaf[0][i / 64] |= 1 << (i % 64);
+    }
+    let mut prev_len = n;
+    for bitmap in is_leaf.iter_mut().take(max_bits).skip(1) {
+        let packages = prev_len / 2;
+        let (mut leaf, mut pkg, mut len) = (0usize, 0usize, 0usize);
+        while leaf < n || pkg < packages {
+            let pkg_weight = if pkg < packages { prev[2 * pkg] + prev[2 * pkg + 1] } else { u64::MAX };
+            if leaf < n && freqs[leaves[leaf] as usize] as u64 <= pkg_weight {
+                cur[len] = freqs[leaves[leaf] as usize] as u64;
+                bitmap[len / 64] |= 1 << (len % 64);
+                leaf += 1;
+            } else {
+                cur[len] = pkg_weight;
+                pkg += 1;
+            }
+            len += 1;
+        }
+        core::mem::swap(&mut prev, &mut cur);
+        prev_len = len;
+    }
+
+    let mut take = 2 * n - 2;
+    for bitmap in is_leaf[..max_bits].iter().rev() {
+        let mut selected_leaves = 0usize;
+        for (w, &word) in bitmap.iter().enumerate() {
+            let bits = take.saturating_sub(w * 64).min(64);
+            if bits == 0 {
+                break;
+            }
+            let mask = if bits == 64 { !0 } else { (1u64 << bits) - 1 };
+            selected_leaves += (word & mask).count_ones() as usize;
+        }
+        for &sym in &leaves[..selected_leaves] {
+            lengths[sym as usize] += 1;
+        }
+        take = 2 * (take - selected_leaves);
+    }
+}
+
+/// Run-length encode the code lengths of a dynamic block header (RFC 1951, section 3.2.7).
+///
+/// `lengths` is the literal/length code lengths followed by the distance code lengths;
+/// runs may cross from one into the other, as the decoder allows. Each op written to `ops`
+/// is a code length symbol 0-18 and the value of its extra bits: 16 repeats the previous
+/// length 3-6 times, 17 and 18 give 3-10 and 11-138 zeros. Returns the number of ops;
+/// `ops` needs room for one per length in the worst case.
+pub fn rle_code_lengths(lengths: &[u8], ops: &mut [(u8, u8)]) -> usize {
+    let mut count = 0usize;
+    let mut emit = |sym: u8, extra: usize| {
+        ops[count] = (sym, extra as u8);
+        count += 1;
+    };
+    let mut i = 0usize;
+    while i < lengths.len() {
+        let len = lengths[i];
+        let mut run = lengths[i..].iter().take_while(|&&l| l == len).count();
+        i += run;
+        if len == 0 {
+            while run >= 11 {
+                let r = run.min(138);
+                emit(18, r - 11);
+                run -= r;
+            }
+            if run >= 3 {
+                emit(17, run - 3);
+                run = 0;
+            }
+        } else {
+            // The length itself, then repeats of it
+            emit(len, 0);
+            run -= 1;
+            while run >= 3 {
+                let r = run.min(6);
+                emit(16, r - 3);
+                run -= r;
+            }
+        }
+        for _ in 0..run {
+            emit(len, 0);
+        }
+    }
+    count
+}
+
+/// Assign canonical codes (RFC 1951, section 3.2.2) for `lengths`, bit-reversed so they
+/// can be written LSB first.
+pub fn codes_from_lengths(lengths: &[u8], codes: &mut [u16]) {
+    let mut counts = [0u16; MAX_CODE_BITS + 1];
+    for &l in lengths {
+        counts[l as usize] += 1;
+    }
+    counts[0] = 0;
+    let mut next_code = [0u32; MAX_CODE_BITS + 1];
+    let mut code = 0u32;
+    for bits in 1..=MAX_CODE_BITS {
+        code = (code + counts[bits - 1] as u32) << 1;
+        next_code[bits] = code;
+    }
+    for (sym, &l) in lengths.iter().enumerate() {
+        if l == 0 {
+            codes[sym] = 0;
+            continue;
+        }
+        codes[sym] = reverse_bits(next_code[l as usize], l as usize) as u16;
+        next_code[l as usize] += 1;
+    }
+}
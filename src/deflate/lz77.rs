@@ -0,0 +1,115 @@
+/// Shortest match DEFLATE can encode
+pub(crate) const MIN_MATCH: usize = 3;
+/// Longest match DEFLATE can encode
+pub(crate) const MAX_MATCH: usize = 258;
+/// Position value meaning "no entry"; position 0 of the window is never matched
+const NIL: u16 = 0;
+
+/// Hash chains over the compressor's window: `head` holds the latest position for each
+/// of `HASH` hashes of the next three bytes, `prev` links each position to the previous
+/// one with the same hash. Positions index a buffer of twice `WINDOW` bytes, as in zlib,
+/// and matches reach at most `WINDOW` bytes back.
+pub(crate) struct MatchFinder<const WINDOW: usize, const HASH: usize> {
+    head: [u16; HASH],
+    prev: [u16; WINDOW],
+}
+
+impl<const WINDOW: usize, const HASH: usize> MatchFinder<WINDOW, HASH> {
+    const HASH_SHIFT: u32 = 32 - HASH.trailing_zeros();
+
+    pub const fn new() -> Self {
+        MatchFinder { head: [NIL; HASH], prev: [NIL; WINDOW] }
+    }
+
+    #[inline]
+    fn hash(data: &[u8], pos: usize) -> usize {
+        let v = u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], 0]);
+        (v.wrapping_mul(0x9E37_79B1) >> Self::HASH_SHIFT) as usize
+    }
+
+    /// Add `pos` to the hash chains. Positions too close to the end of `data` to start a
+    /// match are skipped.
+    #[inline]
+    pub fn insert(&mut self, data: &[u8], pos: usize) {
+        if pos + MIN_MATCH > data.len() {
+            return;
+        }
+        let h = Self::hash(data, pos);
+        self.prev[pos & (WINDOW - 1)] = self.head[h];
+        self.head[h] = pos as u16;
+    }
+
+    /// Forget all positions, so later matches cannot refer to earlier data.
+    pub fn clear(&mut self) {
+        self.head.fill(NIL);
+    }
+
+    /// Account for the buffer moving down by `WINDOW` bytes: positions below that fall
+    /// out of it.
+    pub fn slide(&mut self) {
+        for p in self.head.iter_mut().chain(self.prev.iter_mut()) {
+            *p = p.saturating_sub(WINDOW as u16);
+        }
+    }
+
+    /// Find the longest match for `pos`, which must already be inserted, that is longer
+    /// than `min_len` and lies within `data`. Follows at most `max_chain` links and stops
+    /// early once a match of `nice_len` is found. Returns `(length, distance)`, or `(0, 0)`
+    /// if nothing better was found.
+    pub fn longest_match(&self, data: &[u8], pos: usize, min_len: usize, max_chain: usize, nice_len: usize) -> (usize, usize) {
+        if pos + MIN_MATCH > data.len() {
+            return (0, 0);
+        }
+        let max_len = (data.len() - pos).min(MAX_MATCH);
+        let nice_len = nice_len.min(max_len);
+        let limit = pos.saturating_sub(WINDOW);
+        let cur = &data[pos..pos + max_len];
+
+        let mut best_len = min_len.max(MIN_MATCH - 1);
+        let mut best_dist = 0usize;
+        let mut cand = self.prev[pos & (WINDOW - 1)] as usize;
+        let mut chain = max_chain;
+        while cand != NIL as usize && cand < pos && chain > 0 && best_len < max_len {
+            if cand < limit {
+                break;
+            }
+            let prior = &data[cand..cand + max_len];
+            // Cheap reject: a longer match must also agree at the current best length
+            if prior[best_len] == cur[best_len] {
+                let len = common_prefix(prior, cur);
+                if len > best_len {
+                    best_len = len;
+                    best_dist = pos - cand;
+                    if len >= nice_len {
+                        break;
+                    }
+                }
+            }
+            // Links are only valid while they go backwards; a slot reused by a newer
+            // position ends the chain
+            let next = self.prev[cand & (WINDOW - 1)] as usize;
+            if next >= cand {
+                break;
+            }
+            cand = next;
+            chain -= 1;
+        }
+        if best_dist == 0 { (0, 0) } else { (best_len, best_dist) }
+    }
+}
+
+// Length of the common prefix of two equally long slices, compared eight bytes at a time
+#[inline]
+pub(crate) fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    let mut len = 0usize;
+    let mut wa = a.chunks_exact(8);
+    let mut wb = b.chunks_exact(8);
+    for (x, y) in (&mut wa).zip(&mut wb) {
+        let diff = u64::from_le_bytes(x.try_into().unwrap()) ^ u64::from_le_bytes(y.try_into().unwrap());
+        if diff != 0 {
+            return len + (diff.trailing_zeros() / 8) as usize;
+        }
+        len += 8;
+    }
+    len + wa.remainder().iter().zip(wb.remainder()).take_while(|(x, y)| x == y).count()
+}
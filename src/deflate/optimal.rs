@@ -0,0 +1,551 @@
+// Optimal parsing in the manner of Zopfli. Matches at every position are found once; a
+// shortest-path search over a bit-cost model then picks the tokens of each block, and is
+// re-run with costs taken from its own previous output for as long as that helps. Blocks
+// are split where separate Huffman codes pay for their extra headers.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::checksum::Adler32;
+use crate::deflate::bitwriter::BitWriter;
+use crate::deflate::block::{dist_code, length_code, BlockTypes, BlockWriter, Tokens, DIST_CODES, END_OF_BLOCK, LITLEN_CODES};
+use crate::deflate::lz77::{common_prefix, MAX_MATCH, MIN_MATCH};
+use crate::deflate::{zlib_header, Strategy};
+use crate::inflate::{DIST_EXTRA, LENGTH_EXTRA, MAX_WINDOW};
+
+const HASH_BITS: u32 = 16;
+// Hash chain links followed per position (Zopfli's ZOPFLI_MAX_CHAIN_HITS)
+const MAX_CHAIN: usize = 8192;
+// Input is parsed and split in pieces of this size, bounding the work per piece
+const MASTER_BLOCK: usize = 1_000_000;
+// Blocks one piece is split into at most
+const MAX_BLOCKS: usize = 15;
+// Blocks with fewer tokens are not split further
+const MIN_SPLIT_TOKENS: usize = 10;
+// Costs are in 1/256 bits
+const COST_FRAC_BITS: u32 = 8;
+
+/// Compress `data` into raw DEFLATE with an exhaustive search for the smallest output.
+///
+/// Far slower than `deflate_raw` at level 9 (seconds per megabyte), meant for data
+/// compressed once and decompressed many times. Each of the `iterations` (at least one;
+/// Zopfli uses 15) refines the parse with costs measured on the previous one. The output
+/// is ordinary DEFLATE for any decoder.
+pub fn deflate_raw_optimal(data: &[u8], iterations: u32) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() / 3 + 64);
+    compress(data, iterations, &mut out);
+    out
+}
+
+/// Compress `data` into a zlib stream as `deflate_raw_optimal` does.
+pub fn deflate_zlib_optimal(data: &[u8], iterations: u32) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() / 3 + 64);
+    out.extend_from_slice(&zlib_header(9, Strategy::Default, MAX_WINDOW, false));
+    compress(data, iterations, &mut out);
+    let mut adler = Adler32::new();
+    adler.update(data);
+    out.extend_from_slice(&adler.finish().to_be_bytes());
+    out
+}
+
+fn compress(data: &[u8], iterations: u32, out: &mut Vec<u8>) {
+    let matches = Matches::find(data);
+    let runs = run_lengths(data);
+    let mut bw = BitWriter::<4096>::new();
+    let mut writer = BlockWriter::new();
+    let mut start = 0;
+    loop {
+        let end = (start + MASTER_BLOCK).min(data.len());
+        let greedy = TokenList::greedy(data, &matches, start, end);
+        let splits = split_points(&greedy);
+
+        // Parse each block optimally, then see whether splitting the result anew does better
+        let mut tokens = TokenList::new(start);
+        let mut bounds = block_bounds(&greedy, &splits);
+        for w in bounds.windows(2) {
+            tokens.append(&optimal_parse(data, &matches, &runs, w[0], w[1], iterations.max(1)));
+        }
+        let resplit = split_points(&tokens);
+        let resplit_bounds = block_bounds(&tokens, &resplit);
+        if total_bits(&tokens, &resplit_bounds) < total_bits(&tokens, &bounds) {
+            bounds = resplit_bounds;
+        }
+
+        let last_piece = end == data.len();
+        let mut first = 0;
+        for (k, w) in bounds.windows(2).enumerate() {
+            let count = tokens.count_until(first, w[1]);
+            let freqs = tokens.freqs(first, first + count);
+            let view = tokens.view(first, first + count, &freqs);
+            let raw = Some(&data[w[0]..w[1]]);
+            let last = last_piece && k + 2 == bounds.len();
+            writer.start(&view, raw, last, BlockTypes::Any, bw.bit_offset());
+            while !writer.resume(&view, raw, &mut bw) {
+                drain(&mut bw, out);
+            }
+            first += count;
+        }
+        if last_piece {
+            break;
+        }
+        start = end;
+    }
+    bw.align_to_byte();
+    drain(&mut bw, out);
+}
+
+fn drain<const N: usize>(bw: &mut BitWriter<N>, out: &mut Vec<u8>) {
+    let len = out.len();
+    out.resize(len + N, 0);
+    let n = bw.drain_into(&mut out[len..]);
+    out.truncate(len + n);
+}
+
+// Every match worth considering at each position: pairs of increasing length, each with
+// the closest distance reaching that length, so shorter lengths take the distance of the
+// first pair at least as long
+struct Matches {
+    offsets: Vec<u32>,
+    pairs: Vec<(u16, u16)>,
+}
+
+impl Matches {
+    fn find(data: &[u8]) -> Self {
+        let n = data.len();
+        let mut head = vec![u32::MAX; 1 << HASH_BITS];
+        let mut prev = vec![u32::MAX; n];
+        let mut offsets = Vec::with_capacity(n + 1);
+        let mut pairs = Vec::new();
+        for pos in 0..n {
+            offsets.push(pairs.len() as u32);
+            if pos + MIN_MATCH > n {
+                continue;
+            }
+            let h = hash(data, pos);
+            let max_len = (n - pos).min(MAX_MATCH);
+            let cur = &data[pos..pos + max_len];
+            let mut best = MIN_MATCH - 1;
+            let mut cand = head[h];
+            let mut chain = MAX_CHAIN;
+            while cand != u32::MAX && chain > 0 && best < max_len {
+                let c = cand as usize;
+                if pos - c > MAX_WINDOW {
+                    break;
+                }
+                let prior = &data[c..c + max_len];
+                if prior[best] == cur[best] {
+                    let len = common_prefix(prior, cur);
+                    if len > best {
+                        pairs.push((len as u16, (pos - c) as u16));
+                        best = len;
+                    }
+                }
+                cand = prev[c];
+                chain -= 1;
+            }
+            prev[pos] = head[h];
+            head[h] = pos as u32;
+        }
+        offsets.push(pairs.len() as u32);
+        Matches { offsets, pairs }
+    }
+
+    fn at(&self, pos: usize) -> &[(u16, u16)] {
+        &self.pairs[self.offsets[pos] as usize..self.offsets[pos + 1] as usize]
+    }
+}
+
+#[inline]
+fn hash(data: &[u8], pos: usize) -> usize {
+    let v = u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], 0]);
+    (v.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+}
+
+// For each position, how many bytes from there on equal it (at most u16::MAX)
+fn run_lengths(data: &[u8]) -> Vec<u16> {
+    let mut runs = vec![0u16; data.len()];
+    for i in (0..data.len()).rev() {
+        runs[i] = if i + 1 < data.len() && data[i + 1] == data[i] { runs[i + 1].saturating_add(1) } else { 1 };
+    }
+    runs
+}
+
+// Tokens in the layout of `Block`, covering the input from `start` on
+struct TokenList {
+    start: usize,
+    dist: Vec<u16>,
+    lc: Vec<u8>,
+}
+
+impl TokenList {
+    fn new(start: usize) -> Self {
+        TokenList { start, dist: Vec::new(), lc: Vec::new() }
+    }
+
+    fn push_literal(&mut self, byte: u8) {
+        self.dist.push(0);
+        self.lc.push(byte);
+    }
+
+    fn push_match(&mut self, length: usize, distance: usize) {
+        self.dist.push(distance as u16);
+        self.lc.push((length - MIN_MATCH) as u8);
+    }
+
+    fn append(&mut self, other: &TokenList) {
+        self.dist.extend_from_slice(&other.dist);
+        self.lc.extend_from_slice(&other.lc);
+    }
+
+    fn len(&self) -> usize {
+        self.dist.len()
+    }
+
+    fn token_len(&self, i: usize) -> usize {
+        if self.dist[i] == 0 { 1 } else { self.lc[i] as usize + MIN_MATCH }
+    }
+
+    // Tokens from `first` on that end at or before input position `end`
+    fn count_until(&self, first: usize, end: usize) -> usize {
+        let mut pos = self.start + (0..first).map(|i| self.token_len(i)).sum::<usize>();
+        let mut count = 0;
+        while first + count < self.len() && pos < end {
+            pos += self.token_len(first + count);
+            count += 1;
+        }
+        count
+    }
+
+    // Longest match at each position, clipped to `end`; enough for first statistics
+    fn greedy(data: &[u8], matches: &Matches, start: usize, end: usize) -> Self {
+        let mut tokens = TokenList::new(start);
+        let mut pos = start;
+        while pos < end {
+            match matches.at(pos).last() {
+                Some(&(len, dist)) if (len as usize).min(end - pos) >= MIN_MATCH => {
+                    let len = (len as usize).min(end - pos);
+                    tokens.push_match(len, dist as usize);
+                    pos += len;
+                }
+                _ => {
+                    tokens.push_literal(data[pos]);
+                    pos += 1;
+                }
+            }
+        }
+        tokens
+    }
+
+    fn freqs(&self, first: usize, last: usize) -> Freqs {
+        let mut freqs = Freqs::new();
+        for i in first..last {
+            if self.dist[i] == 0 {
+                freqs.litlen[self.lc[i] as usize] += 1;
+            } else {
+                freqs.litlen[257 + length_code(self.lc[i] as usize + MIN_MATCH)] += 1;
+                freqs.dist[dist_code(self.dist[i] as usize)] += 1;
+            }
+        }
+        freqs
+    }
+
+    fn view<'a>(&'a self, first: usize, last: usize, freqs: &'a Freqs) -> Tokens<'a> {
+        Tokens { dist: &self.dist[first..last], lc: &self.lc[first..last], litlen_freq: &freqs.litlen, dist_freq: &freqs.dist }
+    }
+
+    // Size in bits of tokens `first..last` as one block
+    fn bits(&self, first: usize, last: usize) -> usize {
+        let raw_len = (first..last).map(|i| self.token_len(i)).sum();
+        self.view(first, last, &self.freqs(first, last)).best_bits(raw_len)
+    }
+}
+
+// Symbol counts of some tokens, with the end-of-block code counted once
+#[derive(Clone)]
+struct Freqs {
+    litlen: [u32; LITLEN_CODES],
+    dist: [u32; DIST_CODES],
+}
+
+impl Freqs {
+    fn new() -> Self {
+        let mut litlen = [0; LITLEN_CODES];
+        litlen[END_OF_BLOCK] = 1;
+        Freqs { litlen, dist: [0; DIST_CODES] }
+    }
+}
+
+// Token indices at which to start new blocks, chosen by repeatedly splitting the largest
+// block at the point that makes its two halves smallest, while that beats keeping it whole
+fn split_points(tokens: &TokenList) -> Vec<usize> {
+    let mut splits: Vec<usize> = Vec::new();
+    let mut done: Vec<(usize, usize)> = Vec::new();
+    while splits.len() + 1 < MAX_BLOCKS {
+        // Largest block not yet found unsplittable
+        let mut bounds = vec![0];
+        bounds.extend_from_slice(&splits);
+        bounds.push(tokens.len());
+        let Some((first, last)) = bounds
+            .windows(2)
+            .map(|w| (w[0], w[1]))
+            .filter(|&(a, b)| b - a >= MIN_SPLIT_TOKENS && !done.contains(&(a, b)))
+            .max_by_key(|&(a, b)| b - a)
+        else {
+            break;
+        };
+        let (at, split_bits) = find_minimum(|i| tokens.bits(first, i) + tokens.bits(i, last), first + 1, last);
+        if split_bits < tokens.bits(first, last) {
+            let pos = splits.partition_point(|&s| s < at);
+            splits.insert(pos, at);
+        } else {
+            done.push((first, last));
+        }
+    }
+    splits
+}
+
+// Smallest value of `f` over `start..end`: every point for short ranges, else narrowing
+// down around the best of nine samples (Zopfli's FindMinimum)
+fn find_minimum(f: impl Fn(usize) -> usize, mut start: usize, mut end: usize) -> (usize, usize) {
+    if end - start < 1024 {
+        return (start..end).map(|i| (i, f(i))).min_by_key(|&(_, v)| v).unwrap();
+    }
+    const SAMPLES: usize = 9;
+    let mut best = (start, usize::MAX);
+    while end - start > SAMPLES {
+        let step = (end - start) / (SAMPLES + 1);
+        let points: [usize; SAMPLES] = core::array::from_fn(|i| start + (i + 1) * step);
+        let (k, value) = points.iter().map(|&p| f(p)).enumerate().min_by_key(|&(_, v)| v).unwrap();
+        if value > best.1 {
+            break;
+        }
+        best = (points[k], value);
+        if k > 0 {
+            start = points[k - 1];
+        }
+        if k + 1 < SAMPLES {
+            end = points[k + 1];
+        }
+    }
+    best
+}
+
+// Input positions where the blocks given by token `splits` start, plus the end
+fn block_bounds(tokens: &TokenList, splits: &[usize]) -> Vec<usize> {
+    let mut bounds = vec![tokens.start];
+    let mut pos = tokens.start;
+    let mut next = splits.iter().peekable();
+    for i in 0..tokens.len() {
+        if next.peek() == Some(&&i) {
+            bounds.push(pos);
+            next.next();
+        }
+        pos += tokens.token_len(i);
+    }
+    bounds.push(pos);
+    bounds
+}
+
+// Size in bits of `tokens` written as blocks with the given input `bounds`
+fn total_bits(tokens: &TokenList, bounds: &[usize]) -> usize {
+    let mut first = 0;
+    let mut bits = 0;
+    for w in bounds.windows(2) {
+        let count = tokens.count_until(first, w[1]);
+        bits += tokens.bits(first, first + count);
+        first += count;
+    }
+    bits
+}
+
+// Tokens for input `start..end` found by shortest paths under a cost model, the model
+// re-estimated from each result; the smallest result is kept
+fn optimal_parse(data: &[u8], matches: &Matches, runs: &[u16], start: usize, end: usize, iterations: u32) -> TokenList {
+    let greedy = TokenList::greedy(data, matches, start, end);
+    let mut stats = greedy.freqs(0, greedy.len());
+    let mut best = greedy;
+    let mut best_bits = usize::MAX;
+    let mut best_stats = stats.clone();
+    let mut last_bits = 0;
+    let mut randomized = false;
+    let mut rng = Xorshift(0x2545_F491);
+    for i in 0..iterations {
+        let tokens = shortest_path(data, matches, runs, start, end, &CostModel::new(&stats));
+        let bits = tokens.bits(0, tokens.len());
+        let last_stats = core::mem::replace(&mut stats, tokens.freqs(0, tokens.len()));
+        if bits < best_bits {
+            best_bits = bits;
+            best_stats = last_stats.clone();
+            best = tokens;
+        }
+        // Blending in the previous statistics converges slower but better, once
+        // randomizing has started
+        if randomized {
+            stats.add_half(&last_stats);
+        }
+        // Stuck: restart from the best statistics with some symbols' counts shuffled
+        if i > 5 && bits == last_bits {
+            stats = best_stats.clone();
+            stats.randomize(&mut rng);
+            randomized = true;
+        }
+        last_bits = bits;
+    }
+    best
+}
+
+// Cheapest tokens for `start..end`: costs to reach each position, relaxed forward over
+// the literal and every match length, then the path traced back from the end
+fn shortest_path(data: &[u8], matches: &Matches, runs: &[u16], start: usize, end: usize, model: &CostModel) -> TokenList {
+    let n = end - start;
+    let mut cost = vec![u64::MAX; n + 1];
+    // Length and distance of the step reaching each position (distance 0 for a literal)
+    let mut step = vec![(0u16, 0u16); n + 1];
+    cost[0] = 0;
+    let mut i = 0;
+    while i < n {
+        let pos = start + i;
+        // Within a long run of one byte, take whole distance-1 matches without searching
+        if i > MAX_MATCH && i + 2 * MAX_MATCH < n && runs[pos] as usize > 2 * MAX_MATCH && runs[pos - MAX_MATCH] as usize > MAX_MATCH {
+            let c = model.match_cost(MAX_MATCH, 1);
+            for _ in 0..MAX_MATCH {
+                cost[i + MAX_MATCH] = cost[i] + c;
+                step[i + MAX_MATCH] = (MAX_MATCH as u16, 1);
+                i += 1;
+            }
+        }
+        let pos = start + i;
+        let here = cost[i];
+        let lit = here + model.literal[data[pos] as usize];
+        if lit < cost[i + 1] {
+            cost[i + 1] = lit;
+            step[i + 1] = (1, 0);
+        }
+        let max_len = MAX_MATCH.min(n - i);
+        let mut len = MIN_MATCH;
+        for &(pair_len, dist) in matches.at(pos) {
+            let dist_cost = here + model.dist_cost(dist as usize);
+            while len <= (pair_len as usize).min(max_len) {
+                let c = dist_cost + model.length[len];
+                if c < cost[i + len] {
+                    cost[i + len] = c;
+                    step[i + len] = (len as u16, dist);
+                }
+                len += 1;
+            }
+            if len > max_len {
+                break;
+            }
+        }
+        i += 1;
+    }
+
+    let mut path = Vec::new();
+    let mut j = n;
+    while j > 0 {
+        path.push(step[j]);
+        j -= step[j].0 as usize;
+    }
+    let mut tokens = TokenList::new(start);
+    let mut pos = start;
+    for &(len, dist) in path.iter().rev() {
+        if dist == 0 {
+            tokens.push_literal(data[pos]);
+        } else {
+            tokens.push_match(len as usize, dist as usize);
+        }
+        pos += len as usize;
+    }
+    tokens
+}
+
+// Bits each symbol would take under codes fitted to some statistics: its entropy
+// -log2(count / total), with unused symbols priced as if seen once
+struct CostModel {
+    literal: [u64; 256],
+    // Length symbol plus extra bits, by match length
+    length: [u64; MAX_MATCH + 1],
+    dist: [u64; DIST_CODES],
+}
+
+impl CostModel {
+    fn new(stats: &Freqs) -> Self {
+        let litlen = entropy(&stats.litlen);
+        let dist = entropy(&stats.dist);
+        let mut model = CostModel { literal: [0; 256], length: [0; MAX_MATCH + 1], dist: [0; DIST_CODES] };
+        model.literal.copy_from_slice(&litlen[..256]);
+        for len in MIN_MATCH..=MAX_MATCH {
+            let code = length_code(len);
+            model.length[len] = litlen[257 + code] + ((LENGTH_EXTRA[code] as u64) << COST_FRAC_BITS);
+        }
+        for (code, cost) in model.dist.iter_mut().enumerate() {
+            *cost = dist[code] + ((DIST_EXTRA[code] as u64) << COST_FRAC_BITS);
+        }
+        model
+    }
+
+    fn dist_cost(&self, distance: usize) -> u64 {
+        self.dist[dist_code(distance)]
+    }
+
+    fn match_cost(&self, length: usize, distance: usize) -> u64 {
+        self.length[length] + self.dist_cost(distance)
+    }
+}
+
+fn entropy<const N: usize>(counts: &[u32; N]) -> [u64; N] {
+    let total: u64 = counts.iter().map(|&c| c as u64).sum();
+    let log_total = log2_scaled(total.max(1));
+    counts.map(|c| log_total.saturating_sub(log2_scaled(c.max(1) as u64)) as u64)
+}
+
+// log2(x) in 1/256 bits, for x at least 1: the integer part from the highest set bit, the
+// fraction by repeated squaring of the mantissa
+fn log2_scaled(x: u64) -> u32 {
+    let int = 63 - x.leading_zeros();
+    // Mantissa in [1, 2) with 16 fractional bits
+    let mut m = if int >= 16 { x >> (int - 16) } else { x << (16 - int) };
+    let mut frac = 0u32;
+    for _ in 0..COST_FRAC_BITS {
+        m = (m * m) >> 16;
+        frac <<= 1;
+        if m >= 2 << 16 {
+            m >>= 1;
+            frac |= 1;
+        }
+    }
+    (int << COST_FRAC_BITS) | frac
+}
+
+impl Freqs {
+    fn add_half(&mut self, other: &Freqs) {
+        for (a, b) in self.litlen.iter_mut().zip(&other.litlen).chain(self.dist.iter_mut().zip(&other.dist)) {
+            *a += b / 2;
+        }
+    }
+
+    // Replace about a third of the counts with other symbols' counts
+    fn randomize(&mut self, rng: &mut Xorshift) {
+        fn shuffle(counts: &mut [u32], rng: &mut Xorshift) {
+            for i in 0..counts.len() {
+                if rng.next().is_multiple_of(3) {
+                    counts[i] = counts[rng.next() as usize % counts.len()];
+                }
+            }
+        }
+        shuffle(&mut self.litlen, rng);
+        shuffle(&mut self.dist, rng);
+        self.litlen[END_OF_BLOCK] = 1;
+    }
+}
+
+struct Xorshift(u32);
+
+impl Xorshift {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+}
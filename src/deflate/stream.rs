@@ -0,0 +1,531 @@
+use crate::checksum::{Adler32, Crc32};
+use crate::deflate::bitwriter::BitWriter;
+use crate::deflate::block::{write_empty_stored, Block, BlockTypes, BlockWriter};
+use crate::deflate::lz77::{MatchFinder, MAX_MATCH, MIN_MATCH};
+use crate::deflate::{zlib_header, Config, Strategy, CONFIGS, FIRST_LAZY_LEVEL, TOO_FAR};
+use crate::inflate::{Status, StreamState, MAX_WINDOW, MIN_WINDOW};
+
+/// Hash table entries used unless chosen otherwise, as in zlib's default memLevel
+pub const DEFAULT_HASH: usize = 32768;
+/// Tokens collected per block unless chosen otherwise, as in zlib's default memLevel
+pub const DEFAULT_BLOCK_TOKENS: usize = 16384;
+
+// Matches this short are dropped by `Strategy::Filtered`
+const FILTERED_MAX_DROP: usize = 5;
+// Input kept ahead of the current position so matches are not cut short by a chunk
+// boundary: a longest match plus the bytes hashed after it
+const MIN_LOOKAHEAD: usize = MAX_MATCH + MIN_MATCH + 1;
+// Compressed bytes waiting for room in the caller's output
+const PENDING: usize = 1024;
+
+/// How far `Deflater::compress` should push the data it has been given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flush {
+    /// Compress as input allows; some of it may stay buffered
+    None,
+    /// Emit everything so far and byte-align the output with an empty stored block, so
+    /// the receiver can decode all of it
+    Sync,
+    /// As `Sync`, and forget the history so decoding can restart from this point
+    Full,
+    /// Emit everything, the final block and the zlib or gzip trailer; the stream then ends
+    Finish,
+}
+
+// Format around the DEFLATE data
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Container {
+    Raw,
+    Zlib,
+    // Member data and trailer; the caller writes the header
+    Gzip,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    Header,
+    Compress,
+    FlushMarker { full: bool },
+    Trailer,
+    Done,
+}
+
+// Why `parse` stopped
+enum Parsed {
+    BlockFull,
+    NeedsInput,
+    // All input is compressed and the flush asked for can be completed
+    Flushed,
+}
+
+/// Resumable DEFLATE compressor writing into caller-provided output slices, in the manner
+/// of zlib's `deflate()`.
+///
+/// Input can be passed in pieces of any size; output is produced as blocks complete, or
+/// earlier when a `Flush` asks for it. Nothing is allocated: the buffers are part of the
+/// struct and sized by its parameters, so `size_of::<Deflater<..>>()` is the whole memory
+/// footprint.
+///
+/// - `WINDOW`: how far back matches reach, a power of two from 256 to 32768. The zlib
+///   header declares it (CINFO), so an `Inflater::<WINDOW>` can decode the output. Two
+///   windows of input are buffered.
+/// - `HASH`: hash table entries, a power of two from 256 to 65536; fewer means more
+///   collisions and shorter matches at higher levels.
+/// - `BLOCK_TOKENS`: literals and matches collected per block, three bytes each; fewer
+///   means more block headers.
+///
+/// The defaults match zlib and need around 240 KiB, so keep such a compressor in a
+/// `static` or a `Box` rather than on a small stack. `Deflater<1024, 1024, 1024>` needs
+/// under 16 KiB.
+pub struct Deflater<const WINDOW: usize = MAX_WINDOW, const HASH: usize = DEFAULT_HASH, const BLOCK_TOKENS: usize = DEFAULT_BLOCK_TOKENS> {
+    stage: Stage,
+    container: Container,
+    level: u8,
+    strategy: Strategy,
+    // The last WINDOW bytes of history plus as much new input again
+    window: [[u8; WINDOW]; 2],
+    // Current position in the window and bytes available after it
+    strstart: usize,
+    lookahead: usize,
+    // Window range covered by the tokens of the current block; the start goes negative
+    // once the window slides past it, and a stored block is then no longer possible
+    block_start: isize,
+    token_end: usize,
+    // Matches may not reach back before this position (after a full flush)
+    history_start: usize,
+    matcher: MatchFinder<WINDOW, HASH>,
+    // Lazy matching: the match found at the previous position, not yet emitted
+    match_available: bool,
+    match_length: usize,
+    match_dist: usize,
+    block: Block<BLOCK_TOKENS>,
+    writer: BlockWriter,
+    bw: BitWriter<PENDING>,
+    adler: Adler32,
+    // Adler-32 of the preset dictionary, if one was set
+    dict_id: Option<u32>,
+    // Gzip trailer: CRC-32 and length modulo 2^32 of the input
+    crc: Crc32,
+    isize: u32,
+    // No input taken since the last sync or full flush marker
+    flushed: bool,
+}
+
+impl Deflater {
+    /// Compressor producing a zlib stream: header, DEFLATE data and Adler-32 trailer.
+    /// `level` is as for `deflate_zlib`.
+    pub fn new_zlib(level: u8) -> Self {
+        Self::new(Container::Zlib, level)
+    }
+
+    /// Compressor producing raw DEFLATE data.
+    pub fn new_raw(level: u8) -> Self {
+        Self::new(Container::Raw, level)
+    }
+
+    /// Compressor producing the body of a gzip member: DEFLATE data and the CRC-32/ISIZE
+    /// trailer. Write the member header first, e.g. with `GzipHeader::write_to`.
+    pub fn new_gzip(level: u8) -> Self {
+        Self::new(Container::Gzip, level)
+    }
+}
+
+impl<const WINDOW: usize, const HASH: usize, const BLOCK_TOKENS: usize> Deflater<WINDOW, HASH, BLOCK_TOKENS> {
+    // Lookahead kept before the window slides; small windows keep less so that at least
+    // half a window of history survives a slide
+    const LOOKAHEAD: usize = if MIN_LOOKAHEAD < WINDOW / 2 { MIN_LOOKAHEAD } else { WINDOW / 2 };
+
+    /// Compressor for a zlib stream with `WINDOW` bytes of history, e.g.
+    /// `Deflater::<1024, 1024, 1024>::new_zlib_windowed(6)`.
+    pub fn new_zlib_windowed(level: u8) -> Self {
+        Self::new(Container::Zlib, level)
+    }
+
+    /// Compressor for raw DEFLATE data whose distances stay within `WINDOW` bytes.
+    pub fn new_raw_windowed(level: u8) -> Self {
+        Self::new(Container::Raw, level)
+    }
+
+    /// Compressor for the body of a gzip member with `WINDOW` bytes of history.
+    pub fn new_gzip_windowed(level: u8) -> Self {
+        Self::new(Container::Gzip, level)
+    }
+
+    fn new(container: Container, level: u8) -> Self {
+        const { assert!(WINDOW.is_power_of_two() && WINDOW >= MIN_WINDOW && WINDOW <= MAX_WINDOW, "window must be a power of two from 256 to 32768") };
+        const { assert!(HASH.is_power_of_two() && HASH >= 256 && HASH <= 65536, "hash size must be a power of two from 256 to 65536") };
+        const { assert!(BLOCK_TOKENS > 0, "blocks need room for at least one token") };
+        Deflater {
+            stage: Stage::Header,
+            container,
+            level: level.min(9),
+            strategy: Strategy::Default,
+            window: [[0; WINDOW]; 2],
+            strstart: 0,
+            lookahead: 0,
+            block_start: 0,
+            token_end: 0,
+            history_start: 0,
+            matcher: MatchFinder::new(),
+            match_available: false,
+            match_length: 0,
+            match_dist: 0,
+            block: Block::new(),
+            writer: BlockWriter::new(),
+            bw: BitWriter::new(),
+            adler: Adler32::new(),
+            dict_id: None,
+            crc: Crc32::new(),
+            isize: 0,
+            flushed: false,
+        }
+    }
+
+    /// Search for matches as `strategy` says. Call before compressing starts, as the zlib
+    /// header reflects it.
+    pub fn set_strategy(&mut self, strategy: Strategy) {
+        self.strategy = strategy;
+    }
+
+    /// Prime the window with a preset dictionary, as zlib's `deflateSetDictionary`: matches
+    /// may refer to its last `WINDOW` bytes as if they preceded the input. A zlib stream
+    /// then declares it with FDICT and DICTID, and the decoder needs the same dictionary.
+    ///
+    /// # Panics
+    /// If compressing has started or a non-empty dictionary was already set; `reset` makes
+    /// room for a new one.
+    pub fn set_dictionary(&mut self, dict: &[u8]) {
+        assert!(self.stage == Stage::Header && self.strstart == 0, "set_dictionary after compressing started");
+        let mut adler = Adler32::new();
+        adler.update(dict);
+        self.dict_id = Some(adler.finish());
+
+        let dict = &dict[dict.len().saturating_sub(WINDOW)..];
+        self.window.as_flattened_mut()[..dict.len()].copy_from_slice(dict);
+        for pos in 0..dict.len() {
+            self.matcher.insert(dict, pos);
+        }
+        self.strstart = dict.len();
+        self.block_start = dict.len() as isize;
+        self.token_end = dict.len();
+    }
+
+    /// Start a new stream with the same container, level and strategy, reusing this
+    /// compressor's memory in place rather than building a new one on the stack. Any
+    /// preset dictionary is dropped.
+    pub fn reset(&mut self) {
+        self.stage = Stage::Header;
+        self.strstart = 0;
+        self.lookahead = 0;
+        self.block_start = 0;
+        self.token_end = 0;
+        self.history_start = 0;
+        // The window keeps its bytes, but with the chains cleared nothing refers to them
+        self.matcher.clear();
+        self.match_available = false;
+        self.match_length = 0;
+        self.match_dist = 0;
+        self.block.clear();
+        self.writer = BlockWriter::new();
+        self.bw = BitWriter::new();
+        self.adler = Adler32::new();
+        self.dict_id = None;
+        self.crc = Crc32::new();
+        self.isize = 0;
+        self.flushed = false;
+    }
+
+    /// True once `Flush::Finish` has completed and all output has been taken.
+    pub fn is_done(&self) -> bool {
+        self.stage == Stage::Done && self.bw.is_empty()
+    }
+
+    /// Compress as much of `input` into `output` as possible.
+    ///
+    /// Returns `NeedsInput` once all of `input` is taken and the requested flush is
+    /// complete, `NeedsOutput` when `output` filled up first (call again with the rest of
+    /// the input and the same flush), and `Done` once a `Finish` has been fully written.
+    pub fn compress(&mut self, input: &[u8], output: &mut [u8], flush: Flush) -> Status {
+        let mut in_pos = 0usize;
+        let mut out_pos = 0usize;
+        let state = loop {
+            out_pos += self.bw.drain_into(&mut output[out_pos..]);
+            let out_full = out_pos == output.len();
+
+            if self.writer.is_busy() {
+                let raw = block_raw(self.window.as_flattened(), self.block_start, self.token_end);
+                if self.writer.resume(&self.block.tokens(), raw, &mut self.bw) {
+                    self.block.clear();
+                    self.block_start = self.token_end as isize;
+                } else if out_full {
+                    break StreamState::NeedsOutput;
+                }
+                continue;
+            }
+
+            match self.stage {
+                Stage::Header => {
+                    if self.container == Container::Zlib {
+                        self.bw.write_bytes(&zlib_header(self.level, self.strategy, WINDOW, self.dict_id.is_some()));
+                        if let Some(dict_id) = self.dict_id {
+                            self.bw.write_bytes(&dict_id.to_be_bytes());
+                        }
+                    }
+                    self.stage = Stage::Compress;
+                }
+                Stage::Compress => match self.parse(input, &mut in_pos, flush) {
+                    Parsed::BlockFull => self.start_block(false),
+                    Parsed::NeedsInput => break self.idle_state(),
+                    Parsed::Flushed => match flush {
+                        Flush::None => unreachable!("parse only completes a flush when asked to"),
+                        Flush::Finish => {
+                            self.start_block(true);
+                            self.stage = Stage::Trailer;
+                        }
+                        // Repeated flushes with no new input add nothing
+                        Flush::Sync | Flush::Full if self.flushed => break self.idle_state(),
+                        Flush::Sync | Flush::Full => {
+                            if !self.block.is_empty() {
+                                self.start_block(false);
+                            }
+                            self.stage = Stage::FlushMarker { full: flush == Flush::Full };
+                        }
+                    },
+                },
+                Stage::FlushMarker { full } => {
+                    if !write_empty_stored(&mut self.bw, false) {
+                        if out_full {
+                            break StreamState::NeedsOutput;
+                        }
+                        continue;
+                    }
+                    if full {
+                        self.matcher.clear();
+                        self.history_start = self.strstart;
+                    }
+                    self.flushed = true;
+                    self.stage = Stage::Compress;
+                }
+                Stage::Trailer => {
+                    if self.bw.room() < 8 {
+                        if out_full {
+                            break StreamState::NeedsOutput;
+                        }
+                        continue;
+                    }
+                    self.bw.align_to_byte();
+                    match self.container {
+                        Container::Raw => {}
+                        Container::Zlib => self.bw.write_bytes(&self.adler.finish().to_be_bytes()),
+                        Container::Gzip => {
+                            self.bw.write_bytes(&self.crc.finish().to_le_bytes());
+                            self.bw.write_bytes(&self.isize.to_le_bytes());
+                        }
+                    }
+                    self.stage = Stage::Done;
+                }
+                Stage::Done => break if self.bw.is_empty() { StreamState::Done } else { StreamState::NeedsOutput },
+            }
+        };
+        Status { consumed: in_pos, produced: out_pos, state }
+    }
+
+    // Out of input: done for now unless compressed bytes are still waiting for room
+    fn idle_state(&self) -> StreamState {
+        if self.bw.is_empty() { StreamState::NeedsInput } else { StreamState::NeedsOutput }
+    }
+
+    fn start_block(&mut self, last: bool) {
+        let raw = block_raw(self.window.as_flattened(), self.block_start, self.token_end);
+        let types = match self.strategy {
+            _ if self.level == 0 => BlockTypes::Stored,
+            Strategy::Fixed => BlockTypes::StoredOrFixed,
+            _ => BlockTypes::Any,
+        };
+        self.writer.start(&self.block.tokens(), raw, last, types, self.bw.bit_offset());
+    }
+
+    // Turn input into tokens until the block fills up, more input is needed, or (when
+    // flushing) everything has been tokenized.
+    fn parse(&mut self, input: &[u8], in_pos: &mut usize, flush: Flush) -> Parsed {
+        let cfg = &CONFIGS[self.level as usize];
+        loop {
+            if self.lookahead < Self::LOOKAHEAD {
+                // Stored blocks need their data, so write the block before it slides out
+                if self.level == 0 && self.must_slide() && !self.block.is_empty() && self.block_start < WINDOW as isize {
+                    return Parsed::BlockFull;
+                }
+                self.fill_window(input, in_pos);
+                if self.lookahead < Self::LOOKAHEAD && flush == Flush::None {
+                    return Parsed::NeedsInput;
+                }
+                if self.lookahead == 0 {
+                    break;
+                }
+            }
+            match (self.level as usize, self.strategy) {
+                (0, _) => self.step_stored(),
+                (_, Strategy::HuffmanOnly) => self.step_stored(),
+                (_, Strategy::Rle) => self.step_rle(),
+                (level, _) if level < FIRST_LAZY_LEVEL => self.step_greedy(cfg),
+                _ => self.step_lazy(cfg),
+            }
+            if self.block.is_full() {
+                return Parsed::BlockFull;
+            }
+        }
+        if self.match_available {
+            self.literal(self.strstart - 1);
+            self.match_available = false;
+            if self.block.is_full() {
+                return Parsed::BlockFull;
+            }
+        }
+        Parsed::Flushed
+    }
+
+    fn must_slide(&self) -> bool {
+        self.strstart >= 2 * WINDOW - Self::LOOKAHEAD
+    }
+
+    // Move the upper half of the window down once the position nears the end, then top up
+    // the lookahead from `input`
+    fn fill_window(&mut self, input: &[u8], in_pos: &mut usize) {
+        if self.must_slide() {
+            self.window[0] = self.window[1];
+            self.strstart -= WINDOW;
+            self.token_end -= WINDOW;
+            self.block_start -= WINDOW as isize;
+            self.history_start = self.history_start.saturating_sub(WINDOW);
+            self.matcher.slide();
+        }
+        let end = self.strstart + self.lookahead;
+        let n = (2 * WINDOW - end).min(input.len() - *in_pos);
+        if n == 0 {
+            return;
+        }
+        let chunk = &input[*in_pos..*in_pos + n];
+        self.window.as_flattened_mut()[end..end + n].copy_from_slice(chunk);
+        match self.container {
+            Container::Raw => {}
+            Container::Zlib => self.adler.update(chunk),
+            Container::Gzip => {
+                self.crc.update(chunk);
+                self.isize = self.isize.wrapping_add(n as u32);
+            }
+        }
+        *in_pos += n;
+        self.lookahead += n;
+        self.flushed = false;
+    }
+
+    fn literal(&mut self, pos: usize) {
+        self.block.push_literal(self.window.as_flattened()[pos]);
+        self.token_end = pos + 1;
+    }
+
+    fn emit_match(&mut self, pos: usize, length: usize, distance: usize) {
+        self.block.push_match(length, distance);
+        self.token_end = pos + length;
+    }
+
+    // Search for a match at the current position longer than `prev_length`
+    fn find_match(&self, prev_length: usize, cfg: &Config) -> (usize, usize) {
+        let data = &self.window.as_flattened()[..self.strstart + self.lookahead];
+        let chain = if prev_length >= cfg.good_length { cfg.max_chain / 4 } else { cfg.max_chain };
+        self.matcher.longest_match(data, self.strstart, prev_length, chain.max(1), cfg.nice_length)
+    }
+
+    fn insert(&mut self, pos: usize) {
+        self.matcher.insert(&self.window.as_flattened()[..self.strstart + self.lookahead], pos);
+    }
+
+    // Every byte a literal: level 0, written as stored blocks, and `Strategy::HuffmanOnly`
+    fn step_stored(&mut self) {
+        self.literal(self.strstart);
+        self.strstart += 1;
+        self.lookahead -= 1;
+    }
+
+    // Take the longest match at each position (zlib's deflate_fast)
+    fn step_greedy(&mut self, cfg: &Config) {
+        self.insert(self.strstart);
+        let (length, distance) = self.find_match(0, cfg);
+        if length >= MIN_MATCH {
+            self.emit_match(self.strstart, length, distance);
+            // Skip hashing inside long matches to save time
+            if length <= cfg.max_lazy {
+                for pos in self.strstart + 1..self.strstart + length {
+                    self.insert(pos);
+                }
+            }
+            self.strstart += length;
+            self.lookahead -= length;
+        } else {
+            self.literal(self.strstart);
+            self.strstart += 1;
+            self.lookahead -= 1;
+        }
+    }
+
+    // Only runs of the previous byte, as distance-1 matches (zlib's deflate_rle)
+    fn step_rle(&mut self) {
+        let window = self.window.as_flattened();
+        let mut length = 0;
+        if self.strstart > self.history_start && self.lookahead >= MIN_MATCH {
+            let prev = window[self.strstart - 1];
+            let max_len = self.lookahead.min(MAX_MATCH);
+            length = window[self.strstart..self.strstart + max_len].iter().take_while(|&&b| b == prev).count();
+        }
+        if length >= MIN_MATCH {
+            self.emit_match(self.strstart, length, 1);
+            self.strstart += length;
+            self.lookahead -= length;
+        } else {
+            self.literal(self.strstart);
+            self.strstart += 1;
+            self.lookahead -= 1;
+        }
+    }
+
+    // Defer each match by one position and keep the longer of the two (zlib's deflate_slow)
+    fn step_lazy(&mut self, cfg: &Config) {
+        self.insert(self.strstart);
+        let (prev_length, prev_dist) = (self.match_length, self.match_dist);
+        self.match_length = 0;
+        if prev_length < cfg.max_lazy {
+            let (length, distance) = self.find_match(prev_length, cfg);
+            // Matches of the minimum length are not worth it this far back, nor short ones
+            // at all for filtered data
+            let too_short = if self.strategy == Strategy::Filtered { length <= FILTERED_MAX_DROP } else { length == MIN_MATCH && distance > TOO_FAR };
+            if !too_short {
+                self.match_length = length;
+                self.match_dist = distance;
+            }
+        }
+
+        if prev_length >= MIN_MATCH && self.match_length <= prev_length {
+            // The previous match wins; it started one byte back
+            self.emit_match(self.strstart - 1, prev_length, prev_dist);
+            for pos in self.strstart + 1..self.strstart - 1 + prev_length {
+                self.insert(pos);
+            }
+            self.strstart += prev_length - 1;
+            self.lookahead -= prev_length - 1;
+            self.match_available = false;
+            self.match_length = 0;
+        } else {
+            if self.match_available {
+                self.literal(self.strstart - 1);
+            }
+            self.match_available = true;
+            self.strstart += 1;
+            self.lookahead -= 1;
+        }
+    }
+}
+
+// Input covered by the current block, while it is still in the window
+fn block_raw(window: &[u8], block_start: isize, token_end: usize) -> Option<&[u8]> {
+    if block_start >= 0 { Some(&window[block_start as usize..token_end]) } else { None }
+}
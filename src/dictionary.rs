@@ -0,0 +1,149 @@
+// Preset dictionary training, in the manner of zstd's COVER trainer: substrings shared by
+// many samples are picked segment by segment, and the most valuable ones are placed at the
+// end of the dictionary, where matches from the data are shortest.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::deflate::{deflate_zlib, deflate_zlib_with_dictionary};
+
+// Length of the substrings counted across samples
+const DMER: usize = 6;
+// Dmers considered per candidate segment
+const SEGMENT: usize = 32;
+// Counts are kept per hash of a dmer; collisions only blur the scores slightly
+const HASH_BITS: u32 = 18;
+// Hash of a position where no dmer starts (too close to the end of its sample)
+const NONE: u32 = u32::MAX;
+
+fn hash(dmer: &[u8]) -> u32 {
+    let v = dmer.iter().fold(0u64, |v, &b| v << 8 | b as u64);
+    (v.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (64 - HASH_BITS)) as u32
+}
+
+/// Build a preset dictionary of at most `max_size` bytes (32 KiB is all DEFLATE can
+/// reach) from samples of the data it will be used on. Substrings are scored by how many
+/// samples contain them; ones found in a single sample are left out, as that sample can
+/// refer to them itself. Use the result with `deflate_zlib_with_dictionary` and
+/// `inflate_zlib_with_dictionary`.
+pub fn train(samples: &[&[u8]], max_size: usize) -> Vec<u8> {
+    let mut corpus = Vec::new();
+    let mut ranges = Vec::with_capacity(samples.len());
+    for sample in samples {
+        ranges.push(corpus.len()..corpus.len() + sample.len());
+        corpus.extend_from_slice(sample);
+    }
+
+    // Count each dmer once per sample that contains it
+    let mut freq = vec![0u32; 1 << HASH_BITS];
+    let mut seen = vec![usize::MAX; 1 << HASH_BITS];
+    let mut hashes = vec![NONE; corpus.len()];
+    for (i, range) in ranges.iter().enumerate() {
+        for pos in range.start..range.end.saturating_sub(DMER - 1) {
+            let h = hash(&corpus[pos..pos + DMER]);
+            hashes[pos] = h;
+            if seen[h as usize] != i {
+                seen[h as usize] = i;
+                freq[h as usize] += 1;
+            }
+        }
+    }
+    for f in freq.iter_mut().filter(|f| **f < 2) {
+        *f = 0;
+    }
+
+    // Pick the best segment of each epoch (a slice of the corpus) in turn, so the
+    // dictionary covers all of it, until it is full or nothing scores any more
+    let epochs = (max_size / SEGMENT).clamp(1, (corpus.len() / (4 * SEGMENT)).max(1));
+    let epoch_len = corpus.len().div_ceil(epochs).max(1);
+    let mut segments: Vec<(u64, Range<usize>)> = Vec::new();
+    let mut size = 0;
+    let mut idle = 0;
+    let mut epoch = 0;
+    while size < max_size && idle < epochs {
+        let bounds = epoch * epoch_len..((epoch + 1) * epoch_len).min(corpus.len());
+        match best_segment(&hashes, &freq, &ranges, bounds) {
+            Some((score, dmers)) => {
+                // Dmers now in the dictionary gain nothing from being picked again
+                for &h in hashes[dmers.clone()].iter().filter(|&&h| h != NONE) {
+                    freq[h as usize] = 0;
+                }
+                let bytes = dmers.start..dmers.end - 1 + DMER;
+                size += bytes.len();
+                segments.push((score, bytes));
+                idle = 0;
+            }
+            None => idle += 1,
+        }
+        epoch = (epoch + 1) % epochs;
+    }
+
+    // Most valuable last, closest to the data; trim the least valuable to fit
+    segments.sort_by_key(|(score, _)| *score);
+    let mut dict = Vec::with_capacity(size);
+    for (_, bytes) in &segments {
+        dict.extend_from_slice(&corpus[bytes.clone()]);
+    }
+    dict.drain(..dict.len().saturating_sub(max_size));
+    dict
+}
+
+// The window of up to `SEGMENT` dmer positions within `bounds` and a single sample with the
+// highest total score, trimmed of dmers scoring nothing at either end
+fn best_segment(hashes: &[u32], freq: &[u32], ranges: &[Range<usize>], bounds: Range<usize>) -> Option<(u64, Range<usize>)> {
+    let score = |pos: usize| if hashes[pos] == NONE { 0 } else { freq[hashes[pos] as usize] as u64 };
+    let mut best: Option<(u64, usize, usize)> = None;
+    for range in ranges.iter().filter(|r| r.end > bounds.start && r.start < bounds.end) {
+        let (start, end) = (range.start.max(bounds.start), range.end.min(bounds.end));
+        let mut sum = 0u64;
+        for pos in start..end {
+            sum += score(pos);
+            if pos >= start + SEGMENT {
+                sum -= score(pos - SEGMENT);
+            }
+            if best.is_none_or(|(b, _, _)| sum > b) {
+                best = Some((sum, (pos + 1).saturating_sub(SEGMENT).max(start), pos + 1));
+            }
+        }
+    }
+    let (total, mut first, mut end) = best.filter(|(total, _, _)| *total > 0)?;
+    while score(first) == 0 {
+        first += 1;
+    }
+    while score(end - 1) == 0 {
+        end -= 1;
+    }
+    Some((total, first..end))
+}
+
+/// Compressed sizes of one sample with and without a preset dictionary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SampleSaving {
+    /// Uncompressed size
+    pub original: usize,
+    /// zlib stream size compressed on its own
+    pub without_dictionary: usize,
+    /// zlib stream size compressed against the dictionary (including the 4-byte DICTID)
+    pub with_dictionary: usize,
+}
+
+impl SampleSaving {
+    /// Bytes saved by the dictionary; negative if it made the sample larger.
+    pub fn saved(&self) -> isize {
+        self.without_dictionary as isize - self.with_dictionary as isize
+    }
+}
+
+/// Estimate what `dict` is worth by compressing each sample at `level` both with and
+/// without it. Measure on samples held out from training for a fair figure.
+pub fn report(samples: &[&[u8]], dict: &[u8], level: u8) -> Vec<SampleSaving> {
+    samples
+        .iter()
+        .map(|sample| SampleSaving {
+            original: sample.len(),
+            without_dictionary: deflate_zlib(sample, level).len(),
+            with_dictionary: deflate_zlib_with_dictionary(sample, dict, level).len(),
+        })
+        .collect()
+}
@@ -0,0 +1,256 @@
+#[cfg(feature = "alloc")]
+use alloc::{boxed::Box, vec::Vec};
+use core::result::Result;
+
+use crate::checksum::Crc32;
+#[cfg(feature = "alloc")]
+use crate::deflate::{deflate_all, Deflater};
+#[cfg(feature = "alloc")]
+use crate::inflate::inflate_blocks;
+use crate::inflate::InflateError;
+
+// Member header flags (RFC 1952, section 2.3.1)
+const FTEXT: u8 = 0x01;
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+const FRESERVED: u8 = 0xe0;
+// Operating system "unknown"
+const OS_UNKNOWN: u8 = 255;
+
+/// Header fields of a gzip member. Optional fields borrow from the input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GzipHeader<'a> {
+    /// FTEXT: the compressor guessed the data is text
+    pub text: bool,
+    /// Modification time in seconds since the Unix epoch, 0 if not available
+    pub mtime: u32,
+    /// Extra flags (2 = maximum compression, 4 = fastest)
+    pub xfl: u8,
+    /// Operating system the member was written on (3 = Unix, 255 = unknown)
+    pub os: u8,
+    /// FEXTRA field contents, without the 2-byte length prefix
+    pub extra: Option<&'a [u8]>,
+    /// Original file name (ISO 8859-1), without the terminating zero
+    pub name: Option<&'a [u8]>,
+    /// File comment (ISO 8859-1), without the terminating zero
+    pub comment: Option<&'a [u8]>,
+    /// FHCRC: low 16 bits of the CRC-32 of the header, already verified
+    pub header_crc: Option<u16>,
+}
+
+/// One decompressed gzip member.
+#[cfg(feature = "alloc")]
+#[derive(Debug)]
+pub struct GzipMember<'a> {
+    pub header: GzipHeader<'a>,
+    pub data: Vec<u8>,
+    /// Number of input bytes taken by the member, trailer included
+    pub consumed: usize,
+}
+
+impl<'a> GzipHeader<'a> {
+    /// Parse a member header, returning it with the offset of the DEFLATE data.
+    pub fn parse(input: &'a [u8]) -> Result<(Self, usize), InflateError> {
+        if input.len() < 10 {
+            return Err(InflateError::InputTooShort);
+        }
+        if input[0] != 0x1f || input[1] != 0x8b {
+            return Err(InflateError::InvalidHeader);
+        }
+        if input[2] != 8 { // DEFLATE
+            return Err(InflateError::Unsupported);
+        }
+        let flg = input[3];
+        if flg & FRESERVED != 0 {
+            return Err(InflateError::InvalidHeader);
+        }
+        let mtime = u32::from_le_bytes([input[4], input[5], input[6], input[7]]);
+        let mut pos = 10usize;
+
+        let mut extra = None;
+        if flg & FEXTRA != 0 {
+            let xlen_bytes = input.get(pos..pos + 2).ok_or(InflateError::InputTooShort)?;
+            let xlen = u16::from_le_bytes([xlen_bytes[0], xlen_bytes[1]]) as usize;
+            pos += 2;
+            extra = Some(input.get(pos..pos + xlen).ok_or(InflateError::InputTooShort)?);
+            pos += xlen;
+        }
+        let mut name = None;
+        if flg & FNAME != 0 {
+            let field = read_zero_terminated(input, pos)?;
+            pos += field.len() + 1;
+            name = Some(field);
+        }
+        let mut comment = None;
+        if flg & FCOMMENT != 0 {
+            let field = read_zero_terminated(input, pos)?;
+            pos += field.len() + 1;
+            comment = Some(field);
+        }
+        let mut header_crc = None;
+        if flg & FHCRC != 0 {
+            let stored = input.get(pos..pos + 2).ok_or(InflateError::InputTooShort)?;
+            let expected = u16::from_le_bytes([stored[0], stored[1]]);
+            let mut crc = Crc32::new();
+            crc.update(&input[..pos]);
+            let actual = crc.finish() as u16;
+            if expected != actual {
+                return Err(InflateError::ChecksumMismatch { expected: expected as u32, actual: actual as u32 });
+            }
+            pos += 2;
+            header_crc = Some(expected);
+        }
+
+        let header = GzipHeader {
+            text: flg & FTEXT != 0,
+            mtime,
+            xfl: input[8],
+            os: input[9],
+            extra,
+            name,
+            comment,
+            header_crc,
+        };
+        Ok((header, pos))
+    }
+}
+
+impl Default for GzipHeader<'_> {
+    /// No optional fields, no modification time, unknown operating system.
+    fn default() -> Self {
+        GzipHeader { text: false, mtime: 0, xfl: 0, os: OS_UNKNOWN, extra: None, name: None, comment: None, header_crc: None }
+    }
+}
+
+impl GzipHeader<'_> {
+    /// Size of the header as `write_to` writes it.
+    pub fn encoded_len(&self) -> usize {
+        10 + self.extra.map_or(0, |e| 2 + e.len())
+            + self.name.map_or(0, |n| n.len() + 1)
+            + self.comment.map_or(0, |c| c.len() + 1)
+            + self.header_crc.map_or(0, |_| 2)
+    }
+
+    /// Write the member header to the start of `out`, returning its length, or `None` if
+    /// `out` is shorter than `encoded_len()`. A header CRC is written when `header_crc` is
+    /// `Some`; its value is computed, not taken from the field.
+    ///
+    /// # Panics
+    /// If `extra` is longer than 65535 bytes, or `name` or `comment` contains a zero byte.
+    pub fn write_to(&self, out: &mut [u8]) -> Option<usize> {
+        assert!(self.extra.is_none_or(|e| e.len() <= u16::MAX as usize), "gzip extra field too long");
+        assert!(!self.name.into_iter().chain(self.comment).any(|f| f.contains(&0)), "gzip name and comment are zero-terminated");
+        let len = self.encoded_len();
+        let out = out.get_mut(..len)?;
+        let mut flg = 0;
+        for (present, flag) in [(self.text, FTEXT), (self.header_crc.is_some(), FHCRC), (self.extra.is_some(), FEXTRA), (self.name.is_some(), FNAME), (self.comment.is_some(), FCOMMENT)] {
+            if present {
+                flg |= flag;
+            }
+        }
+        out[..4].copy_from_slice(&[0x1f, 0x8b, 8, flg]);
+        out[4..8].copy_from_slice(&self.mtime.to_le_bytes());
+        out[8] = self.xfl;
+        out[9] = self.os;
+        let mut pos = 10;
+        if let Some(extra) = self.extra {
+            out[pos..pos + 2].copy_from_slice(&(extra.len() as u16).to_le_bytes());
+            out[pos + 2..pos + 2 + extra.len()].copy_from_slice(extra);
+            pos += 2 + extra.len();
+        }
+        for field in [self.name, self.comment].into_iter().flatten() {
+            out[pos..pos + field.len()].copy_from_slice(field);
+            out[pos + field.len()] = 0;
+            pos += field.len() + 1;
+        }
+        if self.header_crc.is_some() {
+            let mut crc = Crc32::new();
+            crc.update(&out[..pos]);
+            out[pos..pos + 2].copy_from_slice(&(crc.finish() as u16).to_le_bytes());
+        }
+        Some(len)
+    }
+}
+
+fn read_zero_terminated(input: &[u8], start: usize) -> Result<&[u8], InflateError> {
+    let rest = input.get(start..).ok_or(InflateError::InputTooShort)?;
+    let end = rest.iter().position(|&b| b == 0).ok_or(InflateError::InputTooShort)?;
+    Ok(&rest[..end])
+}
+
+/// Decompress the first gzip member of `input`, verifying its CRC-32 and ISIZE trailer.
+#[cfg(feature = "alloc")]
+pub fn inflate_gzip_member(input: &[u8]) -> Result<GzipMember<'_>, InflateError> {
+    let (header, start) = GzipHeader::parse(input)?;
+
+    let mut data = Vec::new();
+    let mut crc = Crc32::new();
+    let consumed = inflate_blocks(&input[start..], &mut data, |block| crc.update(block))?;
+
+    // Trailer: CRC32 then ISIZE (length modulo 2^32), both LSB first
+    let trailer_pos = start + consumed;
+    let trailer = input.get(trailer_pos..trailer_pos + 8).ok_or(InflateError::TruncatedTrailer)?;
+    let expected = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let actual = crc.finish();
+    if expected != actual {
+        return Err(InflateError::ChecksumMismatch { expected, actual });
+    }
+    let expected = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+    let actual = data.len() as u32;
+    if expected != actual {
+        return Err(InflateError::SizeMismatch { expected: expected as u64, actual: actual as u64 });
+    }
+
+    Ok(GzipMember { header, data, consumed: trailer_pos + 8 })
+}
+
+/// Decompress a gzip file. Concatenated members are decoded in turn and their
+/// data joined, as `gunzip` does.
+#[cfg(feature = "alloc")]
+pub fn inflate_gzip(input: &[u8]) -> Result<Vec<u8>, InflateError> {
+    let mut out = Vec::new();
+    let mut pos = 0usize;
+    loop {
+        let member = inflate_gzip_member(&input[pos..])?;
+        out.extend_from_slice(&member.data);
+        pos += member.consumed;
+        if pos == input.len() {
+            break;
+        }
+    }
+    Ok(out)
+}
+
+/// Compress `data` into a gzip file of one member with the given header fields. XFL is
+/// set from `level` as zlib sets it (2 for level 9, 4 for levels 0 and 1).
+///
+/// # Panics
+/// If `header` is one `GzipHeader::write_to` rejects.
+#[cfg(feature = "alloc")]
+pub fn deflate_gzip(data: &[u8], level: u8, header: &GzipHeader) -> Vec<u8> {
+    let mut out = Vec::new();
+    append_gzip_member(&mut out, data, level, header);
+    out
+}
+
+/// Compress `data` as a new gzip member at the end of `file`, leaving the members
+/// already there untouched. Readers join the members' data, so this appends to the
+/// decompressed file without recompressing it.
+///
+/// # Panics
+/// If `header` is one `GzipHeader::write_to` rejects.
+#[cfg(feature = "alloc")]
+pub fn append_gzip_member(file: &mut Vec<u8>, data: &[u8], level: u8, header: &GzipHeader) {
+    let xfl = match level {
+        0 | 1 => 4,
+        9.. => 2,
+        _ => 0,
+    };
+    let header = GzipHeader { xfl, ..header.clone() };
+    let start = file.len();
+    file.resize(start + header.encoded_len(), 0);
+    header.write_to(&mut file[start..]);
+    file.extend_from_slice(&deflate_all(Box::new(Deflater::new_gzip(level)), data));
+}
@@ -1,13 +1,21 @@
+#[cfg(feature = "alloc")]
 use alloc::vec::Vec;
+use core::fmt;
 use core::result::Result;
 
+use crate::checksum::Adler32;
 use crate::inflate::bitreader::BitReader;
-use crate::inflate::huffman::{HuffmanTable, build_fixed_litlen_table, build_fixed_dist_table};
+use crate::inflate::huffman::{CodeLenTable, DistTable, EntryKind, LitLenTable, build_fixed_litlen_table, build_fixed_dist_table};
+use crate::inflate::output::{Output, SliceOutput};
 
 pub mod bitreader;
 pub mod huffman;
+pub(crate) mod output;
+pub mod stream;
 
-#[derive(Debug)]
+pub use stream::{Inflater, Status, StreamState, MAX_WINDOW, MIN_WINDOW};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum InflateError {
     InputTooShort,
     InvalidHeader,
@@ -15,9 +23,132 @@ pub enum InflateError {
     BadBlockData,
     BadHuffmanCode,
     OutputOverflow,
+    /// The stream ended before the 4-byte Adler-32 trailer
+    TruncatedTrailer,
+    /// The checksum stored in the stream does not match the decompressed data
+    ChecksumMismatch { expected: u32, actual: u32 },
+    /// The length stored in the stream does not match the decompressed data
+    SizeMismatch { expected: u64, actual: u64 },
+    /// The stream was compressed against a preset dictionary with this Adler-32 and none was given
+    DictionaryRequired { dictid: u32 },
+    /// The supplied dictionary is not the one the stream was compressed against
+    DictionaryMismatch { expected: u32, actual: u32 },
+    /// A back reference reaches further than the window the decoder keeps or the stream declared
+    DistanceTooFarBack,
+    /// The zlib header declares a window larger than the decoder's
+    WindowTooLarge { declared: usize },
+}
+
+impl fmt::Display for InflateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InflateError::InputTooShort => write!(f, "compressed input ended unexpectedly"),
+            InflateError::InvalidHeader => write!(f, "invalid stream header"),
+            InflateError::Unsupported => write!(f, "unsupported compression method or block type"),
+            InflateError::BadBlockData => write!(f, "invalid block data"),
+            InflateError::BadHuffmanCode => write!(f, "invalid Huffman code"),
+            InflateError::OutputOverflow => write!(f, "output buffer too small"),
+            InflateError::TruncatedTrailer => write!(f, "stream trailer is truncated"),
+            InflateError::ChecksumMismatch { expected, actual } =>
+                write!(f, "checksum mismatch: expected {:#010x}, got {:#010x}", expected, actual),
+            InflateError::SizeMismatch { expected, actual } =>
+                write!(f, "length mismatch: expected {}, got {}", expected, actual),
+            InflateError::DictionaryRequired { dictid } =>
+                write!(f, "preset dictionary {:#010x} required", dictid),
+            InflateError::DictionaryMismatch { expected, actual } =>
+                write!(f, "wrong preset dictionary: expected {:#010x}, got {:#010x}", expected, actual),
+            InflateError::DistanceTooFarBack => write!(f, "back reference distance too far back"),
+            InflateError::WindowTooLarge { declared } =>
+                write!(f, "stream window of {} bytes exceeds the decoder window", declared),
+        }
+    }
 }
 
+#[cfg(feature = "std")]
+impl std::error::Error for InflateError {}
+
+// Base lengths and extra bits of length symbols 257..=285 (RFC 1951, section 3.2.5)
+pub(crate) const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+pub(crate) const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+// Base distances and extra bits of distance symbols 0..=29
+pub(crate) const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+pub(crate) const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+// Order in which the code length code lengths are stored in a dynamic block header
+pub(crate) const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// Decompress a zlib (RFC 1950) stream: header, DEFLATE data and Adler-32 trailer.
+///
+/// Streams compressed against a preset dictionary fail with `DictionaryRequired`;
+/// use `inflate_zlib_with_dictionary` for those.
+#[cfg(feature = "alloc")]
 pub fn inflate_zlib(input: &[u8]) -> Result<Vec<u8>, InflateError> {
+    inflate_zlib_inner(input, None, false)
+}
+
+/// Decompress a zlib stream like `inflate_zlib`, but fail with `DistanceTooFarBack` on any
+/// back reference further than the window its header declares (CINFO).
+///
+/// The one-shot decoder keeps all of its output, so it would otherwise follow such a
+/// reference; a streaming decoder sized for the declared window could not.
+#[cfg(feature = "alloc")]
+pub fn inflate_zlib_enforcing_window(input: &[u8]) -> Result<Vec<u8>, InflateError> {
+    inflate_zlib_inner(input, None, true)
+}
+
+/// Decompress a zlib stream that may have been compressed against the preset dictionary `dict`.
+///
+/// The dictionary's Adler-32 must match the DICTID in the header. Streams without FDICT
+/// set are decoded normally and the dictionary is ignored.
+#[cfg(feature = "alloc")]
+pub fn inflate_zlib_with_dictionary(input: &[u8], dict: &[u8]) -> Result<Vec<u8>, InflateError> {
+    inflate_zlib_inner(input, Some(dict), false)
+}
+
+/// Decompress a zlib stream into `out` without allocating, returning the number of bytes written.
+///
+/// Fails with `OutputOverflow` if the decompressed data does not fit in `out`.
+pub fn inflate_zlib_into(input: &[u8], out: &mut [u8]) -> Result<usize, InflateError> {
+    let (start, _) = parse_zlib_header(input, None)?;
+    let mut sink = SliceOutput::new(out);
+    let mut adler = Adler32::new();
+    let consumed = inflate_blocks(&input[start..], &mut sink, |block| adler.update(block))?;
+    check_zlib_trailer(input, start + consumed, &adler)?;
+    Ok(sink.written().len())
+}
+
+#[cfg(feature = "alloc")]
+fn inflate_zlib_inner(input: &[u8], dict: Option<&[u8]>, enforce_window: bool) -> Result<Vec<u8>, InflateError> {
+    let (start, history) = parse_zlib_header(input, dict)?;
+    // The header was validated, so CINFO is at most 7
+    let max_dist = if enforce_window { 1usize << ((input[0] >> 4) + 8) } else { usize::MAX };
+
+    // A preset dictionary is placed in front of the output so back references can reach it.
+    let mut out = history.to_vec();
+    let mut adler = Adler32::new();
+    let consumed = inflate_blocks_windowed(&input[start..], &mut out, max_dist, |block| adler.update(block))?;
+    out.drain(..history.len());
+
+    check_zlib_trailer(input, start + consumed, &adler)?;
+    Ok(out)
+}
+
+// Validate the zlib header, returning where the DEFLATE data starts and the preset
+// dictionary (empty if none) it was compressed against.
+fn parse_zlib_header<'d>(input: &[u8], dict: Option<&'d [u8]>) -> Result<(usize, &'d [u8]), InflateError> {
     // Parse zlib header: 2 bytes
     if input.len() < 2 {
         return Err(InflateError::InputTooShort);
@@ -25,27 +156,72 @@ pub fn inflate_zlib(input: &[u8]) -> Result<Vec<u8>, InflateError> {
     let cmf = input[0];
     let flg = input[1];
     // Check checkbits
-    if ((cmf as u16) << 8 | flg as u16) % 31 != 0 {
+    if !((cmf as u16) << 8 | flg as u16).is_multiple_of(31) {
         return Err(InflateError::InvalidHeader);
     }
     let cm = cmf & 0x0f;
     if cm != 8 { // DEFLATE
         return Err(InflateError::Unsupported);
     }
+    // CINFO: log2 of the window size minus 8; windows beyond 32 KiB are not allowed
+    let cinfo = cmf >> 4;
+    if cinfo > 7 {
+        return Err(InflateError::InvalidHeader);
+    }
     let fdict = (flg & 0x20) != 0;
-    if fdict {
-        // We won't support preset dictionaries
-        return Err(InflateError::Unsupported);
+    if !fdict {
+        return Ok((2, &[]));
     }
-    // The remaining of the buffer is deflate stream plus optional Adler32 at end.
-    // We'll ignore the Adler32 check for now (future improvement).
+    // DICTID: Adler32 of the preset dictionary, MSB first
+    let id = input.get(2..6).ok_or(InflateError::InputTooShort)?;
+    let dictid = u32::from_be_bytes([id[0], id[1], id[2], id[3]]);
+    let dict = dict.ok_or(InflateError::DictionaryRequired { dictid })?;
+    let mut dict_adler = Adler32::new();
+    dict_adler.update(dict);
+    let actual = dict_adler.finish();
+    if actual != dictid {
+        return Err(InflateError::DictionaryMismatch { expected: dictid, actual });
+    }
+    Ok((6, dict))
+}
 
-    // Prepare bitreader to point at deflate stream starting at input[2]
-    let mut br = BitReader::new(&input[2..]);
+// The deflate stream is followed by the Adler32 of the output, at the next byte boundary, MSB first
+fn check_zlib_trailer(input: &[u8], pos: usize, adler: &Adler32) -> Result<(), InflateError> {
+    let trailer = input.get(pos..pos + 4).ok_or(InflateError::TruncatedTrailer)?;
+    let expected = u32::from_be_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let actual = adler.finish();
+    if expected != actual {
+        return Err(InflateError::ChecksumMismatch { expected, actual });
+    }
+    Ok(())
+}
+
+/// Decompress a raw DEFLATE (RFC 1951) stream with no container around it.
+///
+/// Returns the decompressed data and the number of input bytes up to and including
+/// the byte holding the end of the final block; anything after that is left to the caller.
+#[cfg(feature = "alloc")]
+pub fn inflate_raw(input: &[u8]) -> Result<(Vec<u8>, usize), InflateError> {
     let mut out = Vec::new();
+    let consumed = inflate_blocks(input, &mut out, |_| {})?;
+    Ok((out, consumed))
+}
+
+// Decode DEFLATE blocks up to the final one, appending to `out`. `on_block` sees the output
+// of each block once it is complete. Returns the number of input bytes consumed.
+pub(crate) fn inflate_blocks<O: Output, F: FnMut(&[u8])>(input: &[u8], out: &mut O, on_block: F) -> Result<usize, InflateError> {
+    inflate_blocks_windowed(input, out, usize::MAX, on_block)
+}
+
+// `inflate_blocks`, failing with `DistanceTooFarBack` on distances beyond `max_dist`
+fn inflate_blocks_windowed<O: Output, F: FnMut(&[u8])>(input: &[u8], out: &mut O, max_dist: usize, mut on_block: F) -> Result<usize, InflateError> {
+    let mut br = BitReader::new(input);
+    // Built at the first fixed block and shared by the rest
+    let mut fixed: Option<(LitLenTable, DistTable)> = None;
 
     // Main loop over blocks
     loop {
+        let block_start = out.written().len();
         let bfinal = br.read_bits(1).ok_or(InflateError::InputTooShort)? as u8;
         let btype = br.read_bits(2).ok_or(InflateError::InputTooShort)? as u8;
         match btype {
@@ -65,129 +241,84 @@ pub fn inflate_zlib(input: &[u8]) -> Result<Vec<u8>, InflateError> {
                 }
                 for _ in 0..len {
                     let b = br.read_byte().ok_or(InflateError::InputTooShort)?;
-                    out.push(b);
+                    out.push(b)?;
                 }
             }
             1 | 2 => {
                 // Huffman compressed block (1=Fixed, 2=Dynamic)
-                let litlen_table: HuffmanTable;
-                let dist_table: HuffmanTable;
-                if btype == 1 {
-                    litlen_table = build_fixed_litlen_table();
-                    dist_table = build_fixed_dist_table();
+                let dynamic: (LitLenTable, DistTable);
+                let (litlen_table, dist_table) = if btype == 1 {
+                    let (litlen, dist) = fixed.get_or_insert_with(|| (build_fixed_litlen_table(), build_fixed_dist_table()));
+                    (&*litlen, &*dist)
                 } else {
                     // dynamic Huffman codes
                     // read HLIT, HDIST, HCLEN
                     let hlit = br.read_bits(5).ok_or(InflateError::InputTooShort)? as usize + 257;
                     let hdist = br.read_bits(5).ok_or(InflateError::InputTooShort)? as usize + 1;
                     let hclen = br.read_bits(4).ok_or(InflateError::InputTooShort)? as usize + 4;
-                    let code_len_order = [16usize,17,18,0,8,7,9,6,10,5,11,4,12,3,13,2,14,1,15];
                     let mut clens = [0u8; 19];
-                    for i in 0..hclen {
-                        clens[code_len_order[i]] = br.read_bits(3).ok_or(InflateError::InputTooShort)? as u8;
+                    for &i in CODE_LENGTH_ORDER.iter().take(hclen) {
+                        clens[i] = br.read_bits(3).ok_or(InflateError::InputTooShort)? as u8;
                     }
-                    let cl_table = HuffmanTable::from_lengths(&clens)?;
-                    // read HLIT + HDIST code lengths using the code length Huffman
-                    let mut litlen_lens: Vec<u8> = Vec::new();
-                    litlen_lens.resize(hlit, 0u8);
-                    let mut dist_lens: Vec<u8> = Vec::new();
-                    dist_lens.resize(hdist, 0u8);
+                    let cl_table = CodeLenTable::from_lengths(&clens)?;
+                    // read HLIT + HDIST code lengths using the code length Huffman;
+                    // repeats may run from the litlen lengths into the dist lengths
+                    let mut lens = [0u8; 288 + 32];
+                    let total = hlit + hdist;
                     let mut idx = 0usize;
-                    while idx < hlit + hdist {
+                    while idx < total {
                         let sym = cl_table.read_symbol(&mut br)?;
-                        match sym {
-                            0..=15 => {
-                                if idx < hlit { litlen_lens[idx] = sym as u8; } else { dist_lens[idx - hlit] = sym as u8; }
-                                idx += 1;
-                            }
+                        let (val, repeat) = match sym {
+                            0..=15 => (sym as u8, 1),
                             16 => {
                                 // repeat previous 3-6 times
-                                let repeat = br.read_bits(2).ok_or(InflateError::InputTooShort)? + 3;
                                 if idx == 0 { return Err(InflateError::BadHuffmanCode); }
-                                let val = if idx <= hlit { litlen_lens[idx-1] } else { dist_lens[idx - hlit - 1] };
-                                for _ in 0..repeat {
-                                    if idx < hlit { litlen_lens[idx] = val; } else { dist_lens[idx - hlit] = val; }
-                                    idx += 1;
-                                }
-                            }
-                            17 => {
-                                // repeat zero 3-10 times
-                                let repeat = br.read_bits(3).ok_or(InflateError::InputTooShort)? + 3;
-                                for _ in 0..repeat {
-                                    if idx < hlit { litlen_lens[idx] = 0; } else { dist_lens[idx - hlit] = 0; }
-                                    idx += 1;
-                                }
-                            }
-                            18 => {
-                                // repeat zero 11-138 times
-                                let repeat = br.read_bits(7).ok_or(InflateError::InputTooShort)? + 11;
-                                for _ in 0..repeat {
-                                    if idx < hlit { litlen_lens[idx] = 0; } else { dist_lens[idx - hlit] = 0; }
-                                    idx += 1;
-                                }
+                                (lens[idx - 1], br.read_bits(2).ok_or(InflateError::InputTooShort)? as usize + 3)
                             }
+                            // repeat zero 3-10 times
+                            17 => (0, br.read_bits(3).ok_or(InflateError::InputTooShort)? as usize + 3),
+                            // repeat zero 11-138 times
+                            18 => (0, br.read_bits(7).ok_or(InflateError::InputTooShort)? as usize + 11),
                             _ => return Err(InflateError::BadHuffmanCode)
-                        }
+                        };
+                        if idx + repeat > total { return Err(InflateError::BadHuffmanCode); }
+                        lens[idx..idx + repeat].fill(val);
+                        idx += repeat;
                     }
-                    litlen_table = HuffmanTable::from_lengths(&litlen_lens)?;
-                    dist_table = HuffmanTable::from_lengths(&dist_lens)?;
-                }
+                    dynamic = (LitLenTable::litlen_from_lengths(&lens[..hlit])?, DistTable::dist_from_lengths(&lens[hlit..total])?);
+                    (&dynamic.0, &dynamic.1)
+                };
 
                 // now decode symbols until end of block
                 loop {
-                    let sym = litlen_table.read_symbol(&mut br)?;
-                    if sym < 256 {
-                        out.push(sym as u8);
-                    } else if sym == 256 {
-                        break;
-                    } else if sym > 256 && sym <= 285 {
-                        // length code
-                        let (base_len, extra_bits) = match sym {
-                            257 => (3, 0), 258 => (4,0), 259 => (5,0), 260 => (6,0),
-                            261 => (7,0), 262 => (8,0), 263 => (9,0), 264 => (10,0),
-                            265 => (11,1), 266 => (13,1), 267 => (15,1), 268 => (17,1),
-                            269 => (19,2), 270 => (23,2), 271 => (27,2), 272 => (31,2),
-                            273 => (35,3), 274 => (43,3), 275 => (51,3), 276 => (59,3),
-                            277 => (67,4), 278 => (83,4), 279 => (99,4), 280 => (115,4),
-                            281 => (131,5), 282 => (163,5), 283 => (195,5), 284 => (227,5),
-                            285 => (258,0), _ => return Err(InflateError::BadHuffmanCode),
-                        };
-                        let extra = if extra_bits > 0 { br.read_bits(extra_bits as usize).ok_or(InflateError::InputTooShort)? as usize } else { 0 };
-                        let length = base_len + extra;
-                        // distance code
-                        let dist_sym = dist_table.read_symbol(&mut br)?;
-                        if dist_sym > 29 { return Err(InflateError::BadHuffmanCode); }
-                        let (base_dist, dist_extra) = match dist_sym {
-                            0 => (1,0), 1 => (2,0), 2 => (3,0), 3 => (4,0),
-                            4 => (5,1), 5 => (7,1), 6 => (9,2), 7 => (13,2),
-                            8 => (17,3), 9 => (25,3), 10 => (33,4), 11 => (49,4),
-                            12 => (65,5), 13 => (97,5), 14 => (129,6), 15 => (193,6),
-                            16 => (257,7), 17 => (385,7), 18 => (513,8), 19 => (769,8),
-                            20 => (1025,9), 21 => (1537,9), 22 => (2049,10), 23 => (3073,10),
-                            24 => (4097,11), 25 => (6145,11), 26 => (8193,12), 27 => (12289,12),
-                            28 => (16385,13), 29 => (24577,13), _ => return Err(InflateError::BadHuffmanCode),
-                        };
-                        let dist_extra_val = if dist_extra > 0 { br.read_bits(dist_extra as usize).ok_or(InflateError::InputTooShort)? as usize } else { 0 };
-                        let distance = base_dist + dist_extra_val;
-                        // Now copy `length` bytes from distance back in `out`.
-                        let current_len = out.len();
-                        if distance == 0 || distance > current_len { return Err(InflateError::BadBlockData); }
-                        for i in 0..length {
-                            let pos = current_len - distance + i;
-                            let b = out[pos];
-                            out.push(b);
+                    let entry = litlen_table.decode(&mut br)?;
+                    match entry.kind() {
+                        EntryKind::Symbol => out.push(entry.value() as u8)?,
+                        EntryKind::EndOfBlock => break,
+                        EntryKind::Base => {
+                            // length code, base and extra bit count resolved by the table
+                            let extra = br.read_bits(entry.extra() as usize).ok_or(InflateError::InputTooShort)? as usize;
+                            let length = entry.value() as usize + extra;
+                            // distance code
+                            let dist = dist_table.decode(&mut br)?;
+                            if dist.kind() != EntryKind::Base { return Err(InflateError::BadHuffmanCode); }
+                            let extra = br.read_bits(dist.extra() as usize).ok_or(InflateError::InputTooShort)? as usize;
+                            let distance = dist.value() as usize + extra;
+                            if distance > max_dist { return Err(InflateError::DistanceTooFarBack); }
+                            // Now copy `length` bytes from distance back in `out`.
+                            out.copy_match(distance, length)?;
                         }
-                    } else {
-                        return Err(InflateError::BadHuffmanCode);
+                        EntryKind::Invalid => return Err(InflateError::BadHuffmanCode),
                     }
                 }
             }
             _ => return Err(InflateError::Unsupported),
         }
+        on_block(&out.written()[block_start..]);
         if bfinal != 0 {
             break;
         }
     }
 
-    Ok(out)
+    Ok(br.bytes_consumed())
 }
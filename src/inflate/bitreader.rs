@@ -1,9 +1,12 @@
-
+/// LSB-first bit reader over a byte slice with a 64-bit buffer.
+///
+/// `refill` tops the buffer up to at least 56 bits (fewer only at the end of the input),
+/// so any read of up to 32 bits needs at most one refill.
 pub struct BitReader<'a> {
     data: &'a [u8],
     byte_pos: usize,
-    bit_buf: u32,
-    bit_count: u8,
+    bit_buf: u64,
+    bit_count: u32,
 }
 
 impl<'a> BitReader<'a> {
@@ -11,28 +14,48 @@ impl<'a> BitReader<'a> {
         BitReader { data, byte_pos: 0, bit_buf: 0, bit_count: 0 }
     }
 
-    // Fill the buffer to ensure we have at least `need` bits in bit_buf
-    fn ensure_bits(&mut self, need: u8) -> bool {
-        while self.bit_count < need {
-            if self.byte_pos >= self.data.len() {
-                return false;
+    /// Fill the buffer with as many whole bytes as fit.
+    #[inline]
+    pub fn refill(&mut self) {
+        if self.bit_count > 56 {
+            return;
+        }
+        if let Some(bytes) = self.data.get(self.byte_pos..self.byte_pos + 8) {
+            // Fast path: one unaligned little-endian load. Bits of the byte that does not
+            // fit end up above bit_count; the next refill ORs the same values back in, so
+            // they are harmless.
+            let mut word = [0u8; 8];
+            word.copy_from_slice(bytes);
+            self.bit_buf |= u64::from_le_bytes(word) << self.bit_count;
+            let taken = (63 - self.bit_count) / 8;
+            self.byte_pos += taken as usize;
+            self.bit_count += taken * 8;
+        } else {
+            // Tail: byte by byte until the input runs out
+            while self.bit_count <= 56 && self.byte_pos < self.data.len() {
+                self.bit_buf |= (self.data[self.byte_pos] as u64) << self.bit_count;
+                self.byte_pos += 1;
+                self.bit_count += 8;
             }
-            let b = self.data[self.byte_pos] as u32;
-            self.byte_pos += 1;
-            self.bit_buf |= b << self.bit_count;
-            self.bit_count += 8;
         }
-        true
+    }
+
+    // Make sure at least `need` bits are buffered
+    #[inline]
+    fn ensure_bits(&mut self, need: u32) -> bool {
+        if self.bit_count < need {
+            self.refill();
+        }
+        self.bit_count >= need
     }
 
     pub fn read_bits(&mut self, n: usize) -> Option<u32> {
         if n == 0 { return Some(0); }
         if n > 32 { return None; }
-        if !self.ensure_bits(n as u8) { return None; }
-        let mask = if n == 32 { !0u32 } else { (1u32 << n) - 1 };
-        let bits = self.bit_buf & mask;
+        if !self.ensure_bits(n as u32) { return None; }
+        let bits = (self.bit_buf & ((1u64 << n) - 1)) as u32;
         self.bit_buf >>= n;
-        self.bit_count -= n as u8;
+        self.bit_count -= n as u32;
         Some(bits)
     }
 
@@ -40,36 +63,45 @@ impl<'a> BitReader<'a> {
     pub fn peek_bits(&mut self, n: usize) -> Option<u32> {
         if n == 0 { return Some(0); }
         if n > 32 { return None; }
-        if !self.ensure_bits(n as u8) { return None; }
-        let mask = if n == 32 { !0u32 } else { (1u32 << n) - 1 };
-        Some(self.bit_buf & mask)
+        if !self.ensure_bits(n as u32) { return None; }
+        Some((self.bit_buf & ((1u64 << n) - 1)) as u32)
+    }
+
+    /// Peek up to `n` bits (at most 32). Past the end of the input the missing bits read
+    /// as zero; the second value is how many of the returned bits are real.
+    #[inline]
+    pub fn peek_bits_padded(&mut self, n: usize) -> (u32, usize) {
+        self.ensure_bits(n as u32);
+        ((self.bit_buf & ((1u64 << n) - 1)) as u32, (self.bit_count as usize).min(n))
+    }
+
+    /// Drop `n` bits that were already peeked
+    #[inline]
+    pub fn consume(&mut self, n: usize) {
+        self.bit_buf >>= n;
+        self.bit_count -= n as u32;
     }
 
     pub fn align_to_byte(&mut self) {
-        let skip = (self.bit_count % 8) as u32;
-        if skip > 0 {
-            self.read_bits(skip as usize);
-        }
+        let skip = self.bit_count % 8;
+        self.consume(skip as usize);
     }
 
     pub fn read_byte(&mut self) -> Option<u8> {
-        // if we have a partially consumed bitbuf, drain lowest bits until byte aligned
-        if self.bit_count >= 8 {
-            let b = (self.bit_buf & 0xff) as u8;
-            self.bit_buf >>= 8;
-            self.bit_count -= 8;
-            return Some(b);
-        }
-        // not enough buffered; but we must be byte-aligned for stored block reads
-        if self.bit_count != 0 {
+        // Stored block reads come after align_to_byte, so whole bytes are buffered
+        if !self.bit_count.is_multiple_of(8) {
             // This should not happen because caller should call align_to_byte first
             return None;
         }
-        if self.byte_pos >= self.data.len() { return None; }
-        let b = self.data[self.byte_pos];
-        self.byte_pos += 1;
-        Some(b)
+        self.read_bits(8).map(|b| b as u8)
     }
 
+    /// Position of the next unbuffered input byte
     pub fn byte_pos(&self) -> usize { self.byte_pos }
+
+    /// Number of input bits consumed so far
+    pub fn bits_consumed(&self) -> usize { self.byte_pos * 8 - self.bit_count as usize }
+
+    /// Number of input bytes used so far, counting a partially read byte as used
+    pub fn bytes_consumed(&self) -> usize { self.byte_pos - (self.bit_count / 8) as usize }
 }
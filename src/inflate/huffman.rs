@@ -1,14 +1,86 @@
-use alloc::vec::Vec;
 use crate::inflate::bitreader::BitReader;
+use crate::inflate::{DIST_BASE, DIST_EXTRA, LENGTH_BASE, LENGTH_EXTRA};
 use crate::InflateError;
 
-pub struct HuffmanTable {
+/// Longest code allowed by DEFLATE
+pub const MAX_CODE_BITS: usize = 15;
+/// Largest alphabet (literal/length) a table is built for
+pub const MAX_SYMBOLS: usize = 288;
+// Widest root table of the table types below
+const MAX_ROOT_BITS: usize = 11;
+
+// Largest possible table sizes for each alphabet, root table plus subtables, as computed
+// by zlib's examples/enough.c for (symbols, root bits, max code length)
+const LITLEN_ENOUGH: usize = 2342; // enough 288 11 15
+const DIST_ENOUGH: usize = 402; // enough 32 8 15
+const CODELEN_ENOUGH: usize = 128; // enough 19 7 7
+
+/// Literal/length table: 11-bit root lookup
+pub type LitLenTable = HuffmanTable<11, LITLEN_ENOUGH>;
+/// Distance table: 8-bit root lookup
+pub type DistTable = HuffmanTable<8, DIST_ENOUGH>;
+/// Code length table of a dynamic block header: codes never exceed 7 bits
+pub type CodeLenTable = HuffmanTable<7, CODELEN_ENOUGH>;
+
+// Entry layout: bits 0-4 code length to consume, bits 5-7 kind,
+// bits 8-15 extra bits (or subtable index bits), bits 16-31 value
+const KIND_SYMBOL: u32 = 0;
+const KIND_BASE: u32 = 1;
+const KIND_END_OF_BLOCK: u32 = 2;
+const KIND_SUBTABLE: u32 = 3;
+const KIND_INVALID: u32 = 4;
+
+/// A decoded table entry: what the code stands for and how many bits it took.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Entry(u32);
+
+/// Meaning of a decoded `Entry`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    /// A literal byte or, for tables built by `from_lengths`, the symbol itself
+    Symbol,
+    /// A length or distance: `value()` plus the next `extra()` bits
+    Base,
+    EndOfBlock,
+    Invalid,
+}
+
+impl Entry {
+    const fn new(len: u32, kind: u32, extra: u32, value: u32) -> Self {
+        Entry(len | (kind << 5) | (extra << 8) | (value << 16))
+    }
+
+    /// Number of bits the code occupies
+    pub fn code_len(self) -> u32 { self.0 & 0x1f }
+    pub fn extra(self) -> u32 { (self.0 >> 8) & 0xff }
+    pub fn value(self) -> u16 { (self.0 >> 16) as u16 }
+
+    fn raw_kind(self) -> u32 { (self.0 >> 5) & 0x7 }
+
+    pub fn kind(self) -> EntryKind {
+        match self.raw_kind() {
+            KIND_SYMBOL => EntryKind::Symbol,
+            KIND_BASE => EntryKind::Base,
+            KIND_END_OF_BLOCK => EntryKind::EndOfBlock,
+            _ => EntryKind::Invalid,
+        }
+    }
+}
+
+const INVALID: Entry = Entry::new(0, KIND_INVALID, 0, 0);
+
+/// Table-driven canonical Huffman decoder.
+///
+/// The low `ROOT` bits of the input index the root table directly; codes longer than
+/// that go through a second lookup in a subtable indexed by the following bits, so a
+/// symbol is decoded with one peek and one consume. Fixed size so it can be built
+/// without an allocator.
+pub struct HuffmanTable<const ROOT: usize, const SIZE: usize> {
     pub max_bits: usize,
-    pub children: Vec<i32>,
-    pub symbol: Vec<i32>,
+    entries: [Entry; SIZE],
 }
 
-fn reverse_bits(mut v: u32, len: usize) -> u32 {
+pub(crate) fn reverse_bits(mut v: u32, len: usize) -> u32 {
     let mut r = 0u32;
     for _ in 0..len {
         r = (r << 1) | (v & 1);
@@ -17,105 +89,175 @@ fn reverse_bits(mut v: u32, len: usize) -> u32 {
     r
 }
 
-impl HuffmanTable {
+impl<const ROOT: usize, const SIZE: usize> HuffmanTable<ROOT, SIZE> {
+    /// Build a table whose entries decode to the symbols themselves.
     pub fn from_lengths(lengths: &[u8]) -> Result<Self, InflateError> {
+        Self::build(lengths, |sym| Entry::new(0, KIND_SYMBOL, 0, sym as u32))
+    }
+
+    // `entry_for` gives the entry of each symbol; its length field is filled in here
+    fn build<F: Fn(usize) -> Entry>(lengths: &[u8], entry_for: F) -> Result<Self, InflateError> {
+        if lengths.len() > MAX_SYMBOLS { return Err(InflateError::BadHuffmanCode); }
         let mut max_bits = 0usize;
         for &l in lengths.iter() {
             if l as usize > max_bits { max_bits = l as usize; }
         }
-        if max_bits == 0 { max_bits = 1; }
-        if max_bits > 15 {
-            // We'll cap at 15
-            max_bits = 15;
-        }
+        if max_bits > MAX_CODE_BITS { return Err(InflateError::BadHuffmanCode); }
 
         // Count codes per length
-        let mut counts: Vec<usize> = Vec::new();
-        counts.resize(max_bits + 1, 0usize);
+        let mut counts = [0u16; MAX_CODE_BITS + 1];
         for &l in lengths.iter() {
-            if l as usize > max_bits { return Err(InflateError::BadHuffmanCode); }
             if l > 0 { counts[l as usize] += 1; }
         }
-        // compute next_code
-        let mut next_code: Vec<u32> = Vec::new();
-        next_code.resize(max_bits + 1, 0u32);
+        // Reject over-subscribed codes; incomplete ones are allowed
+        let mut left = 1i32;
+        for &count in counts.iter().skip(1) {
+            left <<= 1;
+            left -= count as i32;
+            if left < 0 { return Err(InflateError::BadHuffmanCode); }
+        }
+        // compute first code of each length
+        let mut next_code = [0u32; MAX_CODE_BITS + 1];
         let mut code = 0u32;
-        for bits in 1..=max_bits {
+        for bits in 1..=MAX_CODE_BITS {
             code = (code + counts[bits - 1] as u32) << 1;
             next_code[bits] = code;
         }
 
-        // build trie
-        let mut children: Vec<i32> = Vec::new();
-        let mut symbol_vec: Vec<i32> = Vec::new();
-        children.push(-1); children.push(-1); symbol_vec.push(-1); // root
+        // Longest code behind each root entry, to size the subtables
+        let mut sub_bits_buf = [0u8; 1 << MAX_ROOT_BITS];
+        let sub_bits = &mut sub_bits_buf[..1 << ROOT];
+        let mut codes = next_code;
+        for &l in lengths.iter() {
+            let len = l as usize;
+            if len <= ROOT { if len > 0 { codes[len] += 1; } continue; }
+            let c = codes[len];
+            codes[len] += 1;
+            let root = reverse_bits(c >> (len - ROOT), ROOT) as usize;
+            sub_bits[root] = sub_bits[root].max((len - ROOT) as u8);
+        }
+
+        let mut entries = [INVALID; SIZE];
+        let mut used = 1usize << ROOT;
+        if used > SIZE { return Err(InflateError::BadHuffmanCode); }
+        for (root, &bits) in sub_bits.iter().enumerate() {
+            if bits == 0 { continue; }
+            let size = 1usize << bits;
+            if used + size > SIZE { return Err(InflateError::BadHuffmanCode); }
+            entries[root] = Entry::new(ROOT as u32, KIND_SUBTABLE, bits as u32, used as u32);
+            used += size;
+        }
 
         for (sym, &l) in lengths.iter().enumerate() {
             let len = l as usize;
             if len == 0 { continue; }
             let c = next_code[len];
             next_code[len] += 1;
-            let rev = reverse_bits(c, len);
-            let mut node = 0usize;
-            for i in 0..len {
-                let bit = ((rev >> i) & 1) as usize;
-                let child_idx = node * 2 + bit;
-                if child_idx >= children.len() { children.resize(child_idx + 1, -1); }
-                let child = children[child_idx];
-                if child == -1 {
-                    let new_node = symbol_vec.len() as i32;
-                    children.push(-1); children.push(-1);
-                    symbol_vec.push(-1);
-                    children[child_idx] = new_node;
-                    node = new_node as usize;
-                } else {
-                    node = child as usize;
+            let entry = Entry(entry_for(sym).0 | len as u32);
+            // Codes are read LSB first, so index by the reversed code and replicate the
+            // entry over every value of the bits that follow it
+            let rev = reverse_bits(c, len) as usize;
+            if len <= ROOT {
+                let mut i = rev;
+                while i < 1 << ROOT {
+                    entries[i] = entry;
+                    i += 1 << len;
+                }
+            } else {
+                let ptr = entries[rev & ((1 << ROOT) - 1)];
+                let start = ptr.value() as usize;
+                let size = 1usize << ptr.extra();
+                let mut i = rev >> ROOT;
+                while i < size {
+                    entries[start + i] = entry;
+                    i += 1 << (len - ROOT);
                 }
             }
-            if symbol_vec[node] != -1 { return Err(InflateError::BadHuffmanCode); }
-            symbol_vec[node] = sym as i32;
         }
 
-        Ok(HuffmanTable { max_bits, children, symbol: symbol_vec })
+        Ok(HuffmanTable { max_bits, entries })
+    }
+
+    /// Look up the entry for a code given at least `max_bits` upcoming bits, LSB first.
+    pub fn lookup(&self, bits: u32) -> Entry {
+        let entry = self.entries[bits as usize & ((1 << ROOT) - 1)];
+        if entry.raw_kind() != KIND_SUBTABLE {
+            return entry;
+        }
+        let sub = (bits >> ROOT) as usize & ((1 << entry.extra()) - 1);
+        self.entries[entry.value() as usize + sub]
+    }
+
+    /// Decode the next code from `br`: one peek of the longest code length and one consume.
+    pub fn decode(&self, br: &mut BitReader) -> Result<Entry, InflateError> {
+        let (bits, avail) = br.peek_bits_padded(MAX_CODE_BITS);
+        let entry = self.lookup(bits);
+        if entry.raw_kind() == KIND_INVALID {
+            return Err(if avail < self.max_bits { InflateError::InputTooShort } else { InflateError::BadHuffmanCode });
+        }
+        if entry.code_len() as usize > avail { return Err(InflateError::InputTooShort); }
+        br.consume(entry.code_len() as usize);
+        Ok(entry)
     }
 
     pub fn read_symbol(&self, br: &mut BitReader) -> Result<u16, InflateError> {
-        let mut node = 0usize;
-        loop {
-            if self.symbol[node] != -1 {
-                return Ok(self.symbol[node] as u16);
-            }
-            let bit = br.read_bits(1).ok_or(InflateError::InputTooShort)? as usize;
-            let child_idx = node * 2 + bit;
-            if child_idx >= self.children.len() { return Err(InflateError::BadHuffmanCode); }
-            let child = self.children[child_idx];
-            if child == -1 { return Err(InflateError::BadHuffmanCode); }
-            node = child as usize;
+        Ok(self.decode(br)?.value())
+    }
+
+    /// Decode a code from up to `avail` bits already buffered (LSB first) without consuming them.
+    /// Returns `None` if more bits are needed.
+    pub fn decode_bits(&self, bits: u64, avail: u32) -> Result<Option<Entry>, InflateError> {
+        let mask = if avail >= 32 { !0u32 } else { (1u32 << avail) - 1 };
+        let entry = self.lookup(bits as u32 & mask);
+        if entry.raw_kind() == KIND_INVALID || entry.code_len() > avail {
+            if (avail as usize) < self.max_bits { return Ok(None); }
+            return Err(InflateError::BadHuffmanCode);
         }
+        Ok(Some(entry))
     }
 
     // Debug helpers
     pub fn max_bits(&self) -> usize { self.max_bits }
-    pub fn children(&self) -> &[i32] { &self.children }
-    pub fn symbols(&self) -> &[i32] { &self.symbol }
+    pub fn entries(&self) -> &[Entry] { &self.entries }
+}
+
+impl LitLenTable {
+    /// Build a literal/length table: literals, end of block, and lengths with their
+    /// base and extra bits resolved in the entry.
+    pub fn litlen_from_lengths(lengths: &[u8]) -> Result<Self, InflateError> {
+        Self::build(lengths, |sym| match sym {
+            0..=255 => Entry::new(0, KIND_SYMBOL, 0, sym as u32),
+            256 => Entry::new(0, KIND_END_OF_BLOCK, 0, 0),
+            257..=285 => Entry::new(0, KIND_BASE, LENGTH_EXTRA[sym - 257] as u32, LENGTH_BASE[sym - 257] as u32),
+            _ => Entry::new(0, KIND_INVALID, 0, 0),
+        })
+    }
+}
+
+impl DistTable {
+    /// Build a distance table with base distance and extra bits resolved in the entry.
+    pub fn dist_from_lengths(lengths: &[u8]) -> Result<Self, InflateError> {
+        Self::build(lengths, |sym| match sym {
+            0..=29 => Entry::new(0, KIND_BASE, DIST_EXTRA[sym] as u32, DIST_BASE[sym] as u32),
+            _ => Entry::new(0, KIND_INVALID, 0, 0),
+        })
+    }
 }
 
 // build fixed tables for litlen and dist
 
-pub fn build_fixed_litlen_table() -> HuffmanTable {
+pub fn build_fixed_litlen_table() -> LitLenTable {
     // per RFC 1951
     // litlen lengths: 0-143:8 bits, 144-255:9, 256-279:7, 280-287:8
-    let mut lengths: Vec<u8> = Vec::new();
-    lengths.resize(288, 0u8);
-    for i in 0..=143 { lengths[i] = 8; }
-    for i in 144..=255 { lengths[i] = 9; }
-    for i in 256..=279 { lengths[i] = 7; }
-    for i in 280..=287 { lengths[i] = 8; }
-    HuffmanTable::from_lengths(&lengths).expect("failed to build fixed litlen table")
+    let mut lengths = [0u8; 288];
+    lengths[0..=143].fill(8);
+    lengths[144..=255].fill(9);
+    lengths[256..=279].fill(7);
+    lengths[280..=287].fill(8);
+    LitLenTable::litlen_from_lengths(&lengths).expect("failed to build fixed litlen table")
 }
 
-pub fn build_fixed_dist_table() -> HuffmanTable {
-    let mut lengths = Vec::new();
-    lengths.resize(32, 5u8);
-    HuffmanTable::from_lengths(&lengths).expect("failed to build fixed dist table")
+pub fn build_fixed_dist_table() -> DistTable {
+    let lengths = [5u8; 32];
+    DistTable::dist_from_lengths(&lengths).expect("failed to build fixed dist table")
 }
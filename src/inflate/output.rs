@@ -0,0 +1,75 @@
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+use crate::InflateError;
+
+// Destination of the one-shot block decoder: everything written so far doubles as the
+// history that back references copy from.
+pub(crate) trait Output {
+    // All bytes written so far
+    fn written(&self) -> &[u8];
+
+    fn push(&mut self, b: u8) -> Result<(), InflateError>;
+
+    // Append `length` bytes starting `distance` bytes back; the ranges may overlap
+    fn copy_match(&mut self, distance: usize, length: usize) -> Result<(), InflateError>;
+}
+
+#[cfg(feature = "alloc")]
+impl Output for Vec<u8> {
+    fn written(&self) -> &[u8] { self }
+
+    fn push(&mut self, b: u8) -> Result<(), InflateError> {
+        Vec::push(self, b);
+        Ok(())
+    }
+
+    fn copy_match(&mut self, distance: usize, length: usize) -> Result<(), InflateError> {
+        let current_len = self.len();
+        if distance == 0 || distance > current_len { return Err(InflateError::BadBlockData); }
+        self.reserve(length);
+        for i in 0..length {
+            let b = self[current_len - distance + i];
+            Vec::push(self, b);
+        }
+        Ok(())
+    }
+}
+
+// Caller-provided buffer; running out of room is an error rather than a reallocation
+pub(crate) struct SliceOutput<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> SliceOutput<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        SliceOutput { buf, pos: 0 }
+    }
+}
+
+impl Output for SliceOutput<'_> {
+    fn written(&self) -> &[u8] { &self.buf[..self.pos] }
+
+    fn push(&mut self, b: u8) -> Result<(), InflateError> {
+        if self.pos >= self.buf.len() { return Err(InflateError::OutputOverflow); }
+        self.buf[self.pos] = b;
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn copy_match(&mut self, distance: usize, length: usize) -> Result<(), InflateError> {
+        if distance == 0 || distance > self.pos { return Err(InflateError::BadBlockData); }
+        if length > self.buf.len() - self.pos { return Err(InflateError::OutputOverflow); }
+        let start = self.pos - distance;
+        if distance >= length {
+            self.buf.copy_within(start..start + length, self.pos);
+        } else {
+            for i in 0..length {
+                self.buf[self.pos + i] = self.buf[start + i];
+            }
+        }
+        self.pos += length;
+        Ok(())
+    }
+}
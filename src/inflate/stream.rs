@@ -0,0 +1,453 @@
+use core::result::Result;
+
+use crate::checksum::Adler32;
+use crate::inflate::huffman::{CodeLenTable, DistTable, Entry, EntryKind, HuffmanTable, LitLenTable, build_fixed_litlen_table, build_fixed_dist_table};
+use crate::inflate::{InflateError, CODE_LENGTH_ORDER};
+
+/// History needed for any DEFLATE stream (RFC 1951 maximum distance)
+pub const MAX_WINDOW: usize = 32768;
+/// Smallest window a zlib header can declare (CINFO = 0)
+pub const MIN_WINDOW: usize = 256;
+
+/// Why `Inflater::decompress` or `Deflater::compress` returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamState {
+    /// All of the input was used; call again with more
+    NeedsInput,
+    /// The output slice is full; call again with more room
+    NeedsOutput,
+    /// The end of the stream was reached: its trailer (if any) was verified, or for
+    /// `Deflater`, written
+    Done,
+}
+
+/// Result of one `Inflater::decompress` or `Deflater::compress` call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    /// Bytes of `input` used; the rest must be passed again on the next call
+    pub consumed: usize,
+    /// Bytes written to the start of `output`
+    pub produced: usize,
+    pub state: StreamState,
+}
+
+// Position in the stream. Each state only moves on once all the bits it needs are
+// buffered, so it can be re-entered after running out of input or output.
+#[derive(Clone, Copy)]
+enum State {
+    ZlibHeader,
+    BlockHeader,
+    StoredHeader,
+    Stored { remaining: usize },
+    TableSizes,
+    CodeLengthLengths { hlit: usize, hdist: usize, hclen: usize, idx: usize },
+    CodeLengths { hlit: usize, hdist: usize, idx: usize },
+    Symbol,
+    Distance { length: usize },
+    Copy { length: usize, distance: usize },
+    ZlibTrailer,
+    Done,
+    // An error was returned; it is returned again on every later call
+    Failed(InflateError),
+}
+
+// Input bits carried over between calls, LSB first
+struct Bits {
+    buf: u64,
+    count: u32,
+}
+
+// Buffers of the current call
+struct Io<'a, 'b> {
+    input: &'a [u8],
+    in_pos: usize,
+    output: &'b mut [u8],
+    out_pos: usize,
+    // end of the output already added to the Adler32
+    hashed: usize,
+}
+
+impl Bits {
+    // Move the next input byte into the bit buffer
+    fn pull(&mut self, io: &mut Io) -> bool {
+        if io.in_pos >= io.input.len() {
+            return false;
+        }
+        self.buf |= (io.input[io.in_pos] as u64) << self.count;
+        io.in_pos += 1;
+        self.count += 8;
+        true
+    }
+
+    fn need(&mut self, n: u32, io: &mut Io) -> bool {
+        while self.count < n {
+            if !self.pull(io) {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn consume(&mut self, n: u32) {
+        self.buf >>= n;
+        self.count -= n;
+    }
+
+    // Caller must have checked that `n` bits are buffered
+    fn take(&mut self, n: u32) -> u32 {
+        let v = (self.buf & ((1u64 << n) - 1)) as u32;
+        self.consume(n);
+        v
+    }
+}
+
+// Decode a code without consuming it, pulling input bytes until the code is complete.
+fn decode<const ROOT: usize, const SIZE: usize>(bits: &mut Bits, table: Option<&HuffmanTable<ROOT, SIZE>>, io: &mut Io) -> Result<Option<Entry>, InflateError> {
+    let table = table.ok_or(InflateError::BadBlockData)?;
+    loop {
+        if let Some(found) = table.decode_bits(bits.buf, bits.count)? {
+            return Ok(Some(found));
+        }
+        if !bits.pull(io) {
+            return Ok(None);
+        }
+    }
+}
+
+/// Resumable DEFLATE decoder for input and output arriving in pieces.
+///
+/// Keeps a `WINDOW`-byte history ring buffer and the block decoding state between calls,
+/// so input can be fed in chunks of any size (down to single bytes) and output drained
+/// into small buffers. An error is final: later calls return it again.
+///
+/// The default 32 KiB window decodes any stream. Smaller windows (a power of two from
+/// 256 bytes) save RAM but only decode streams whose encoder kept its back references
+/// within that distance: zlib streams declaring a larger window (CINFO) are refused with
+/// `WindowTooLarge`, and a reference reaching further back fails with `DistanceTooFarBack`.
+pub struct Inflater<const WINDOW: usize = MAX_WINDOW> {
+    state: State,
+    zlib: bool,
+    last_block: bool,
+    bits: Bits,
+    window: [u8; WINDOW],
+    wpos: usize,
+    whave: usize,
+    // longest distance accepted
+    max_dist: usize,
+    enforce_window: bool,
+    litlen: Option<LitLenTable>,
+    dist: Option<DistTable>,
+    // `litlen` and `dist` hold the fixed tables, so a following fixed block can reuse them
+    fixed_tables: bool,
+    codelen: Option<CodeLenTable>,
+    lens: [u8; 288 + 32],
+    adler: Adler32,
+}
+
+impl Inflater {
+    /// Decoder for a zlib stream; the Adler-32 trailer is verified before `Done` is reported.
+    pub fn new_zlib() -> Self {
+        Self::new(true)
+    }
+
+    /// Decoder for a raw DEFLATE stream. `Done` is reported after the final block; input
+    /// past it is not consumed.
+    pub fn new_raw() -> Self {
+        Self::new(false)
+    }
+}
+
+impl<const WINDOW: usize> Inflater<WINDOW> {
+    /// Decoder for a zlib stream with a `WINDOW`-byte history, e.g. `Inflater::<2048>::new_zlib_windowed()`.
+    pub fn new_zlib_windowed() -> Self {
+        Self::new(true)
+    }
+
+    /// Decoder for a raw DEFLATE stream whose encoder kept distances within `WINDOW` bytes.
+    pub fn new_raw_windowed() -> Self {
+        Self::new(false)
+    }
+
+    fn new(zlib: bool) -> Self {
+        const { assert!(WINDOW.is_power_of_two() && WINDOW >= MIN_WINDOW && WINDOW <= MAX_WINDOW, "window must be a power of two from 256 to 32768") };
+        Inflater {
+            state: if zlib { State::ZlibHeader } else { State::BlockHeader },
+            zlib,
+            last_block: false,
+            bits: Bits { buf: 0, count: 0 },
+            window: [0u8; WINDOW],
+            wpos: 0,
+            whave: 0,
+            max_dist: WINDOW,
+            enforce_window: false,
+            litlen: None,
+            dist: None,
+            fixed_tables: false,
+            codelen: None,
+            lens: [0u8; 288 + 32],
+            adler: Adler32::new(),
+        }
+    }
+
+    /// Also reject back references beyond the window a zlib header declares (CINFO),
+    /// even if this decoder's own window could serve them. Call before decoding starts.
+    pub fn set_enforce_window(&mut self, enforce: bool) {
+        self.enforce_window = enforce;
+    }
+
+    /// True once the end of the stream has been reached.
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, State::Done)
+    }
+
+    /// Decode as much of `input` into `output` as possible.
+    pub fn decompress(&mut self, input: &[u8], output: &mut [u8]) -> Result<Status, InflateError> {
+        let mut io = Io { input, in_pos: 0, output, out_pos: 0, hashed: 0 };
+        let state = self.run(&mut io);
+        if let Err(e) = state {
+            self.state = State::Failed(e);
+        }
+        if self.zlib {
+            self.adler.update(&io.output[io.hashed..io.out_pos]);
+        }
+        Ok(Status { consumed: io.in_pos, produced: io.out_pos, state: state? })
+    }
+
+    fn put(&mut self, io: &mut Io, b: u8) {
+        io.output[io.out_pos] = b;
+        io.out_pos += 1;
+        self.window[self.wpos] = b;
+        self.wpos = (self.wpos + 1) & (WINDOW - 1);
+        if self.whave < WINDOW {
+            self.whave += 1;
+        }
+    }
+
+    fn end_block(&mut self) {
+        self.state = if !self.last_block {
+            State::BlockHeader
+        } else if self.zlib {
+            State::ZlibTrailer
+        } else {
+            State::Done
+        };
+    }
+
+    fn run(&mut self, io: &mut Io) -> Result<StreamState, InflateError> {
+        loop {
+            match self.state {
+                State::ZlibHeader => {
+                    if !self.bits.need(16, io) { return Ok(StreamState::NeedsInput); }
+                    let cmf = (self.bits.buf & 0xff) as u8;
+                    let flg = ((self.bits.buf >> 8) & 0xff) as u8;
+                    if !((cmf as u16) << 8 | flg as u16).is_multiple_of(31) {
+                        return Err(InflateError::InvalidHeader);
+                    }
+                    if cmf & 0x0f != 8 {
+                        return Err(InflateError::Unsupported);
+                    }
+                    // CINFO: log2 of the encoder's window size minus 8
+                    let cinfo = cmf >> 4;
+                    if cinfo > 7 {
+                        return Err(InflateError::InvalidHeader);
+                    }
+                    let declared = 1usize << (cinfo + 8);
+                    if declared > WINDOW {
+                        return Err(InflateError::WindowTooLarge { declared });
+                    }
+                    if self.enforce_window {
+                        self.max_dist = declared;
+                    }
+                    if flg & 0x20 != 0 {
+                        // Report the DICTID so the caller can tell which dictionary is wanted
+                        if !self.bits.need(48, io) { return Ok(StreamState::NeedsInput); }
+                        let id = (self.bits.buf >> 16) as u32;
+                        return Err(InflateError::DictionaryRequired { dictid: id.swap_bytes() });
+                    }
+                    self.bits.consume(16);
+                    self.state = State::BlockHeader;
+                }
+                State::BlockHeader => {
+                    if !self.bits.need(3, io) { return Ok(StreamState::NeedsInput); }
+                    self.last_block = self.bits.take(1) != 0;
+                    match self.bits.take(2) {
+                        0 => {
+                            // Stored block: skip to the byte boundary
+                            self.bits.consume(self.bits.count % 8);
+                            self.state = State::StoredHeader;
+                        }
+                        1 => {
+                            if !self.fixed_tables {
+                                self.litlen = Some(build_fixed_litlen_table());
+                                self.dist = Some(build_fixed_dist_table());
+                                self.fixed_tables = true;
+                            }
+                            self.state = State::Symbol;
+                        }
+                        2 => self.state = State::TableSizes,
+                        _ => return Err(InflateError::Unsupported),
+                    }
+                }
+                State::StoredHeader => {
+                    if !self.bits.need(32, io) { return Ok(StreamState::NeedsInput); }
+                    let len = self.bits.take(16) as u16;
+                    let nlen = self.bits.take(16) as u16;
+                    if len != !nlen {
+                        return Err(InflateError::BadBlockData);
+                    }
+                    self.state = State::Stored { remaining: len as usize };
+                }
+                State::Stored { mut remaining } => {
+                    while remaining > 0 {
+                        if io.out_pos == io.output.len() {
+                            self.state = State::Stored { remaining };
+                            return Ok(StreamState::NeedsOutput);
+                        }
+                        // Bytes already in the bit buffer come first, then straight from the input
+                        let b = if self.bits.count >= 8 {
+                            self.bits.take(8) as u8
+                        } else if io.in_pos < io.input.len() {
+                            io.in_pos += 1;
+                            io.input[io.in_pos - 1]
+                        } else {
+                            self.state = State::Stored { remaining };
+                            return Ok(StreamState::NeedsInput);
+                        };
+                        self.put(io, b);
+                        remaining -= 1;
+                    }
+                    self.end_block();
+                }
+                State::TableSizes => {
+                    if !self.bits.need(14, io) { return Ok(StreamState::NeedsInput); }
+                    let hlit = self.bits.take(5) as usize + 257;
+                    let hdist = self.bits.take(5) as usize + 1;
+                    let hclen = self.bits.take(4) as usize + 4;
+                    if hlit > 286 || hdist > 30 {
+                        return Err(InflateError::BadHuffmanCode);
+                    }
+                    self.lens[..19].fill(0);
+                    self.state = State::CodeLengthLengths { hlit, hdist, hclen, idx: 0 };
+                }
+                State::CodeLengthLengths { hlit, hdist, hclen, mut idx } => {
+                    while idx < hclen {
+                        if !self.bits.need(3, io) {
+                            self.state = State::CodeLengthLengths { hlit, hdist, hclen, idx };
+                            return Ok(StreamState::NeedsInput);
+                        }
+                        self.lens[CODE_LENGTH_ORDER[idx]] = self.bits.take(3) as u8;
+                        idx += 1;
+                    }
+                    self.codelen = Some(CodeLenTable::from_lengths(&self.lens[..19])?);
+                    self.state = State::CodeLengths { hlit, hdist, idx: 0 };
+                }
+                State::CodeLengths { hlit, hdist, mut idx } => {
+                    let total = hlit + hdist;
+                    while idx < total {
+                        let (sym, len) = match decode(&mut self.bits, self.codelen.as_ref(), io)? {
+                            Some(entry) => (entry.value(), entry.code_len()),
+                            None => {
+                                self.state = State::CodeLengths { hlit, hdist, idx };
+                                return Ok(StreamState::NeedsInput);
+                            }
+                        };
+                        // Repeat codes are only consumed together with their extra bits
+                        let extra = match sym { 16 => 2, 17 => 3, 18 => 7, _ => 0 };
+                        if !self.bits.need(len + extra, io) {
+                            self.state = State::CodeLengths { hlit, hdist, idx };
+                            return Ok(StreamState::NeedsInput);
+                        }
+                        self.bits.consume(len);
+                        let (val, repeat) = match sym {
+                            0..=15 => (sym as u8, 1),
+                            16 => {
+                                // repeat previous 3-6 times
+                                if idx == 0 { return Err(InflateError::BadHuffmanCode); }
+                                (self.lens[idx - 1], self.bits.take(2) as usize + 3)
+                            }
+                            17 => (0, self.bits.take(3) as usize + 3),
+                            18 => (0, self.bits.take(7) as usize + 11),
+                            _ => return Err(InflateError::BadHuffmanCode),
+                        };
+                        if idx + repeat > total { return Err(InflateError::BadHuffmanCode); }
+                        self.lens[idx..idx + repeat].fill(val);
+                        idx += repeat;
+                    }
+                    self.fixed_tables = false;
+                    self.litlen = Some(LitLenTable::litlen_from_lengths(&self.lens[..hlit])?);
+                    self.dist = Some(DistTable::dist_from_lengths(&self.lens[hlit..total])?);
+                    self.state = State::Symbol;
+                }
+                State::Symbol => {
+                    loop {
+                        let entry = match decode(&mut self.bits, self.litlen.as_ref(), io)? {
+                            Some(entry) => entry,
+                            None => return Ok(StreamState::NeedsInput),
+                        };
+                        match entry.kind() {
+                            EntryKind::Symbol => {
+                                if io.out_pos == io.output.len() { return Ok(StreamState::NeedsOutput); }
+                                self.bits.consume(entry.code_len());
+                                self.put(io, entry.value() as u8);
+                            }
+                            EntryKind::EndOfBlock => {
+                                self.bits.consume(entry.code_len());
+                                self.end_block();
+                                break;
+                            }
+                            EntryKind::Base => {
+                                // Length codes are only consumed together with their extra bits
+                                if !self.bits.need(entry.code_len() + entry.extra(), io) { return Ok(StreamState::NeedsInput); }
+                                self.bits.consume(entry.code_len());
+                                let length = entry.value() as usize + self.bits.take(entry.extra()) as usize;
+                                self.state = State::Distance { length };
+                                break;
+                            }
+                            EntryKind::Invalid => return Err(InflateError::BadHuffmanCode),
+                        }
+                    }
+                }
+                State::Distance { length } => {
+                    let entry = match decode(&mut self.bits, self.dist.as_ref(), io)? {
+                        Some(entry) => entry,
+                        None => return Ok(StreamState::NeedsInput),
+                    };
+                    if entry.kind() != EntryKind::Base { return Err(InflateError::BadHuffmanCode); }
+                    if !self.bits.need(entry.code_len() + entry.extra(), io) { return Ok(StreamState::NeedsInput); }
+                    self.bits.consume(entry.code_len());
+                    let distance = entry.value() as usize + self.bits.take(entry.extra()) as usize;
+                    if distance > self.max_dist { return Err(InflateError::DistanceTooFarBack); }
+                    if distance > self.whave { return Err(InflateError::BadBlockData); }
+                    self.state = State::Copy { length, distance };
+                }
+                State::Copy { mut length, distance } => {
+                    while length > 0 {
+                        if io.out_pos == io.output.len() {
+                            self.state = State::Copy { length, distance };
+                            return Ok(StreamState::NeedsOutput);
+                        }
+                        let b = self.window[(self.wpos + WINDOW - distance) & (WINDOW - 1)];
+                        self.put(io, b);
+                        length -= 1;
+                    }
+                    self.state = State::Symbol;
+                }
+                State::ZlibTrailer => {
+                    // Adler32 of the output, MSB first, at the next byte boundary
+                    self.bits.consume(self.bits.count % 8);
+                    if !self.bits.need(32, io) { return Ok(StreamState::NeedsInput); }
+                    let expected = self.bits.take(32).swap_bytes();
+                    self.adler.update(&io.output[io.hashed..io.out_pos]);
+                    io.hashed = io.out_pos;
+                    let actual = self.adler.finish();
+                    if expected != actual {
+                        return Err(InflateError::ChecksumMismatch { expected, actual });
+                    }
+                    self.state = State::Done;
+                }
+                State::Done => return Ok(StreamState::Done),
+                State::Failed(e) => return Err(e),
+            }
+        }
+    }
+}
@@ -0,0 +1,127 @@
+// std::io adapters around the streaming Inflater (feature "std")
+
+use std::boxed::Box;
+use std::io::{self, Read, Write};
+use std::vec;
+use std::vec::Vec;
+
+use crate::inflate::{InflateError, Inflater, StreamState};
+
+const BUF_SIZE: usize = 8 * 1024;
+
+impl From<InflateError> for io::Error {
+    fn from(e: InflateError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Reads compressed data from `R` and yields it decompressed.
+pub struct InflateReader<R> {
+    inner: R,
+    inflater: Box<Inflater>,
+    buf: Vec<u8>,
+    pos: usize,
+    len: usize,
+    eof: bool,
+}
+
+impl<R: Read> InflateReader<R> {
+    /// Decompress a zlib stream read from `inner`.
+    pub fn new_zlib(inner: R) -> Self {
+        Self::with_inflater(inner, Inflater::new_zlib())
+    }
+
+    /// Decompress a raw DEFLATE stream read from `inner`.
+    pub fn new_raw(inner: R) -> Self {
+        Self::with_inflater(inner, Inflater::new_raw())
+    }
+
+    fn with_inflater(inner: R, inflater: Inflater) -> Self {
+        InflateReader { inner, inflater: Box::new(inflater), buf: vec![0u8; BUF_SIZE], pos: 0, len: 0, eof: false }
+    }
+
+    /// Give back the underlying reader. Input read past the end of the stream is lost.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for InflateReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.pos == self.len && !self.eof {
+                self.len = self.inner.read(&mut self.buf)?;
+                self.pos = 0;
+                self.eof = self.len == 0;
+            }
+            let status = self.inflater.decompress(&self.buf[self.pos..self.len], out)?;
+            self.pos += status.consumed;
+            if status.produced > 0 {
+                return Ok(status.produced);
+            }
+            match status.state {
+                StreamState::Done => return Ok(0),
+                StreamState::NeedsInput if self.eof => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, InflateError::InputTooShort));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Accepts compressed data and writes it decompressed to `W`.
+///
+/// Bytes written after the end of the stream are an error: `write` takes everything up to
+/// the end, then fails with `ErrorKind::InvalidData` on the rest.
+pub struct InflateWriter<W: Write> {
+    inner: W,
+    inflater: Box<Inflater>,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> InflateWriter<W> {
+    /// Decompress a zlib stream written to this adapter.
+    pub fn new_zlib(inner: W) -> Self {
+        InflateWriter { inner, inflater: Box::new(Inflater::new_zlib()), buf: vec![0u8; BUF_SIZE] }
+    }
+
+    /// Decompress a raw DEFLATE stream written to this adapter.
+    pub fn new_raw(inner: W) -> Self {
+        InflateWriter { inner, inflater: Box::new(Inflater::new_raw()), buf: vec![0u8; BUF_SIZE] }
+    }
+
+    /// Check that the whole stream was received and give back the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.inflater.is_done() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, InflateError::InputTooShort));
+        }
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for InflateWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let mut pos = 0usize;
+        loop {
+            let status = self.inflater.decompress(&data[pos..], &mut self.buf)?;
+            pos += status.consumed;
+            self.inner.write_all(&self.buf[..status.produced])?;
+            if status.state != StreamState::NeedsOutput {
+                if status.state == StreamState::Done && pos == 0 && !data.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "trailing data after the compressed stream"));
+                }
+                // Reports the bytes up to the end of the stream; the next write gets the error
+                return Ok(pos);
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -1,20 +1,48 @@
-// TODO: Uncomment this line to perform test and use as library
-//
-#![cfg_attr(target_arch="arm", no_std)]
-//
+// no_std on every target. Features:
+// - `alloc`: APIs returning `Vec<u8>` (needs a global allocator)
+// - `std`: `std::io` adapters and `std::error::Error` for `InflateError` (implies `alloc`)
+// With neither, the streaming `Inflater`, `Deflater`, `inflate_zlib_into`, `checksum`,
+// `zip::ZipArchive` (extracting into buffers) and `png::decode_into` are still available.
+#![no_std]
 #![deny(warnings)]
 
+#[cfg(feature = "alloc")]
 extern crate alloc;
+// Hosted targets link std even without the `std` feature: it supplies the panic handler and
+// allocator the cdylib needs. Bare-metal (`target_os = "none"`) builds stay core-only.
+#[cfg(any(feature = "std", test, not(target_os = "none")))]
+extern crate std;
+
+pub mod checksum;
 
 // expose the main API
 pub mod inflate;
+pub mod deflate;
+pub mod gzip;
+#[cfg(feature = "alloc")]
+pub mod dictionary;
+#[cfg(feature = "std")]
+pub mod io;
+pub mod png;
+pub mod zip;
 
-pub use inflate::{inflate_zlib, InflateError};
+#[cfg(feature = "alloc")]
+pub use inflate::{inflate_raw, inflate_zlib, inflate_zlib_enforcing_window, inflate_zlib_with_dictionary};
+pub use inflate::{inflate_zlib_into, InflateError, Inflater, Status, StreamState};
+#[cfg(feature = "alloc")]
+pub use deflate::{deflate_raw, deflate_raw_optimal, deflate_raw_with_strategy, deflate_zlib, deflate_zlib_optimal, deflate_zlib_with_dictionary, deflate_zlib_with_strategy};
+pub use deflate::{Deflater, Flush, Strategy};
+#[cfg(feature = "alloc")]
+pub use gzip::{append_gzip_member, deflate_gzip, inflate_gzip};
+pub use gzip::GzipHeader;
 
-#[cfg(test)] 
+#[cfg(all(test, feature = "alloc"))]
 mod tests {
-    use crate::inflate_zlib;
-    use std::process::Command;
+    use crate::{deflate_raw, deflate_raw_optimal, deflate_raw_with_strategy, deflate_zlib, deflate_zlib_optimal, deflate_zlib_with_dictionary, deflate_zlib_with_strategy, append_gzip_member, deflate_gzip, inflate_gzip, inflate_raw, inflate_zlib, inflate_zlib_into, inflate_zlib_with_dictionary, Flush, InflateError, StreamState};
+    use std::string::String;
+    use std::vec::Vec;
+    use std::{format, vec};
+    use std::process::{Command, Stdio};
 
     #[test]
     fn roundtrip_simple() {
@@ -72,4 +100,1645 @@ mod tests {
         let decompressed = inflate_zlib(&compressed).expect("decompress");
         assert_eq!(decompressed, input);
     }
+
+    #[test]
+    fn adler32_known_values() {
+        use crate::checksum::Adler32;
+        let mut a = Adler32::new();
+        a.update(b"Wikipedia");
+        assert_eq!(a.finish(), 0x11E6_0398);
+
+        // Long input exercises the deferred modulo path; compare against the bytewise definition
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 + i / 251) as u8).collect();
+        let (mut s1, mut s2) = (1u32, 0u32);
+        for &b in &data {
+            s1 = (s1 + b as u32) % 65521;
+            s2 = (s2 + s1) % 65521;
+        }
+        let mut a = Adler32::new();
+        a.update(&data[..12345]);
+        a.update(&data[12345..]);
+        assert_eq!(a.finish(), (s2 << 16) | s1);
+    }
+
+    #[test]
+    fn corrupted_trailer_is_rejected() {
+        use flate2::{Compression, write::ZlibEncoder};
+        use std::io::Write;
+        let mut enc = ZlibEncoder::new(Vec::new(), Compression::default());
+        enc.write_all(b"The quick brown fox jumps over the lazy dog").unwrap();
+        let mut compressed = enc.finish().unwrap();
+
+        let last = compressed.len() - 1;
+        compressed[last] ^= 0x01;
+        match inflate_zlib(&compressed) {
+            Err(InflateError::ChecksumMismatch { expected, actual }) => assert_eq!(expected ^ 0x01, actual),
+            other => panic!("expected checksum mismatch, got {:?}", other),
+        }
+
+        compressed.truncate(last - 1);
+        assert!(matches!(inflate_zlib(&compressed), Err(InflateError::TruncatedTrailer)));
+    }
+
+    #[test]
+    fn raw_deflate_reports_consumed() {
+        use flate2::{Compression, write::DeflateEncoder};
+        use std::io::Write;
+        let mut data = Vec::new();
+        for i in 0..2000u32 {
+            data.extend_from_slice(format!("record {} value {}\n", i, i % 17).as_bytes());
+        }
+        for level in [0, 1, 9] {
+            let mut enc = DeflateEncoder::new(Vec::new(), Compression::new(level));
+            enc.write_all(&data).unwrap();
+            let mut compressed = enc.finish().unwrap();
+            let stream_len = compressed.len();
+            // Trailing container bytes must be left untouched
+            compressed.extend_from_slice(b"TRAILER");
+
+            let (out, consumed) = inflate_raw(&compressed).expect("decompress");
+            assert_eq!(out, data);
+            assert_eq!(consumed, stream_len);
+        }
+    }
+
+    #[test]
+    fn gzip_header_fields_and_members() {
+        use crate::gzip::inflate_gzip_member;
+        use flate2::{Compression, GzBuilder};
+        use std::io::Write;
+        let mut enc = GzBuilder::new()
+            .filename("log.txt")
+            .comment("daily log")
+            .extra(vec![b'A', b'B', 2, 0, 1, 2])
+            .mtime(1_700_000_000)
+            .write(Vec::new(), Compression::best());
+        enc.write_all(b"first member\n").unwrap();
+        let mut file = enc.finish().unwrap();
+        let first_len = file.len();
+
+        let mut enc = GzBuilder::new().write(Vec::new(), Compression::fast());
+        enc.write_all(b"second member\n").unwrap();
+        file.extend_from_slice(&enc.finish().unwrap());
+
+        let member = inflate_gzip_member(&file).expect("first member");
+        assert_eq!(member.data, b"first member\n");
+        assert_eq!(member.consumed, first_len);
+        assert_eq!(member.header.name, Some(&b"log.txt"[..]));
+        assert_eq!(member.header.comment, Some(&b"daily log"[..]));
+        assert_eq!(member.header.extra, Some(&[b'A', b'B', 2, 0, 1, 2][..]));
+        assert_eq!(member.header.mtime, 1_700_000_000);
+
+        assert_eq!(inflate_gzip(&file).expect("decompress"), b"first member\nsecond member\n");
+    }
+
+    #[test]
+    fn gzip_checks_header_crc_and_trailer() {
+        use flate2::{Compression, GzBuilder};
+        use std::io::Write;
+        let mut enc = GzBuilder::new().filename("a").write(Vec::new(), Compression::default());
+        enc.write_all(b"The quick brown fox jumps over the lazy dog").unwrap();
+        let plain = enc.finish().unwrap();
+
+        // Add an FHCRC field after the file name
+        let header_len = 10 + 2;
+        let mut with_hcrc = plain[..header_len].to_vec();
+        with_hcrc[3] |= 0x02;
+        let crc = crc_of(&with_hcrc) as u16;
+        with_hcrc.extend_from_slice(&crc.to_le_bytes());
+        with_hcrc.extend_from_slice(&plain[header_len..]);
+        assert_eq!(inflate_gzip(&with_hcrc).expect("decompress"), b"The quick brown fox jumps over the lazy dog");
+        with_hcrc[header_len] ^= 0xff;
+        assert!(matches!(inflate_gzip(&with_hcrc), Err(InflateError::ChecksumMismatch { .. })));
+
+        let mut bad_size = plain.clone();
+        let last = bad_size.len() - 1;
+        bad_size[last] ^= 0x80;
+        assert!(matches!(inflate_gzip(&bad_size), Err(InflateError::SizeMismatch { .. })));
+
+        assert!(matches!(inflate_gzip(&plain[..plain.len() - 3]), Err(InflateError::TruncatedTrailer)));
+    }
+
+    #[test]
+    fn roundtrip_python_zlib_dictionary() {
+        let dict = b"{\"sensor\":\"temperature\",\"unit\":\"celsius\",\"value\":";
+        let input = b"{\"sensor\":\"temperature\",\"unit\":\"celsius\",\"value\":21.5}";
+
+        let python_cmd = if Command::new("python3").arg("--version").output().is_ok() {
+            "python3"
+        } else {
+            "python"
+        };
+        let script = "import sys, zlib, base64; d = base64.b64decode(sys.argv[1]); inp = base64.b64decode(sys.argv[2]); c = zlib.compressobj(zdict=d); out = c.compress(inp) + c.flush(); sys.stdout.write(base64.b64encode(out).decode())";
+        let out = Command::new(python_cmd)
+            .arg("-c")
+            .arg(script)
+            .arg(base64::encode(dict))
+            .arg(base64::encode(input))
+            .output()
+            .expect("failed to run python to create compressed test data");
+        assert!(out.status.success(), "python script failed: {}", String::from_utf8_lossy(&out.stderr));
+        let compressed = base64::decode(String::from_utf8(out.stdout).unwrap().trim()).unwrap();
+        assert_eq!(compressed[1] & 0x20, 0x20, "python did not set FDICT");
+
+        let dictid = u32::from_be_bytes([compressed[2], compressed[3], compressed[4], compressed[5]]);
+        match inflate_zlib(&compressed) {
+            Err(InflateError::DictionaryRequired { dictid: id }) => assert_eq!(id, dictid),
+            other => panic!("expected DictionaryRequired, got {:?}", other),
+        }
+        assert!(matches!(
+            inflate_zlib_with_dictionary(&compressed, b"some other dictionary"),
+            Err(InflateError::DictionaryMismatch { .. })
+        ));
+        let decompressed = inflate_zlib_with_dictionary(&compressed, dict).expect("decompress");
+        assert_eq!(decompressed, input);
+    }
+
+    // Feed `compressed` through an Inflater in `in_chunk` sized pieces, draining `out_chunk` bytes at a time
+    fn inflate_chunked<const W: usize>(mut inflater: crate::Inflater<W>, compressed: &[u8], in_chunk: usize, out_chunk: usize) -> (Vec<u8>, usize) {
+        let mut out = Vec::new();
+        let mut buf = vec![0u8; out_chunk];
+        let mut pos = 0usize;
+        loop {
+            let end = (pos + in_chunk).min(compressed.len());
+            let status = inflater.decompress(&compressed[pos..end], &mut buf).expect("decompress");
+            pos += status.consumed;
+            out.extend_from_slice(&buf[..status.produced]);
+            match status.state {
+                StreamState::Done => return (out, pos),
+                StreamState::NeedsInput => assert!(end > pos || end < compressed.len(), "stalled at {}", pos),
+                StreamState::NeedsOutput => {}
+            }
+        }
+    }
+
+    #[test]
+    fn streaming_inflate_in_small_pieces() {
+        use crate::Inflater;
+        use flate2::{Compression, write::{DeflateEncoder, ZlibEncoder}};
+        use std::io::Write;
+        let mut data = Vec::new();
+        for i in 0..3000u32 {
+            data.extend_from_slice(format!("sample {} reading {}\n", i, (i * 37) % 101).as_bytes());
+        }
+        for level in [0, 1, 6, 9] {
+            let mut enc = ZlibEncoder::new(Vec::new(), Compression::new(level));
+            enc.write_all(&data).unwrap();
+            let compressed = enc.finish().unwrap();
+            for (in_chunk, out_chunk) in [(1, 1), (3, 7), (64, 5), (1 << 20, 1 << 20)] {
+                let (out, consumed) = inflate_chunked(Inflater::new_zlib(), &compressed, in_chunk, out_chunk);
+                assert_eq!(out, data, "level {} chunks {}/{}", level, in_chunk, out_chunk);
+                assert_eq!(consumed, compressed.len());
+            }
+
+            let mut enc = DeflateEncoder::new(Vec::new(), Compression::new(level));
+            enc.write_all(&data).unwrap();
+            let mut compressed = enc.finish().unwrap();
+            let stream_len = compressed.len();
+            compressed.extend_from_slice(b"TRAILER");
+            let (out, consumed) = inflate_chunked(Inflater::new_raw(), &compressed, 13, 100);
+            assert_eq!(out, data);
+            assert_eq!(consumed, stream_len);
+        }
+    }
+
+    #[test]
+    fn streaming_inflate_detects_bad_checksum() {
+        use crate::Inflater;
+        use flate2::{Compression, write::ZlibEncoder};
+        use std::io::Write;
+        let mut enc = ZlibEncoder::new(Vec::new(), Compression::default());
+        enc.write_all(b"The quick brown fox jumps over the lazy dog").unwrap();
+        let mut compressed = enc.finish().unwrap();
+        let last = compressed.len() - 1;
+        compressed[last] ^= 0x01;
+
+        let mut inflater = Inflater::new_zlib();
+        let mut buf = [0u8; 64];
+        let err = inflater.decompress(&compressed, &mut buf).unwrap_err();
+        assert!(matches!(err, InflateError::ChecksumMismatch { .. }));
+        // The error is final rather than resuming from where the stream broke
+        assert_eq!(inflater.decompress(&[], &mut buf).unwrap_err(), err);
+        assert_eq!(inflater.decompress(&compressed, &mut buf).unwrap_err(), err);
+        assert!(!inflater.is_done());
+    }
+
+    #[test]
+    fn inflate_into_caller_buffer() {
+        use flate2::{Compression, write::ZlibEncoder};
+        use std::io::Write;
+        let mut data = Vec::new();
+        for i in 0..1000u32 {
+            data.extend_from_slice(format!("line {:04} {}\n", i, "abc".repeat((i % 5) as usize)).as_bytes());
+        }
+        for level in [0, 1, 9] {
+            let mut enc = ZlibEncoder::new(Vec::new(), Compression::new(level));
+            enc.write_all(&data).unwrap();
+            let compressed = enc.finish().unwrap();
+
+            let mut exact = vec![0u8; data.len()];
+            assert_eq!(inflate_zlib_into(&compressed, &mut exact).expect("decompress"), data.len());
+            assert_eq!(exact, data);
+
+            let mut roomy = vec![0u8; data.len() + 100];
+            assert_eq!(inflate_zlib_into(&compressed, &mut roomy).expect("decompress"), data.len());
+            assert_eq!(&roomy[..data.len()], &data[..]);
+
+            let mut short = vec![0u8; data.len() - 1];
+            assert!(matches!(inflate_zlib_into(&compressed, &mut short), Err(InflateError::OutputOverflow)));
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn io_adapters() {
+        use crate::io::{InflateReader, InflateWriter};
+        use flate2::{Compression, write::ZlibEncoder};
+        use std::io::{Read, Write};
+        let mut data = Vec::new();
+        for i in 0..5000u32 {
+            data.extend_from_slice(format!("{} bottles of beer\n", i).as_bytes());
+        }
+        let mut enc = ZlibEncoder::new(Vec::new(), Compression::default());
+        enc.write_all(&data).unwrap();
+        let compressed = enc.finish().unwrap();
+
+        let mut out = Vec::new();
+        InflateReader::new_zlib(&compressed[..]).read_to_end(&mut out).expect("read");
+        assert_eq!(out, data);
+
+        let mut writer = InflateWriter::new_zlib(Vec::new());
+        for chunk in compressed.chunks(100) {
+            writer.write_all(chunk).expect("write");
+        }
+        assert_eq!(writer.finish().expect("finish"), data);
+
+        let mut writer = InflateWriter::new_zlib(Vec::new());
+        let mut padded = compressed.clone();
+        padded.extend_from_slice(b"junk");
+        let err = writer.write_all(&padded).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(writer.finish().expect("finish"), data);
+
+        let mut truncated = Vec::new();
+        let err = InflateReader::new_zlib(&compressed[..compressed.len() / 2]).read_to_end(&mut truncated).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn huffman_table_long_codes_use_subtables() {
+        use crate::inflate::huffman::{EntryKind, LitLenTable};
+        // Complete code with lengths 1, 2, ..., 15, 15: most codes go past the 11-bit root
+        let mut lengths = [0u8; 288];
+        for (i, len) in lengths.iter_mut().take(15).enumerate() {
+            *len = i as u8 + 1;
+        }
+        lengths[270] = 15;
+        let table = LitLenTable::litlen_from_lengths(&lengths).expect("build");
+
+        let mut next_code = 0u32;
+        let mut prev_len = 0u8;
+        for (sym, &len) in lengths.iter().enumerate().filter(|(_, &l)| l > 0) {
+            next_code <<= len - prev_len;
+            prev_len = len;
+            // Reverse the canonical code into LSB-first order
+            let mut rev = 0u32;
+            for b in 0..len {
+                rev |= ((next_code >> b) & 1) << (len - 1 - b);
+            }
+            let entry = table.lookup(rev | (0x5a5a << len));
+            assert_eq!(entry.code_len(), len as u32, "symbol {}", sym);
+            if sym < 256 {
+                assert_eq!(entry.kind(), EntryKind::Symbol);
+                assert_eq!(entry.value(), sym as u16);
+            } else {
+                // symbol 270: length base 23 with 2 extra bits
+                assert_eq!(entry.kind(), EntryKind::Base);
+                assert_eq!((entry.value(), entry.extra()), (23, 2));
+            }
+            next_code += 1;
+        }
+    }
+
+    #[test]
+    fn bitreader_wide_reads() {
+        use crate::inflate::bitreader::BitReader;
+        let data: Vec<u8> = (0..20u8).map(|i| i.wrapping_mul(37) ^ 0xa5).collect();
+        let mut br = BitReader::new(&data);
+        assert_eq!(br.read_bits(3), Some((data[0] & 7) as u32));
+        // A full 32-bit read straddling five bytes after a partial byte
+        let expected = (u64::from_le_bytes(data[0..8].try_into().unwrap()) >> 3) as u32;
+        assert_eq!(br.read_bits(32), Some(expected));
+        assert_eq!(br.bits_consumed(), 35);
+        assert_eq!(br.bytes_consumed(), 5);
+
+        // Drain up to the tail; the last bits come from the byte-wise slow path
+        let mut pos = 35usize;
+        while pos + 13 <= data.len() * 8 {
+            let mut v = 0u32;
+            for k in 0..13 {
+                v |= (((data[(pos + k) / 8] >> ((pos + k) % 8)) & 1) as u32) << k;
+            }
+            assert_eq!(br.read_bits(13), Some(v), "at bit {}", pos);
+            pos += 13;
+        }
+        assert_eq!(br.bits_consumed(), pos);
+        assert_eq!(br.read_bits(13), None);
+    }
+
+    // Inputs covering stored, fixed and dynamic blocks, and blocks split by size
+    fn deflate_samples() -> Vec<Vec<u8>> {
+        let mut text = Vec::new();
+        for i in 0..3000u32 {
+            text.extend_from_slice(format!("{} sensor={} value={}\n", i, i % 7, (i * 37) % 1000).as_bytes());
+        }
+        let mut state = 0x2545_f491u32;
+        let noise: Vec<u8> = (0..70_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        vec![Vec::new(), b"a".to_vec(), b"The quick brown fox jumps over the lazy dog".to_vec(), text, noise, vec![0u8; 300_000]]
+    }
+
+    #[test]
+    fn deflate_roundtrip_all_levels() {
+        for data in deflate_samples() {
+            for level in 0..=9 {
+                let compressed = deflate_zlib(&data, level);
+                assert_eq!(inflate_zlib(&compressed).expect("inflate_zlib"), data, "level {} len {}", level, data.len());
+                let raw = deflate_raw(&data, level);
+                let (out, consumed) = inflate_raw(&raw).expect("inflate_raw");
+                assert_eq!(out, data);
+                assert_eq!(consumed, raw.len());
+            }
+        }
+    }
+
+    #[test]
+    fn deflate_output_readable_by_flate2() {
+        use flate2::read::ZlibDecoder;
+        use flate2::{write::ZlibEncoder, Compression};
+        use std::io::{Read, Write};
+        for data in deflate_samples() {
+            for level in [1, 6, 9] {
+                let compressed = deflate_zlib(&data, level);
+                let mut out = Vec::new();
+                ZlibDecoder::new(&compressed[..]).read_to_end(&mut out).expect("flate2 decode");
+                assert_eq!(out, data);
+
+                // Not much worse than zlib at the same level
+                let mut enc = ZlibEncoder::new(Vec::new(), Compression::new(level as u32));
+                enc.write_all(&data).unwrap();
+                let reference = enc.finish().unwrap();
+                assert!(compressed.len() <= reference.len() + reference.len() / 10 + 16, "level {}: {} vs zlib {}", level, compressed.len(), reference.len());
+            }
+        }
+    }
+
+    #[test]
+    fn deflate_picks_block_type() {
+        let samples = deflate_samples();
+        // BTYPE is in bits 1-2 of the first byte of a raw stream
+        assert_eq!((deflate_raw(&samples[3], 6)[0] >> 1) & 3, 2, "text should use a dynamic block");
+        assert_eq!((deflate_raw(&samples[4], 6)[0] >> 1) & 3, 0, "noise should be stored");
+        assert_eq!((deflate_raw(&samples[2], 6)[0] >> 1) & 3, 1, "short text should use the fixed codes");
+        assert_eq!(deflate_raw(&[], 6), [0x03, 0x00]);
+    }
+
+    #[test]
+    fn huffman_lengths_are_optimal_within_limit() {
+        use crate::deflate::huffman::build_lengths;
+        let cost = |freqs: &[u32], lens: &[u8]| freqs.iter().zip(lens).map(|(&f, &l)| f as u64 * l as u64).sum::<u64>();
+        let kraft = |lens: &[u8], max: usize| lens.iter().filter(|&&l| l > 0).map(|&l| 1u64 << (max - l as usize)).sum::<u64>();
+
+        // Fibonacci frequencies make the unrestricted code as deep as possible
+        let mut fib = vec![1u32, 1];
+        while fib.len() < 30 {
+            fib.push(fib[fib.len() - 1] + fib[fib.len() - 2]);
+        }
+        let mut lens = vec![0u8; fib.len()];
+        build_lengths(&fib, 15, &mut lens);
+        assert_eq!(*lens.iter().max().unwrap(), 15);
+        assert_eq!(kraft(&lens, 15), 1 << 15, "code should be complete");
+        build_lengths(&fib[..8], 3, &mut lens[..8]);
+        assert_eq!(&lens[..8], &[3u8; 8]);
+        // Alphabets too small for a complete code
+        build_lengths(&[5], 15, &mut lens[..1]);
+        assert_eq!(lens[0], 1);
+        build_lengths(&[], 15, &mut []);
+
+        // Against exhaustive search on small alphabets
+        for freqs in [[1u32, 1, 2, 3, 5, 8], [10, 1, 1, 1, 1, 1], [0, 7, 3, 0, 2, 9]] {
+            for limit in 3usize..=5 {
+                let mut best = u64::MAX;
+                for combo in 0..(limit + 1).pow(6) {
+                    let mut c = combo;
+                    let cand: Vec<u8> = (0..6).map(|_| { let l = (c % (limit + 1)) as u8; c /= limit + 1; l }).collect();
+                    let fits = freqs.iter().zip(&cand).all(|(&f, &l)| (f > 0) == (l > 0)) && kraft(&cand, limit) <= 1 << limit;
+                    if fits {
+                        best = best.min(cost(&freqs, &cand));
+                    }
+                }
+                let mut lens = [0u8; 6];
+                build_lengths(&freqs, limit, &mut lens);
+                assert!(lens.iter().all(|&l| l as usize <= limit));
+                assert!(kraft(&lens, limit) <= 1 << limit);
+                assert_eq!(cost(&freqs, &lens), best, "freqs {:?} limit {}", freqs, limit);
+            }
+        }
+    }
+
+    #[test]
+    fn code_length_rle_is_inverse_of_header_parsing() {
+        use crate::deflate::huffman::rle_code_lengths;
+        let mut state = 0x1234_5678u32;
+        for round in 0..200 {
+            let n = 258 + (round % 60);
+            let lengths: Vec<u8> = (0..n)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    // Long runs of zeros and of repeated lengths
+                    match state % 8 { 0..=3 => 0, 4 | 5 => 8, _ => (state % 16) as u8 }
+                })
+                .collect();
+            let mut ops = vec![(0u8, 0u8); n];
+            let count = rle_code_lengths(&lengths, &mut ops);
+            // Expand as inflate does for a dynamic block header
+            let mut decoded: Vec<u8> = Vec::new();
+            for &(sym, extra) in &ops[..count] {
+                match sym {
+                    0..=15 => decoded.push(sym),
+                    16 => { assert!(extra <= 3); let prev = *decoded.last().unwrap(); decoded.extend(std::iter::repeat_n(prev, extra as usize + 3)); }
+                    17 => { assert!(extra <= 7); decoded.extend(std::iter::repeat_n(0, extra as usize + 3)); }
+                    18 => { assert!(extra <= 127); decoded.extend(std::iter::repeat_n(0, extra as usize + 11)); }
+                    _ => panic!("bad symbol {}", sym),
+                }
+            }
+            assert_eq!(decoded, lengths);
+        }
+    }
+
+    #[test]
+    fn deflate_block_with_skewed_frequencies() {
+        use crate::deflate::bitwriter::BitWriter;
+        use crate::deflate::block::{Block, BlockWriter};
+        use crate::inflate::{DIST_BASE, LENGTH_BASE};
+        // Symbol frequencies follow the Fibonacci numbers, so unrestricted codes would be deeper than 15 bits
+        let mut fib = vec![1usize, 1];
+        while fib.len() < 25 {
+            fib.push(fib[fib.len() - 1] + fib[fib.len() - 2]);
+        }
+        let data: Vec<u8> = fib.iter().enumerate().flat_map(|(b, &n)| std::iter::repeat_n(b as u8, n)).collect();
+        // About 214000 tokens, all in one block
+        let mut block = Block::<{ 1 << 18 }>::new();
+        for &b in &data {
+            block.push_literal(b);
+        }
+        // Lengths and distances as skewed, one of each code
+        for (i, &n) in fib.iter().enumerate().take(20) {
+            for _ in 0..n {
+                block.push_match(LENGTH_BASE[i] as usize, DIST_BASE[i] as usize);
+            }
+        }
+        let mut expected = data.clone();
+        for (i, &n) in fib.iter().enumerate().take(20) {
+            for _ in 0..n {
+                let start = expected.len() - DIST_BASE[i] as usize;
+                for k in 0..LENGTH_BASE[i] as usize {
+                    expected.push(expected[start + k]);
+                }
+            }
+        }
+        let mut writer = BlockWriter::new();
+        let mut bw = BitWriter::<1024>::new();
+        let mut compressed = Vec::new();
+        let mut chunk = [0u8; 256];
+        writer.start(&block.tokens(), Some(&expected), true, crate::deflate::block::BlockTypes::Any, 0);
+        while !writer.resume(&block.tokens(), Some(&expected), &mut bw) {
+            let n = bw.drain_into(&mut chunk);
+            compressed.extend_from_slice(&chunk[..n]);
+        }
+        bw.align_to_byte();
+        while !bw.is_empty() {
+            let n = bw.drain_into(&mut chunk);
+            compressed.extend_from_slice(&chunk[..n]);
+        }
+        assert_eq!((compressed[0] >> 1) & 3, 2, "expected a dynamic block");
+
+        let (out, consumed) = inflate_raw(&compressed).expect("inflate_raw");
+        assert_eq!(out, expected);
+        assert_eq!(consumed, compressed.len());
+    }
+
+    // Compress `data` with `deflater`, passing input and taking output in pieces and
+    // applying `flushes` at the given input offsets
+    fn deflate_chunked<const W: usize, const H: usize, const T: usize>(mut deflater: crate::Deflater<W, H, T>, data: &[u8], in_chunk: usize, out_chunk: usize, flushes: &[(usize, Flush)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut buf = vec![0u8; out_chunk];
+        let mut pos = 0;
+        let mut next_flush = 0;
+        loop {
+            let mut end = (pos + in_chunk).min(data.len());
+            let mut flush = if end == data.len() { Flush::Finish } else { Flush::None };
+            if let Some(&(at, f)) = flushes.get(next_flush) {
+                if at <= end {
+                    end = at;
+                    flush = f;
+                }
+            }
+            let status = deflater.compress(&data[pos..end], &mut buf, flush);
+            pos += status.consumed;
+            out.extend_from_slice(&buf[..status.produced]);
+            match status.state {
+                StreamState::Done => return out,
+                StreamState::NeedsInput if flush != Flush::None && flush != Flush::Finish && pos == end => next_flush += 1,
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn deflater_reset_starts_a_new_stream() {
+        use crate::{Deflater, Strategy};
+        let samples = deflate_samples();
+        let (text, noise) = (&samples[3], &samples[4]);
+        let finish = |deflater: &mut Deflater<1024, 1024, 1024>, data: &[u8]| {
+            let mut out = vec![0u8; data.len() * 2 + 64];
+            let status = deflater.compress(data, &mut out, Flush::Finish);
+            assert_eq!(status.state, StreamState::Done);
+            out.truncate(status.produced);
+            out
+        };
+        let containers: [fn(u8) -> Deflater<1024, 1024, 1024>; 3] = [Deflater::new_zlib_windowed, Deflater::new_raw_windowed, Deflater::new_gzip_windowed];
+        for new in containers {
+            let mut fresh = new(6);
+            fresh.set_strategy(Strategy::Filtered);
+            let expected = finish(&mut fresh, text);
+            // After a finished stream, one left in the middle of a block, and one with a
+            // preset dictionary, the output is that of a new compressor
+            let mut reused = new(6);
+            reused.set_strategy(Strategy::Filtered);
+            finish(&mut reused, noise);
+            reused.reset();
+            assert_eq!(finish(&mut reused, text), expected);
+            reused.reset();
+            let mut buf = [0u8; 16];
+            reused.compress(&text[..5000], &mut buf, Flush::None);
+            reused.reset();
+            assert_eq!(finish(&mut reused, text), expected);
+            reused.reset();
+            reused.set_dictionary(&noise[..3000]);
+            finish(&mut reused, text);
+            reused.reset();
+            assert_eq!(finish(&mut reused, text), expected);
+        }
+    }
+
+    #[test]
+    fn streaming_deflate_in_small_pieces() {
+        for data in deflate_samples() {
+            for level in [0u8, 1, 6, 9] {
+                let expected = deflate_zlib(&data, level);
+                let compressed = deflate_chunked(crate::Deflater::new_zlib(level), &data, 777, 61, &[]);
+                // Chunking doesn't change the output
+                assert_eq!(compressed, expected, "level {level}, {} bytes", data.len());
+                assert_eq!(inflate_zlib(&compressed).expect("inflate_zlib"), data);
+            }
+        }
+    }
+
+    #[test]
+    fn deflate_sync_and_full_flush() {
+        let data: Vec<u8> = (0..2000).flat_map(|i| format!("line {} of the log, value {}\n", i, i % 17).into_bytes()).collect();
+        let points = [10_000, 30_000, 30_000, 45_000];
+        for level in [0u8, 1, 6] {
+            for flush in [Flush::Sync, Flush::Full] {
+                let flushes: Vec<(usize, Flush)> = points.iter().map(|&p| (p, flush)).collect();
+                let compressed = deflate_chunked(crate::Deflater::new_raw(level), &data, 4096, 100, &flushes);
+                // A repeated flush with no new input adds nothing
+                let markers = compressed.windows(4).filter(|w| *w == [0, 0, 0xff, 0xff]).count();
+                assert_eq!(markers, 3, "level {level} {flush:?}");
+                let (out, _) = inflate_raw(&compressed).expect("inflate_raw");
+                assert_eq!(out, data);
+
+                let mut d = flate2::Decompress::new(false);
+                let mut out = Vec::with_capacity(data.len());
+                d.decompress_vec(&compressed, &mut out, flate2::FlushDecompress::Finish).expect("flate2");
+                assert_eq!(out, data);
+            }
+        }
+
+        // Everything before a sync flush is decodable as soon as it is written
+        let mut deflater = crate::Deflater::new_zlib(6);
+        let mut buf = vec![0u8; 64 * 1024];
+        let status = deflater.compress(&data[..20_000], &mut buf, Flush::Sync);
+        assert_eq!(status.state, StreamState::NeedsInput);
+        assert_eq!(status.consumed, 20_000);
+        let produced = &buf[..status.produced];
+        assert!(produced.ends_with(&[0, 0, 0xff, 0xff]));
+        let mut inflater = crate::Inflater::new_zlib();
+        let mut out = vec![0u8; 64 * 1024];
+        let s = inflater.decompress(produced, &mut out).expect("decompress");
+        assert_eq!(&out[..s.produced], &data[..20_000]);
+    }
+
+    #[test]
+    fn deflate_full_flush_restarts_history() {
+        let data: Vec<u8> = (0..3000).flat_map(|i| format!("record {} status ok\n", i % 50).into_bytes()).collect();
+        let split = 25_000;
+        let mut deflater = crate::Deflater::new_raw(6);
+        let mut buf = vec![0u8; 64 * 1024];
+        let first = deflater.compress(&data[..split], &mut buf, Flush::Full);
+        assert_eq!(first.consumed, split);
+        let head_len = first.produced;
+        let rest = deflater.compress(&data[split..], &mut buf[head_len..], Flush::Finish);
+        assert_eq!(rest.state, StreamState::Done);
+        let compressed = &buf[..head_len + rest.produced];
+        assert_eq!(inflate_raw(compressed).expect("inflate_raw").0, data);
+
+        // The part after a full flush decodes on its own, with no earlier history
+        let (tail, _) = inflate_raw(&compressed[head_len..]).expect("inflate_raw after full flush");
+        assert_eq!(tail, &data[split..]);
+    }
+
+    #[test]
+    fn small_window_deflater() {
+        use crate::Inflater;
+        fn check<const W: usize, const H: usize, const T: usize>(samples: &[Vec<u8>]) {
+            let mut streams = Vec::new();
+            for data in samples {
+                for level in [0u8, 1, 6, 9] {
+                    let compressed = deflate_chunked(crate::Deflater::<W, H, T>::new_zlib_windowed(level), data, 1000, 300, &[]);
+                    // CINFO declares the window
+                    assert_eq!(compressed[0], ((W.trailing_zeros() - 8) << 4) as u8 | 8);
+                    assert_eq!(inflate_zlib(&compressed).expect("inflate_zlib"), *data);
+
+                    let mut inflater = Inflater::<W>::new_zlib_windowed();
+                    inflater.set_enforce_window(true);
+                    let (out, _) = inflate_chunked(inflater, &compressed, 512, 512);
+                    assert_eq!(out, *data, "window {W}, level {level}");
+                    streams.push(compressed);
+                }
+            }
+            // zlib decodes them with no more than the declared window
+            let args: Vec<&[u8]> = streams.iter().map(|s| s.as_slice()).collect();
+            let body = format!("out = b''.join(zlib.decompress(x, {}) for x in a)", W.trailing_zeros());
+            let expected: Vec<u8> = samples.iter().flat_map(|d| [d; 4]).flatten().copied().collect();
+            assert!(python_compress(&body, &args) == expected, "python zlib, window {W}");
+        }
+        let samples = deflate_samples();
+        check::<256, 256, 256>(&samples);
+        check::<1024, 1024, 1024>(&samples);
+        check::<4096, 4096, 2048>(&samples);
+
+        // Small windows still find the repetition in text
+        let text = &samples[3];
+        let compressed = deflate_chunked(crate::Deflater::<1024, 1024, 1024>::new_zlib_windowed(6), text, 4096, 4096, &[]);
+        assert!(compressed.len() < text.len() / 3, "{} of {}", compressed.len(), text.len());
+
+        assert!(core::mem::size_of::<crate::Deflater<1024, 1024, 1024>>() < 16 * 1024);
+        assert!(core::mem::size_of::<crate::Deflater<256, 256, 256>>() < 8 * 1024);
+    }
+
+    #[test]
+    fn optimal_deflate_beats_level_9() {
+        use flate2::{write::ZlibEncoder, Compression};
+        use std::io::Write;
+        for data in deflate_samples() {
+            let compressed = deflate_zlib_optimal(&data, 5);
+            assert_eq!(inflate_zlib(&compressed).expect("inflate_zlib"), data);
+            assert!(compressed.len() <= deflate_zlib(&data, 9).len(), "{} bytes", data.len());
+
+            let mut enc = ZlibEncoder::new(Vec::new(), Compression::best());
+            enc.write_all(&data).unwrap();
+            let zlib_best = enc.finish().unwrap();
+            assert!(compressed.len() <= zlib_best.len(), "{} vs zlib {} for {} bytes", compressed.len(), zlib_best.len(), data.len());
+            let mut d = flate2::Decompress::new(true);
+            let mut out = Vec::with_capacity(data.len());
+            d.decompress_vec(&compressed, &mut out, flate2::FlushDecompress::Finish).expect("flate2");
+            assert_eq!(out, data);
+        }
+
+        // Text gains noticeably over zlib's best
+        let text = &deflate_samples()[3];
+        let optimal = deflate_raw_optimal(text, 10).len();
+        let level9 = deflate_raw(text, 9).len();
+        assert!(optimal * 100 < level9 * 97, "optimal {optimal}, level 9 {level9}");
+    }
+
+    #[test]
+    fn optimal_deflate_splits_blocks() {
+        // Text, noise and text again (too far back to match the first) want different
+        // codes; one block for all would pay for the noise's flat literal code in the text
+        let samples = deflate_samples();
+        let (text, noise) = (&samples[3][..40_000], &samples[4][..20_000]);
+        let data: Vec<u8> = [text, noise, text].concat();
+        let compressed = deflate_raw_optimal(&data, 3);
+        assert_eq!(inflate_raw(&compressed).expect("inflate_raw").0, data);
+        let separate = 2 * deflate_raw_optimal(text, 3).len() + noise.len();
+        assert!(compressed.len() <= separate, "{} vs {}", compressed.len(), separate);
+        // The first block is the text alone
+        assert_eq!((compressed[0] >> 1) & 3, 2);
+    }
+
+    #[test]
+    fn deflate_strategies() {
+        use crate::Strategy;
+        let strategies = [Strategy::Default, Strategy::Filtered, Strategy::HuffmanOnly, Strategy::Rle, Strategy::Fixed];
+        let mut samples = deflate_samples();
+        // Sensor deltas: small values wandering around zero
+        let mut x = 7u32;
+        samples.push((0..20_000).map(|_| { x ^= x << 13; x ^= x >> 17; x ^= x << 5; (x % 7) as u8 }).collect());
+        for data in &samples {
+            for strategy in strategies {
+                for level in [0u8, 1, 3, 6, 9] {
+                    let compressed = deflate_zlib_with_strategy(data, level, strategy);
+                    assert_eq!(inflate_zlib(&compressed).expect("inflate_zlib"), *data, "{strategy:?} level {level}");
+                    let raw = deflate_raw_with_strategy(data, level, strategy);
+                    assert_eq!(inflate_raw(&raw).expect("inflate_raw").0, *data);
+
+                    // FLEVEL as zlib sets it
+                    let flevel = compressed[1] >> 6;
+                    let expected = match (strategy, level) {
+                        (Strategy::HuffmanOnly | Strategy::Rle | Strategy::Fixed, _) | (_, 0 | 1) => 0,
+                        (_, 3) => 1,
+                        (_, 6) => 2,
+                        _ => 3,
+                    };
+                    assert_eq!(flevel, expected, "{strategy:?} level {level}");
+                }
+            }
+        }
+
+        let text = &samples[3];
+        let default = deflate_raw(text, 6).len();
+        // Without matches text compresses far less, but still with Huffman codes
+        let huffman = deflate_raw_with_strategy(text, 6, Strategy::HuffmanOnly);
+        assert!(huffman.len() > default * 2 && huffman.len() < text.len() * 3 / 4);
+        // Runs only: long runs still compress, text barely does
+        let zeros = &samples[5];
+        assert!(deflate_raw_with_strategy(zeros, 6, Strategy::Rle).len() < 1000);
+        assert!(deflate_raw_with_strategy(text, 6, Strategy::Rle).len() > default * 2);
+        // Fixed codes only: the first block header says so
+        let fixed = deflate_raw_with_strategy(text, 6, Strategy::Fixed);
+        assert_eq!((fixed[0] >> 1) & 3, 1);
+        assert!(fixed.len() > default);
+        // Filtered data gains from leaving short matches to the Huffman codes
+        let deltas = &samples[6];
+        assert!(deflate_raw_with_strategy(deltas, 6, Strategy::Filtered).len() < deflate_raw(deltas, 6).len());
+
+        // Decoders other than ours agree
+        for strategy in strategies {
+            let compressed = deflate_zlib_with_strategy(text, 9, strategy);
+            let mut d = flate2::Decompress::new(true);
+            let mut out = Vec::with_capacity(text.len());
+            d.decompress_vec(&compressed, &mut out, flate2::FlushDecompress::Finish).expect("flate2");
+            assert_eq!(out, *text);
+        }
+    }
+
+    #[test]
+    fn fixed_blocks_between_dynamic_ones() {
+        // Fixed, dynamic and fixed blocks again, so the decoders' shared fixed tables must
+        // survive a dynamic block in between
+        let samples = deflate_samples();
+        let text = &samples[3];
+        let mut deflater = std::boxed::Box::new(crate::Deflater::new_zlib(6));
+        let mut compressed = vec![0u8; text.len() * 4 + 64];
+        let mut produced = 0;
+        let parts = [(crate::Strategy::Fixed, Flush::Sync), (crate::Strategy::Default, Flush::Sync), (crate::Strategy::Fixed, Flush::Finish)];
+        for (part, (strategy, flush)) in text.chunks(text.len().div_ceil(3)).zip(parts) {
+            deflater.set_strategy(strategy);
+            let status = deflater.compress(part, &mut compressed[produced..], flush);
+            assert_eq!(status.consumed, part.len());
+            produced += status.produced;
+        }
+        compressed.truncate(produced);
+        assert_eq!((compressed[2] >> 1) & 3, 1);
+        assert_eq!(inflate_zlib(&compressed).unwrap(), *text);
+        for chunk in [1, 7, 4096] {
+            let (out, _) = inflate_chunked(crate::Inflater::new_zlib(), &compressed, chunk, chunk);
+            assert_eq!(out, *text);
+        }
+        let mut d = flate2::Decompress::new(true);
+        let mut out = Vec::with_capacity(text.len());
+        d.decompress_vec(&compressed, &mut out, flate2::FlushDecompress::Finish).expect("flate2");
+        assert_eq!(out, *text);
+    }
+
+    #[test]
+    fn rle_does_not_reach_across_full_flush() {
+        let data = vec![b'x'; 5000];
+        let mut deflater = crate::Deflater::new_raw(6);
+        deflater.set_strategy(crate::Strategy::Rle);
+        let mut buf = vec![0u8; 4096];
+        let first = deflater.compress(&data[..2000], &mut buf, Flush::Full);
+        let head_len = first.produced;
+        let rest = deflater.compress(&data[2000..], &mut buf[head_len..], Flush::Finish);
+        assert_eq!(rest.state, StreamState::Done);
+        let (tail, _) = inflate_raw(&buf[head_len..head_len + rest.produced]).expect("inflate_raw after full flush");
+        assert_eq!(tail, &data[2000..]);
+    }
+
+    #[test]
+    fn gzip_writer_header_fields_and_trailer() {
+        use crate::GzipHeader;
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+        let data = &deflate_samples()[3];
+        let header = GzipHeader {
+            mtime: 1_700_000_000,
+            os: 3,
+            extra: Some(&[b'A', b'B', 2, 0, 7, 9]),
+            name: Some(b"sensors.log"),
+            comment: Some(b"daily log"),
+            header_crc: Some(0),
+            ..GzipHeader::default()
+        };
+        for level in [0u8, 1, 6, 9] {
+            let file = deflate_gzip(data, level, &header);
+            let mut dec = GzDecoder::new(&file[..]);
+            let mut out = Vec::new();
+            dec.read_to_end(&mut out).expect("flate2 GzDecoder");
+            assert_eq!(out, *data);
+            let h = dec.header().expect("gzip header");
+            assert_eq!(h.filename(), Some(&b"sensors.log"[..]));
+            assert_eq!(h.comment(), Some(&b"daily log"[..]));
+            assert_eq!(h.extra(), Some(&[b'A', b'B', 2, 0, 7, 9][..]));
+            assert_eq!(h.mtime(), 1_700_000_000);
+            assert_eq!(h.operating_system(), 3);
+
+            let (parsed, _) = GzipHeader::parse(&file).expect("parse");
+            assert_eq!(parsed.xfl, [4, 4, 0, 2][[0u8, 1, 6, 9].iter().position(|&l| l == level).unwrap()]);
+            assert!(parsed.header_crc.is_some());
+            assert_eq!(inflate_gzip(&file).expect("inflate_gzip"), *data);
+            // CRC-32 and ISIZE trailer
+            let trailer = &file[file.len() - 8..];
+            assert_eq!(trailer[..4], crc_of(data).to_le_bytes());
+            assert_eq!(trailer[4..], (data.len() as u32).to_le_bytes());
+        }
+
+        // Streaming: header written by the caller, then the member body
+        let mut buf = vec![0u8; header.encoded_len()];
+        assert_eq!(header.write_to(&mut buf[..10]), None);
+        let bad_name = GzipHeader { name: Some(b"a\0b"), ..header.clone() };
+        assert!(std::panic::catch_unwind(|| bad_name.write_to(&mut [0u8; 64])).is_err());
+        let len = header.write_to(&mut buf).unwrap();
+        assert_eq!(len, buf.len());
+        let mut file = buf;
+        file.extend_from_slice(&deflate_chunked(crate::Deflater::new_gzip(6), data, 1000, 100, &[]));
+        assert_eq!(inflate_gzip(&file).expect("inflate_gzip"), *data);
+    }
+
+    #[test]
+    fn gzip_append_members() {
+        use crate::GzipHeader;
+        use flate2::read::MultiGzDecoder;
+        use std::io::Read;
+        let days: Vec<Vec<u8>> = (0..4).map(|d| format!("day {d}: all readings nominal\n").repeat(200 + d * 50).into_bytes()).collect();
+        let mut file = Vec::new();
+        let mut expected = Vec::new();
+        for (d, day) in days.iter().enumerate() {
+            let before = file.clone();
+            let name = format!("day{d}.log");
+            append_gzip_member(&mut file, day, 6, &GzipHeader { name: Some(name.as_bytes()), mtime: d as u32, ..GzipHeader::default() });
+            // Earlier members are left as they were
+            assert_eq!(file[..before.len()], before[..]);
+            expected.extend_from_slice(day);
+        }
+        assert_eq!(inflate_gzip(&file).expect("inflate_gzip"), expected);
+        let mut out = Vec::new();
+        MultiGzDecoder::new(&file[..]).read_to_end(&mut out).expect("flate2 MultiGzDecoder");
+        assert_eq!(out, expected);
+        let out = python_compress("import gzip; out = gzip.decompress(a[0])", &[&file]);
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn deflate_with_preset_dictionary() {
+        let dict = b"{\"device\": \"sensor-\", \"temperature\": , \"humidity\": , \"status\": \"ok\"}";
+        let message = b"{\"device\": \"sensor-17\", \"temperature\": 21.5, \"humidity\": 40, \"status\": \"ok\"}";
+        for level in [0u8, 1, 6, 9] {
+            let compressed = deflate_zlib_with_dictionary(message, dict, level);
+            // FDICT set, DICTID is the dictionary's Adler-32
+            assert_eq!(compressed[1] & 0x20, 0x20);
+            assert_eq!(((compressed[0] as u16) << 8 | compressed[1] as u16) % 31, 0);
+            let mut adler = crate::checksum::Adler32::new();
+            adler.update(dict);
+            assert_eq!(compressed[2..6], adler.finish().to_be_bytes());
+
+            assert_eq!(inflate_zlib_with_dictionary(&compressed, dict).expect("inflate with dictionary"), message);
+            assert!(matches!(inflate_zlib(&compressed), Err(InflateError::DictionaryRequired { .. })));
+            // Stock zlib decodes it given the same dictionary
+            let out = python_compress("d = zlib.decompressobj(zdict=a[1]); out = d.decompress(a[0]) + d.flush()", &[&compressed, dict]);
+            assert_eq!(out, message);
+        }
+        // The dictionary pays off on small messages
+        assert!(deflate_zlib_with_dictionary(message, dict, 9).len() + 20 < deflate_zlib(message, 9).len());
+
+        // Dictionaries longer than a small window keep their end
+        let long_dict: Vec<u8> = (0..3000).flat_map(|i| format!("entry {i};").into_bytes()).collect();
+        let data = b"entry 2998;entry 2999;entry 17;";
+        let mut deflater = crate::Deflater::<1024, 1024, 1024>::new_zlib_windowed(6);
+        deflater.set_dictionary(&long_dict);
+        let compressed = deflate_chunked(deflater, data, 100, 100, &[]);
+        assert_eq!(inflate_zlib_with_dictionary(&compressed, &long_dict).expect("inflate with dictionary"), data);
+        assert!(compressed.len() < 30);
+
+        // Too late once compressing has started
+        let mut started = crate::Deflater::<1024, 1024, 1024>::new_zlib_windowed(6);
+        started.compress(data, &mut [0u8; 64], Flush::None);
+        assert!(std::panic::catch_unwind(move || started.set_dictionary(dict)).is_err());
+    }
+
+    #[test]
+    fn trained_dictionary() {
+        let message = |i: u32| {
+            let status = ["ok", "low battery", "offline"][(i % 3) as usize];
+            format!("{{\"device\": \"sensor-{}\", \"temperature\": {}.{}, \"humidity\": {}, \"status\": \"{}\"}}", i * 7 % 100, 15 + i % 12, i % 10, 30 + i * 13 % 50, status).into_bytes()
+        };
+        let training: Vec<Vec<u8>> = (0..200).map(message).collect();
+        let training: Vec<&[u8]> = training.iter().map(|m| m.as_slice()).collect();
+        let dict = crate::dictionary::train(&training, 256);
+        assert!(!dict.is_empty() && dict.len() <= 256);
+        // The field names shared by every sample are kept, the most common near the end
+        let tail = &dict[dict.len().saturating_sub(64)..];
+        assert!(tail.windows(8).any(|w| w == b"humidity" || w == b"temperat" || w == b"\"device\""));
+
+        let held_out: Vec<Vec<u8>> = (1000..1050).map(message).collect();
+        let held_out: Vec<&[u8]> = held_out.iter().map(|m| m.as_slice()).collect();
+        let report = crate::dictionary::report(&held_out, &dict, 6);
+        assert_eq!(report.len(), held_out.len());
+        for (saving, sample) in report.iter().zip(&held_out) {
+            assert_eq!(saving.original, sample.len());
+            assert!(saving.saved() > 0, "{:?}", saving);
+        }
+        let before: usize = report.iter().map(|s| s.without_dictionary).sum();
+        let after: usize = report.iter().map(|s| s.with_dictionary).sum();
+        assert!(after * 2 < before, "{} -> {}", before, after);
+        for sample in &held_out {
+            let compressed = deflate_zlib_with_dictionary(sample, &dict, 6);
+            assert_eq!(inflate_zlib_with_dictionary(&compressed, &dict).expect("inflate with dictionary"), *sample);
+        }
+
+        // Degenerate inputs
+        assert!(crate::dictionary::train(&[], 1024).is_empty());
+        assert!(crate::dictionary::train(&[b"only one sample, nothing shared"], 1024).is_empty());
+        assert!(crate::dictionary::train(&training, 0).is_empty());
+    }
+
+    #[test]
+    fn checksum_combine() {
+        use crate::checksum::{adler32_combine, crc32_combine, Adler32};
+        let samples = deflate_samples();
+        let data: Vec<u8> = samples[3].iter().chain(&samples[4]).copied().collect();
+        let adler_of = |data: &[u8]| {
+            let mut adler = Adler32::new();
+            adler.update(data);
+            adler.finish()
+        };
+        // Known values
+        assert_eq!(adler_of(b"Wikipedia"), 0x11E6_0398);
+        assert_eq!(crc_of(b"123456789"), 0xCBF4_3926);
+        for split in [0, 1, 7, 5552, 65521, 65522, 100_000, data.len() - 1, data.len()] {
+            let (first, second) = data.split_at(split);
+            let len2 = second.len() as u64;
+            assert_eq!(adler32_combine(adler_of(first), adler_of(second), len2), adler_of(&data), "split {}", split);
+            assert_eq!(crc32_combine(crc_of(first), crc_of(second), len2), crc_of(&data), "split {}", split);
+        }
+        // Lengths beyond 4 GiB, built by doubling a run of zeros
+        let mut zeros_crc = crc_of(&[0]);
+        let mut zeros_adler = adler_of(&[0]);
+        for bit in 0..33 {
+            zeros_crc = crc32_combine(zeros_crc, zeros_crc, 1 << bit);
+            zeros_adler = adler32_combine(zeros_adler, zeros_adler, 1 << bit);
+        }
+        // Adler-32 of n zeros is (n mod 65521) << 16 | 1
+        assert_eq!(zeros_adler, (((1u64 << 33) % 65521) as u32) << 16 | 1);
+        let head = crc_of(b"head");
+        let whole = crc32_combine(head, zeros_crc, 1 << 33);
+        let mut half = crc_of(&[0]);
+        for bit in 0..32 {
+            half = crc32_combine(half, half, 1 << bit);
+        }
+        assert_eq!(crc32_combine(crc32_combine(head, half, 1 << 32), half, 1 << 32), whole);
+    }
+
+    #[test]
+    fn zip_archive_from_python() {
+        use crate::zip::{CompressionMethod, DosDateTime, ZipArchive};
+        let samples = deflate_samples();
+        let (text, noise) = (&samples[3], &samples[4][..5000]);
+        let archive = python_compress(
+            "import io, zipfile, struct; b = io.BytesIO(); z = zipfile.ZipFile(b, 'w'); z.comment = b'bundle v1'; \
+             i = zipfile.ZipInfo('text.txt', (2021, 6, 15, 13, 45, 30)); i.compress_type = zipfile.ZIP_DEFLATED; i.comment = b'the text'; \
+             i.extra = struct.pack('<HHBI', 0x5455, 5, 1, 1623764730); z.writestr(i, a[0]); \
+             z.writestr(zipfile.ZipInfo('noise.bin', (1980, 1, 1, 0, 0, 0)), a[1]); \
+             z.writestr(zipfile.ZipInfo('dir/', (2000, 2, 29, 23, 59, 58)), b''); \
+             z.writestr('d\\u00e9j\\u00e0 vu.txt', b'unicode name', zipfile.ZIP_DEFLATED); z.close(); out = b.getvalue()",
+            &[text, noise],
+        );
+        let zip = ZipArchive::new(&archive).expect("open archive");
+        assert_eq!(zip.len(), 4);
+        assert_eq!(zip.comment(), b"bundle v1");
+        let entries: Vec<_> = zip.entries().collect::<Result<_, _>>().expect("central directory");
+        let names: Vec<&[u8]> = entries.iter().map(|e| e.name).collect();
+        assert_eq!(names, [&b"text.txt"[..], b"noise.bin", b"dir/", "d\u{e9}j\u{e0} vu.txt".as_bytes()]);
+
+        let entry = &entries[0];
+        assert_eq!(entry.method, CompressionMethod::Deflated);
+        assert_eq!(entry.comment, b"the text");
+        assert_eq!(entry.modified, DosDateTime { year: 2021, month: 6, day: 15, hour: 13, minute: 45, second: 30 });
+        assert_eq!(entry.unix_mtime(), Some(1623764730));
+        assert_eq!(entry.extra_fields().map(|(id, _)| id).collect::<Vec<_>>(), [0x5455]);
+        assert_eq!((entry.uncompressed_size, entry.crc32), (text.len() as u64, crc_of(text)));
+        assert!(entry.compressed_size < entry.uncompressed_size && !entry.is_utf8() && !entry.is_dir());
+        assert_eq!(zip.extract(entry).expect("extract text"), *text);
+        let mut buf = vec![0u8; text.len()];
+        assert_eq!(zip.extract_into(entry, &mut buf).expect("extract text into buffer"), text.len());
+        assert_eq!(buf, *text);
+        assert!(matches!(zip.extract_into(entry, &mut buf[..100]), Err(InflateError::OutputOverflow)));
+
+        let entry = &entries[1];
+        assert_eq!(entry.method, CompressionMethod::Stored);
+        assert_eq!(entry.modified.to_dos(), (0x21, 0));
+        assert_eq!(entry.unix_mtime(), None);
+        assert_eq!(zip.raw_data(entry).expect("stored data"), noise);
+        assert_eq!(zip.extract(entry).expect("extract noise"), noise);
+
+        assert!(entries[2].is_dir());
+        assert_eq!(entries[2].modified, DosDateTime { year: 2000, month: 2, day: 29, hour: 23, minute: 59, second: 58 });
+        assert_eq!(zip.extract(&entries[2]).expect("extract directory"), b"");
+        assert!(entries[3].is_utf8());
+        let entry = zip.by_name("d\u{e9}j\u{e0} vu.txt".as_bytes()).expect("central directory").expect("entry by name");
+        assert_eq!(zip.extract(&entry).expect("extract unicode"), b"unicode name");
+        assert!(zip.by_name(b"missing").expect("central directory").is_none());
+
+        // Corrupted data fails the CRC check
+        let offset = zip.raw_data(&entries[1]).unwrap().as_ptr() as usize - archive.as_ptr() as usize;
+        let mut corrupt = archive.clone();
+        corrupt[offset + 10] ^= 1;
+        let zip = ZipArchive::new(&corrupt).unwrap();
+        let entry = zip.by_name(b"noise.bin").unwrap().unwrap();
+        assert!(matches!(zip.extract(&entry), Err(InflateError::ChecksumMismatch { .. })));
+        // Unknown methods and encryption are refused
+        let entry = crate::zip::ZipEntry { method: CompressionMethod::Other(12), ..entry };
+        assert!(matches!(zip.extract(&entry), Err(InflateError::Unsupported)));
+        let entry = crate::zip::ZipEntry { method: CompressionMethod::Stored, flags: 1, ..entry };
+        assert!(matches!(zip.extract(&entry), Err(InflateError::Unsupported)));
+        // Not an archive, or cut short
+        assert!(ZipArchive::new(text).is_err());
+        assert!(ZipArchive::new(&archive[..archive.len() - 15]).is_err());
+        let cut = ZipArchive::new(&archive[archive.len() - 40..]);
+        assert!(cut.is_err() || cut.unwrap().entries().any(|e| e.is_err()));
+    }
+
+    #[test]
+    fn zip64_and_data_descriptors() {
+        use crate::zip::{CompressionMethod, LocalEntries, ZipArchive, ZipEntry};
+        let samples = deflate_samples();
+        let (text, noise) = (&samples[3], &samples[4][..5000]);
+        let tricky = b"stored data with a descriptor signature PK\x07\x08 inside it".repeat(3);
+        // Lowering zipfile's limits makes it write ZIP64 records for small test files
+        let zip64 = python_compress(
+            "import io, zipfile; zipfile.ZIP64_LIMIT = 16; zipfile.ZIP_FILECOUNT_LIMIT = 1; b = io.BytesIO(); \
+             z = zipfile.ZipFile(b, 'w', zipfile.ZIP_DEFLATED); z.writestr('text.txt', a[0]); z.writestr('noise.bin', a[1], zipfile.ZIP_STORED); \
+             z.writestr('small', b'tiny'); z.close(); out = b.getvalue()",
+            &[text, noise],
+        );
+        // Writing to an unseekable stream makes it put sizes in data descriptors
+        let streamed = python_compress(
+            "import io, zipfile; b = io.BytesIO(); w = type('W', (), {'write': lambda s, d: b.write(d), 'flush': lambda s: None})(); \
+             z = zipfile.ZipFile(w, 'w', zipfile.ZIP_DEFLATED); f = z.open('text.txt', 'w', force_zip64=True); f.write(a[0]); f.close(); \
+             z.writestr('noise.bin', a[1], zipfile.ZIP_STORED); z.writestr('tricky.txt', a[2], zipfile.ZIP_STORED); \
+             z.writestr('empty', b''); z.writestr('small', b'tiny'); z.close(); out = b.getvalue()",
+            &[text, noise, &tricky],
+        );
+        let contents: [(&[u8], &[u8]); 5] = [(b"text.txt", text), (b"noise.bin", noise), (b"tricky.txt", &tricky), (b"empty", b""), (b"small", b"tiny")];
+
+        assert!(zip64.windows(4).any(|w| w == b"PK\x06\x06") && zip64.windows(4).any(|w| w == b"PK\x06\x07"));
+        let zip = ZipArchive::new(&zip64).expect("open ZIP64 archive");
+        assert_eq!(zip.len(), 3);
+        let entries: Vec<ZipEntry> = zip.entries().collect::<Result<_, _>>().expect("central directory");
+        assert!(entries.iter().all(|e| e.extra_fields().any(|(id, _)| id == 1)));
+        assert_eq!(entries[1].method, CompressionMethod::Stored);
+        for entry in &entries {
+            let (_, expected) = contents.iter().find(|(name, _)| *name == entry.name).unwrap();
+            assert_eq!(entry.uncompressed_size, expected.len() as u64);
+            assert_eq!(zip.extract(entry).expect("extract ZIP64 entry"), *expected);
+        }
+        let local: Vec<ZipEntry> = LocalEntries::new(&zip64).collect::<Result<_, _>>().expect("local headers");
+        assert_eq!(local.len(), 3);
+        for (local, central) in local.iter().zip(&entries) {
+            assert_eq!((local.name, local.crc32, local.compressed_size, local.uncompressed_size), (central.name, central.crc32, central.compressed_size, central.uncompressed_size));
+            assert_eq!(local.header_offset, central.header_offset);
+        }
+
+        let zip = ZipArchive::new(&streamed).expect("open streamed archive");
+        let entries: Vec<ZipEntry> = zip.entries().collect::<Result<_, _>>().expect("central directory");
+        let local: Vec<ZipEntry> = LocalEntries::new(&streamed).collect::<Result<_, _>>().expect("local headers");
+        assert_eq!((entries.len(), local.len()), (5, 5));
+        for ((central, local), (name, expected)) in entries.iter().zip(&local).zip(contents) {
+            assert!(central.has_data_descriptor() && local.has_data_descriptor());
+            assert_eq!((central.name, local.name), (name, name));
+            assert_eq!((local.method, local.crc32, local.compressed_size, local.uncompressed_size), (central.method, central.crc32, central.compressed_size, central.uncompressed_size));
+            assert_eq!(zip.extract(central).expect("extract by central directory"), expected);
+            assert_eq!(LocalEntries::new(&streamed).extract(local).expect("extract by local header"), expected);
+        }
+        assert_eq!(local[0].method, CompressionMethod::Deflated);
+        assert_eq!(local[2].method, CompressionMethod::Stored);
+
+        // The local headers of a truncated download are still readable up to the cut
+        let cut = &streamed[..local[2].header_offset as usize + 40];
+        let mut partial = LocalEntries::new(cut);
+        assert_eq!(partial.next().unwrap().expect("first entry").name, b"text.txt");
+        assert_eq!(partial.next().unwrap().expect("second entry").name, b"noise.bin");
+        assert!(partial.next().unwrap().is_err());
+        assert!(partial.next().is_none());
+        assert!(ZipArchive::new(cut).is_err());
+
+        // A hand-built central directory whose ZIP64 header offset ends past the address space
+        let mut forged = Vec::new();
+        forged.extend_from_slice(b"PK\x01\x02");
+        forged.extend_from_slice(&[45, 0, 45, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        forged.extend_from_slice(&[1, 0, 12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+        forged.extend_from_slice(b"x\x01\x00\x08\x00");
+        forged.extend_from_slice(&(u64::MAX - 1).to_le_bytes());
+        let central_len = forged.len() as u32;
+        forged.extend_from_slice(b"PK\x05\x06\x00\x00\x00\x00\x01\x00\x01\x00");
+        forged.extend_from_slice(&central_len.to_le_bytes());
+        forged.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        let zip = ZipArchive::new(&forged).expect("open forged archive");
+        let entry = zip.entries().next().unwrap().expect("forged entry");
+        assert_eq!(entry.header_offset, u64::MAX - 1);
+        assert!(matches!(zip.extract(&entry), Err(InflateError::InputTooShort)));
+        assert!(matches!(zip.raw_data(&entry), Err(InflateError::InputTooShort)));
+    }
+
+    #[test]
+    fn zip_writer_roundtrip() {
+        use crate::zip::{CompressionMethod, DosDateTime, FileOptions, LocalEntries, ZipArchive, ZipWriter};
+        let samples = deflate_samples();
+        let (text, noise) = (&samples[3], &samples[4][..20_000]);
+        let modified = DosDateTime { year: 2024, month: 3, day: 9, hour: 17, minute: 5, second: 42 };
+        let log = FileOptions { modified, unix_mode: Some(0o100644), ..FileOptions::default() };
+        let stored = FileOptions { method: CompressionMethod::Stored, ..log };
+
+        let mut zip = ZipWriter::new(Vec::new());
+        zip.set_comment(b"device 17 logs");
+        zip.start_file("logs/", &FileOptions { unix_mode: Some(0o40755), ..stored }).unwrap();
+        zip.start_file("logs/app.log", &log).unwrap();
+        for chunk in text.chunks(1000) {
+            zip.write(chunk).unwrap();
+        }
+        zip.write_file("logs/core.bin", noise, &stored).unwrap();
+        zip.start_file("logs/trace.bin", &stored).unwrap();
+        for chunk in noise.chunks(3000) {
+            zip.write(chunk).unwrap();
+        }
+        zip.write_file("logs/empty.log", b"", &FileOptions { level: 9, ..log }).unwrap();
+        zip.write_file("logs/\u{fc}bersicht.txt", b"non-ASCII name", &FileOptions { level: 1, ..log }).unwrap();
+        let archive = zip.finish().unwrap();
+        let contents: [(&str, &[u8]); 6] = [("logs/", b""), ("logs/app.log", text), ("logs/core.bin", noise), ("logs/trace.bin", noise), ("logs/empty.log", b""), ("logs/\u{fc}bersicht.txt", b"non-ASCII name")];
+
+        // Read back by the central directory and by the local headers
+        let zip = ZipArchive::new(&archive).expect("open written archive");
+        assert_eq!(zip.comment(), b"device 17 logs");
+        let entries: Vec<_> = zip.entries().collect::<Result<_, _>>().expect("central directory");
+        let local: Vec<_> = LocalEntries::new(&archive).collect::<Result<_, _>>().expect("local headers");
+        assert_eq!((entries.len(), local.len()), (6, 6));
+        for ((entry, local), (name, data)) in entries.iter().zip(&local).zip(contents) {
+            assert_eq!((entry.name, local.name), (name.as_bytes(), name.as_bytes()));
+            assert_eq!(entry.is_utf8(), !name.is_ascii());
+            assert_eq!((entry.modified, entry.crc32, entry.uncompressed_size), (modified, crc_of(data), data.len() as u64));
+            assert_eq!((local.crc32, local.compressed_size), (entry.crc32, entry.compressed_size));
+            assert_eq!(zip.extract(entry).expect("extract written entry"), data);
+        }
+        assert!(entries[0].is_dir() && entries[0].external_attributes >> 16 == 0o40755);
+        assert_eq!(entries[1].method, CompressionMethod::Deflated);
+        assert!(entries[1].compressed_size * 3 < entries[1].uncompressed_size);
+        assert_eq!(entries[2].method, CompressionMethod::Stored);
+        assert_eq!((entries[2].compressed_size, entries[3].compressed_size), (noise.len() as u64, noise.len() as u64));
+        assert_eq!(entries[3].method, CompressionMethod::Stored);
+        // Only the stored entry given whole has its sizes up front; the rest have data descriptors
+        for (i, (entry, local)) in entries.iter().zip(&local).enumerate() {
+            assert_eq!((entry.has_data_descriptor(), local.has_data_descriptor()), (i != 2, i != 2));
+            assert_eq!(local.extra, b"");
+        }
+        assert_eq!(entries[2].header_offset as usize + 30 + 13 + noise.len(), entries[3].header_offset as usize);
+        assert_eq!(entries[3].header_offset as usize + 30 + 14 + noise.len() + 16, entries[4].header_offset as usize);
+
+        // Python's zipfile agrees and finds the CRCs correct
+        let listing = python_compress(
+            "import io, zipfile; z = zipfile.ZipFile(io.BytesIO(a[0])); assert z.testzip() is None; \
+             out = (z.comment.decode() + ''.join('|%s %s %o %d %d' % (i.filename, i.date_time, i.external_attr >> 16, i.compress_type, len(z.read(i))) for i in z.infolist())).encode()",
+            &[&archive],
+        );
+        let expected: String = contents.iter().zip([0o40755, 0o100644, 0o100644, 0o100644, 0o100644, 0o100644]).zip([0, 8, 0, 0, 8, 8]).map(|(((name, data), mode), method)| {
+            format!("|{} (2024, 3, 9, 17, 5, 42) {:o} {} {}", name, mode, method, data.len())
+        }).collect();
+        assert_eq!(String::from_utf8(listing).unwrap(), format!("device 17 logs{}", expected));
+
+        // Entries that may reach 4 GiB carry a ZIP64 extra field in their local header, and
+        // 8-byte sizes in their data descriptor if they have one
+        let large = FileOptions { large_file: true, ..log };
+        let mut zip = ZipWriter::new(Vec::new());
+        zip.write_file("big.log", text, &large).unwrap();
+        zip.write_file("big.bin", noise, &FileOptions { method: CompressionMethod::Stored, ..large }).unwrap();
+        let archive = zip.finish().unwrap();
+        let zip = ZipArchive::new(&archive).expect("open archive with large entries");
+        let entries: Vec<_> = zip.entries().collect::<Result<_, _>>().expect("central directory");
+        let local: Vec<_> = LocalEntries::new(&archive).collect::<Result<_, _>>().expect("local headers");
+        for ((entry, local), data) in entries.iter().zip(&local).zip([&text[..], noise]) {
+            assert_eq!(local.extra_fields().map(|(id, field)| (id, field.len())).collect::<Vec<_>>(), [(1, 16)]);
+            assert_eq!(u16::from_le_bytes([archive[entry.header_offset as usize + 4], archive[entry.header_offset as usize + 5]]), 45);
+            assert_eq!((local.crc32, local.compressed_size, local.uncompressed_size), (entry.crc32, entry.compressed_size, data.len() as u64));
+            assert_eq!(zip.extract(entry).expect("extract large entry"), data);
+        }
+        let header_len = 30 + 7 + 20;
+        assert_eq!(entries[0].header_offset as usize + header_len + entries[0].compressed_size as usize + 24, entries[1].header_offset as usize);
+        assert!(!entries[1].has_data_descriptor());
+        let read = python_compress("import io, zipfile; z = zipfile.ZipFile(io.BytesIO(a[0])); assert z.testzip() is None; out = z.read('big.log') + z.read('big.bin')", &[&archive]);
+        assert_eq!(read, [&text[..], noise].concat());
+
+        // More than 65535 entries need the ZIP64 end records
+        let mut zip = ZipWriter::new(Vec::new());
+        for i in 0..65537u32 {
+            zip.write_file(&format!("{}", i), &i.to_le_bytes(), &FileOptions { method: CompressionMethod::Stored, ..FileOptions::default() }).unwrap();
+        }
+        let archive = zip.finish().unwrap();
+        let zip = ZipArchive::new(&archive).expect("open ZIP64 archive");
+        assert_eq!(zip.len(), 65537);
+        let last = zip.entries().last().unwrap().expect("last entry");
+        assert_eq!((last.name, zip.extract(&last).expect("extract last entry")), (&b"65536"[..], 65536u32.to_le_bytes().to_vec()));
+        let count = python_compress("import io, zipfile; z = zipfile.ZipFile(io.BytesIO(a[0])); out = b'%d %s' % (len(z.infolist()), z.read('65536'))", &[&archive]);
+        assert_eq!(count, b"65537 \x00\x00\x01\x00");
+    }
+
+    // Reference PNG encoder: a[0] holds the samples as big-endian u16s, a[1] "width height
+    // color_type depth interlace", a[2] and a[3] the PLTE and tRNS data or "none". Rows get
+    // the five filters in turn, and the zlib stream is split over 100-byte IDAT chunks.
+    const PYTHON_PNG: &str = r#"import struct
+w, h, ct, depth, il = map(int, a[1].split())
+ch = {0: 1, 2: 3, 3: 1, 4: 2, 6: 4}[ct]
+s = [int.from_bytes(a[0][i:i + 2], 'big') for i in range(0, len(a[0]), 2)]
+def pack(px):
+    vals = [s[p * ch + c] for p in px for c in range(ch)]
+    if depth == 16: return b''.join(v.to_bytes(2, 'big') for v in vals)
+    if depth == 8: return bytes(vals)
+    bits = ''.join(format(v, '0%db' % depth) for v in vals)
+    bits += '0' * (-len(bits) % 8)
+    return bytes(int(bits[i:i + 8], 2) for i in range(0, len(bits), 8))
+passes = [(0, 0, 8, 8), (4, 0, 8, 8), (0, 4, 4, 8), (2, 0, 4, 4), (0, 2, 2, 4), (1, 0, 2, 2), (0, 1, 1, 2)] if il else [(0, 0, 1, 1)]
+bpp = max(1, ch * depth // 8)
+raw = b''
+n = 0
+for x0, y0, dx, dy in passes:
+    prev = None
+    for y in range(y0, h, dy):
+        row = pack([y * w + x for x in range(x0, w, dx)])
+        if not row: continue
+        prev = prev or bytes(len(row))
+        f = n % 5
+        n += 1
+        out = bytearray([f])
+        for i in range(len(row)):
+            l = row[i - bpp] if i >= bpp else 0
+            u = prev[i]
+            ul = prev[i - bpp] if i >= bpp else 0
+            p = l + u - ul
+            pl, pu, pul = abs(p - l), abs(p - u), abs(p - ul)
+            pred = [0, l, u, (l + u) // 2, l if pl <= pu and pl <= pul else (u if pu <= pul else ul)][f]
+            out.append((row[i] - pred) & 255)
+        raw += bytes(out)
+        prev = row
+def chunk(t, d): return len(d).to_bytes(4, 'big') + t + d + zlib.crc32(t + d).to_bytes(4, 'big')
+z = zlib.compress(raw, 9)
+out = b'\x89PNG\r\n\x1a\n' + chunk(b'IHDR', struct.pack('>IIBBBBB', w, h, depth, ct, 0, 0, il))
+out += chunk(b'tEXt', b'Comment\x00reference encoder')
+if a[2] != b'none': out += chunk(b'PLTE', a[2])
+if a[3] != b'none': out += chunk(b'tRNS', a[3])
+out += b''.join(chunk(b'IDAT', z[i:i + 100]) for i in range(0, len(z), 100)) + chunk(b'IEND', b'')"#;
+
+    // Expected decoder output for samples of the given color type and depth
+    fn png_expected(color_type: u8, depth: u8, samples: &[u16], palette: &[u8], trns: Option<&[u8]>) -> Vec<u8> {
+        let scale = |v: u16| match depth {
+            16 => (v >> 8) as u8,
+            8 => v as u8,
+            _ => (v as u32 * 255 / ((1u32 << depth) - 1)) as u8,
+        };
+        let key = |t: &[u8], c: usize| u16::from_be_bytes([t[2 * c], t[2 * c + 1]]);
+        let mut out = Vec::new();
+        let channels = [1, 0, 3, 1, 2, 0, 4][color_type as usize];
+        for px in samples.chunks(channels) {
+            match (color_type, trns) {
+                (3, _) => {
+                    out.extend_from_slice(&palette[3 * px[0] as usize..][..3]);
+                    if let Some(t) = trns {
+                        out.push(t.get(px[0] as usize).copied().unwrap_or(255));
+                    }
+                }
+                (0, Some(t)) => out.extend_from_slice(&[scale(px[0]), if px[0] == key(t, 0) { 0 } else { 255 }]),
+                (2, Some(t)) => {
+                    out.extend(px.iter().map(|&v| scale(v)));
+                    out.push(if (0..3).all(|c| px[c] == key(t, c)) { 0 } else { 255 });
+                }
+                _ => out.extend(px.iter().map(|&v| scale(v))),
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn png_decode_against_reference_encoder() {
+        use crate::png::{decode, decode_into, read_info, ColorType, PixelFormat};
+        let mut state = 0x9e37_79b9u32;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+        let palette: Vec<u8> = (0..200u32).flat_map(|i| [i as u8, (i * 7) as u8, 255 - i as u8]).collect();
+        let cases: &[(u8, u8, Option<&[u8]>)] = &[
+            (0, 1, None), (0, 2, None), (0, 4, None), (0, 8, None), (0, 16, None), (0, 8, Some(&[0, 7])), (0, 2, Some(&[0, 1])),
+            (2, 8, None), (2, 16, None), (2, 8, Some(&[0, 1, 0, 2, 0, 3])),
+            (3, 1, None), (3, 2, None), (3, 4, None), (3, 8, None), (3, 8, Some(&[0, 128, 255])),
+            (4, 8, None), (4, 16, None), (6, 8, None), (6, 16, None),
+        ];
+        for &(color_type, depth, trns) in cases {
+            for (width, height, interlace) in [(13, 7, 0), (13, 7, 1), (3, 2, 1), (40, 9, 1)] {
+                let channels = [1, 0, 3, 1, 2, 0, 4][color_type as usize];
+                let max = match color_type {
+                    3 => 1 << depth.min(7),
+                    _ => 1u32 << depth,
+                };
+                let samples: Vec<u16> = (0..width * height * channels)
+                    .map(|_| {
+                        // Mostly small values, so tRNS keys and runs turn up
+                        let v = random();
+                        (if v & 3 == 0 { v >> 8 & 7 } else { v >> 8 } % max) as u16
+                    })
+                    .collect();
+                let plte = if color_type == 3 { &palette[..3 * max as usize] } else { &b"none"[..] };
+                let sample_bytes: Vec<u8> = samples.iter().flat_map(|v| v.to_be_bytes()).collect();
+                let params = format!("{} {} {} {} {}", width, height, color_type, depth, interlace);
+                let png = python_compress(PYTHON_PNG, &[&sample_bytes, params.as_bytes(), plte, trns.unwrap_or(b"none")]);
+
+                let info = read_info(&png).expect("read PNG header");
+                assert_eq!((info.width, info.height, info.bit_depth, info.color_type.to_u8(), info.interlaced), (width as u32, height as u32, depth, color_type, interlace == 1));
+                let expected = png_expected(color_type, depth, &samples, plte, trns);
+                let image = decode(&png).unwrap_or_else(|e| panic!("decode {}: {:?}", params, e));
+                assert_eq!(image.pixels, expected, "{} trns {:?}", params, trns);
+                assert_eq!(image.pixels.len(), info.output_len());
+                let alpha = trns.is_some() || matches!(info.color_type, ColorType::GrayAlpha | ColorType::Rgba);
+                assert_eq!(matches!(image.format, PixelFormat::GrayAlpha | PixelFormat::Rgba), alpha);
+
+                let mut out = vec![0u8; info.output_len()];
+                let mut scratch = vec![0u8; info.scratch_len()];
+                decode_into(&png, &mut out, &mut scratch).expect("decode into buffers");
+                assert_eq!(out, expected);
+                if out.len() > 1 {
+                    assert!(matches!(decode_into(&png, &mut out[1..], &mut scratch), Err(InflateError::OutputOverflow)));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn png_decode_rejects_damage() {
+        use crate::png::decode;
+        let samples: Vec<u8> = (0..64u16 * 3).flat_map(|v| (v * 5 % 256).to_be_bytes()).collect();
+        let png = python_compress(PYTHON_PNG, &[&samples, b"8 8 2 8 0", b"none", b"none"]);
+        assert_eq!(decode(&png).expect("decode").pixels.len(), 8 * 8 * 3);
+        // Every chunk's CRC is checked, ancillary ones included
+        let text = png.windows(4).position(|w| w == b"tEXt").unwrap();
+        let mut bad = png.clone();
+        bad[text + 6] ^= 1;
+        assert!(matches!(decode(&bad), Err(InflateError::ChecksumMismatch { .. })));
+        // Bad signature, truncation and unknown critical chunks are errors; a missing IEND is not
+        let mut bad = png.clone();
+        bad[1] = b'Q';
+        assert!(matches!(decode(&bad), Err(InflateError::InvalidHeader)));
+        assert!(decode(&png[..png.len() - 40]).is_err());
+        assert!(decode(&png[..4]).is_err());
+        assert!(decode(&png[..png.len() - 12]).is_ok());
+        let mut bad = png.clone();
+        let len = u32::from_be_bytes(png[text - 4..text].try_into().unwrap()) as usize;
+        bad[text..text + 4].copy_from_slice(b"TEXt");
+        let crc = crc_of(&bad[text..text + 4 + len]);
+        bad[text + 4 + len..text + 8 + len].copy_from_slice(&crc.to_be_bytes());
+        assert!(matches!(decode(&bad), Err(InflateError::Unsupported)));
+        // Chunk lengths over 2^31 - 1 are invalid, and must not wrap the offset arithmetic
+        for len in [0xFFFF_FFF4u32, 0x8000_0000, 0x7FFF_FFFF] {
+            let mut bad = png.clone();
+            bad[text - 4..text].copy_from_slice(&len.to_be_bytes());
+            let expected = if len > i32::MAX as u32 { InflateError::InvalidHeader } else { InflateError::InputTooShort };
+            assert_eq!(decode(&bad).unwrap_err(), expected);
+            assert_eq!(crate::png::read_info(&bad).unwrap_err(), expected);
+        }
+    }
+
+    #[test]
+    fn png_encode_roundtrip() {
+        use crate::png::{decode, encode, ColorType, EncodeOptions, Filter, FilterStrategy, RawImage};
+        let palette: Vec<u8> = (0..16u8).flat_map(|i| [i * 16, 255 - i, i]).collect();
+        // Color type, depth, palette and tRNS (none if empty)
+        let cases: &[(ColorType, u8, &[u8], &[u8])] = &[
+            (ColorType::Gray, 1, &[], &[]), (ColorType::Gray, 4, &[], &[0, 3]), (ColorType::Gray, 16, &[], &[]),
+            (ColorType::Rgb, 8, &[], &[0, 1, 0, 2, 0, 3]), (ColorType::Rgb, 16, &palette, &[]),
+            (ColorType::Indexed, 2, &palette[..12], &[0, 128]), (ColorType::Indexed, 4, &palette, &[]),
+            (ColorType::GrayAlpha, 8, &[], &[]), (ColorType::Rgba, 16, &[], &[]),
+        ];
+        let strategies = [Filter::None, Filter::Sub, Filter::Up, Filter::Average, Filter::Paeth].map(FilterStrategy::Fixed);
+        for &(color_type, depth, plte, trns) in cases {
+            let trns = (!trns.is_empty()).then_some(trns);
+            for (width, height) in [(1, 1), (13, 7), (70, 3)] {
+                let channels = color_type.channels();
+                let max = if color_type == ColorType::Indexed { plte.len() as u32 / 3 } else { 1 << depth };
+                // Smooth with some noise, so each filter has something to do
+                let samples: Vec<u16> = (0..width * height * channels)
+                    .map(|i| {
+                        let (x, y) = (i / channels % width, i / channels / width);
+                        ((x * 3 + y * 5 + i % channels + (i * i) % 3) as u32 % max) as u16
+                    })
+                    .collect();
+                let mut data = Vec::new();
+                for row in samples.chunks(width * channels) {
+                    let mut bits = 0u32;
+                    let mut acc = 0u32;
+                    for &v in row {
+                        match depth {
+                            16 => data.extend_from_slice(&v.to_be_bytes()),
+                            8 => data.push(v as u8),
+                            _ => {
+                                acc = acc << depth | v as u32;
+                                bits += depth as u32;
+                                if bits == 8 {
+                                    data.push(acc as u8);
+                                    (acc, bits) = (0, 0);
+                                }
+                            }
+                        }
+                    }
+                    if bits > 0 {
+                        data.push((acc << (8 - bits)) as u8);
+                    }
+                }
+                let image = RawImage { width: width as u32, height: height as u32, color_type, bit_depth: depth, data: &data, palette: plte, transparency: trns };
+                let expected = png_expected(color_type.to_u8(), depth, &samples, plte, trns);
+                for filter in strategies.into_iter().chain([FilterStrategy::MinSum, FilterStrategy::BruteForce]) {
+                    for level in [0, 6] {
+                        let png = encode(&image, &EncodeOptions { filter, level });
+                        let decoded = decode(&png).unwrap_or_else(|e| panic!("{:?} {} {:?}: {:?}", color_type, depth, filter, e));
+                        assert_eq!((decoded.width, decoded.height), (width as u32, height as u32));
+                        assert_eq!(decoded.pixels, expected, "{:?} {} {}x{} {:?}", color_type, depth, width, height, filter);
+                    }
+                }
+            }
+        }
+
+        // Decoded pixels encode back to the same pixels
+        let pixels: Vec<u8> = (0..40 * 30 * 4).map(|i| ((i % 251) ^ (i / 160)) as u8).collect();
+        let png = encode(&RawImage::new(40, 30, crate::png::PixelFormat::Rgba, &pixels), &EncodeOptions::default());
+        assert_eq!(decode(&png).unwrap().pixels, pixels);
+    }
+
+    #[test]
+    fn png_encode_filters_and_chunks() {
+        use crate::png::{encode, ColorType, EncodeOptions, Filter, FilterStrategy, PixelFormat, RawImage};
+        // A horizontal gradient, the same on every row: Sub suits the first row, Up the rest
+        let (width, height) = (200usize, 50usize);
+        let pixels: Vec<u8> = (0..height).flat_map(|_| (0..width).flat_map(|x| [(x * 2) as u8, x as u8, (255 - x) as u8])).collect();
+        let image = RawImage::new(width as u32, height as u32, PixelFormat::Rgb, &pixels);
+        let encode_with = |filter| encode(&image, &EncodeOptions { filter, level: 6 });
+        let none = encode_with(FilterStrategy::Fixed(Filter::None));
+        let min_sum = encode_with(FilterStrategy::MinSum);
+        let brute = encode_with(FilterStrategy::BruteForce);
+        assert!(min_sum.len() < none.len() && brute.len() < none.len(), "{} {} {}", none.len(), min_sum.len(), brute.len());
+
+        // Chunks, CRCs and the zlib stream as Python reads them
+        let parsed = python_compress(
+            "import struct\nd = a[0]\nassert d[:8] == b'\\x89PNG\\r\\n\\x1a\\n'\np = 8\nkinds = []\nidat = b''\nwhile p < len(d):\n    n, = struct.unpack('>I', d[p:p + 4])\n    t = d[p + 4:p + 8]\n    assert zlib.crc32(d[p + 4:p + 8 + n]) == struct.unpack('>I', d[p + 8 + n:p + 12 + n])[0]\n    kinds.append(t)\n    if t == b'IDAT': idat += d[p + 8:p + 8 + n]\n    p += 12 + n\nout = b' '.join(kinds) + b'|' + zlib.decompress(idat)",
+            &[&min_sum],
+        );
+        let split = parsed.iter().position(|&b| b == b'|').unwrap();
+        assert_eq!(&parsed[..split], b"IHDR IDAT IEND");
+        let raw = &parsed[split + 1..];
+        assert_eq!(raw.len(), height * (3 * width + 1));
+        let filters: Vec<u8> = raw.chunks(3 * width + 1).map(|row| row[0]).collect();
+        assert_eq!(filters[0], 1);
+        assert!(filters[1..].iter().all(|&f| f == 2), "{:?}", filters);
+
+        // Large images are split into 8 KiB IDAT chunks; PLTE and tRNS precede them
+        let indices: Vec<u8> = (0..300 * 300u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+        let palette: Vec<u8> = (0..=255u8).flat_map(|i| [i, i, i]).collect();
+        let image = RawImage { width: 300, height: 300, color_type: ColorType::Indexed, bit_depth: 8, data: &indices, palette: &palette, transparency: Some(&[0, 0, 0]) };
+        let png = encode(&image, &EncodeOptions { filter: FilterStrategy::Fixed(Filter::None), level: 1 });
+        let mut pos = 8;
+        let mut kinds = Vec::new();
+        while pos < png.len() {
+            let len = u32::from_be_bytes(png[pos..pos + 4].try_into().unwrap()) as usize;
+            kinds.push((std::str::from_utf8(&png[pos + 4..pos + 8]).unwrap(), len));
+            pos += 12 + len;
+        }
+        assert_eq!(&kinds[..3], &[("IHDR", 13), ("PLTE", 768), ("tRNS", 3)]);
+        assert!(kinds[3..kinds.len() - 2].iter().all(|&k| k == ("IDAT", 8192)), "{:?}", kinds);
+        assert_eq!(kinds[kinds.len() - 1], ("IEND", 0));
+        let decoded = crate::png::decode(&png).unwrap();
+        assert_eq!(decoded.format, PixelFormat::Rgba);
+        assert!(decoded.pixels.chunks(4).zip(&indices).all(|(px, &i)| px == [i, i, i, if i < 3 { 0 } else { 255 }]));
+    }
+
+    // Run a python snippet that sets `out` from the base64-decoded arguments `a`
+    fn python_compress(body: &str, args: &[&[u8]]) -> Vec<u8> {
+        use std::io::Write;
+        let python_cmd = if Command::new("python3").arg("--version").output().is_ok() {
+            "python3"
+        } else {
+            "python"
+        };
+        let script = format!(
+            "import sys, zlib, base64; a = [base64.b64decode(x) for x in sys.stdin.read().split()]; {}; sys.stdout.write(base64.b64encode(out).decode())",
+            body
+        );
+        // Arguments go through stdin, as they can be larger than the command line allows
+        let mut child = Command::new(python_cmd)
+            .arg("-c")
+            .arg(&script)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("failed to run python to create compressed test data");
+        let input: Vec<String> = args.iter().map(base64::encode).collect();
+        child.stdin.take().unwrap().write_all(input.join("\n").as_bytes()).unwrap();
+        let out = child.wait_with_output().expect("failed to run python to create compressed test data");
+        assert!(out.status.success(), "python script failed: {}", String::from_utf8_lossy(&out.stderr));
+        base64::decode(String::from_utf8(out.stdout).expect("python wrote non-utf8 output").trim()).expect("failed to decode base64 from python")
+    }
+
+    #[test]
+    fn small_window_inflater() {
+        use crate::Inflater;
+        let mut data = Vec::new();
+        for i in 0..4000u32 {
+            data.extend_from_slice(format!("t={} adc={}\n", i, (i * 13) % 1024).as_bytes());
+        }
+        // wbits=10: the encoder promises distances within 1 KiB (CINFO = 2)
+        let small = python_compress("c = zlib.compressobj(9, zlib.DEFLATED, 10); out = c.compress(a[0]) + c.flush()", &[&data]);
+        assert_eq!(small[0] >> 4, 2);
+        let (out, consumed) = inflate_chunked(Inflater::<1024>::new_zlib_windowed(), &small, 10, 33);
+        assert_eq!(out, data);
+        assert_eq!(consumed, small.len());
+
+        // A 32 KiB stream does not fit a 1 KiB decoder
+        let large = python_compress("out = zlib.compress(a[0], 9)", &[&data]);
+        let mut inflater = Inflater::<1024>::new_zlib_windowed();
+        let mut buf = [0u8; 64];
+        assert!(matches!(inflater.decompress(&large, &mut buf), Err(InflateError::WindowTooLarge { declared: 32768 })));
+
+        // CINFO above 7 is invalid for every decoder
+        let mut bad = large.clone();
+        bad[0] = 0x88;
+        bad[1] = (31 - ((bad[0] as u16) << 8 | (bad[1] & 0xe0) as u16) % 31) as u8 | (bad[1] & 0xe0);
+        assert!(matches!(inflate_zlib(&bad), Err(InflateError::InvalidHeader)));
+        assert!(matches!(Inflater::new_zlib().decompress(&bad, &mut buf), Err(InflateError::InvalidHeader)));
+    }
+
+    #[test]
+    fn declared_window_enforcement() {
+        use crate::Inflater;
+        // Repeats 2000 bytes apart need more than the 1 KiB the patched header declares
+        let block: Vec<u8> = (0..2000u32).map(|i| (i * 7919 % 251) as u8).collect();
+        let data = [&block[..], &block[..], &block[..]].concat();
+        let mut compressed = python_compress("out = zlib.compress(a[0], 9)", &[&data]);
+        compressed[0] = 0x28; // CINFO = 2, CM = 8
+        compressed[1] = (31 - ((compressed[0] as u16) << 8 | (compressed[1] & 0xe0) as u16) % 31) as u8 | (compressed[1] & 0xe0);
+
+        let (out, _) = inflate_chunked(Inflater::new_zlib(), &compressed, 100, 100);
+        assert_eq!(out, data);
+
+        let mut strict = Inflater::new_zlib();
+        strict.set_enforce_window(true);
+        let mut buf = vec![0u8; data.len()];
+        assert!(matches!(strict.decompress(&compressed, &mut buf), Err(InflateError::DistanceTooFarBack)));
+
+        assert_eq!(crate::inflate_zlib(&compressed).expect("decompress"), data);
+        assert_eq!(crate::inflate_zlib_enforcing_window(&compressed), Err(InflateError::DistanceTooFarBack));
+        let unpatched = python_compress("out = zlib.compress(a[0], 9)", &[&data]);
+        assert_eq!(crate::inflate_zlib_enforcing_window(&unpatched).expect("decompress"), data);
+    }
+
+    fn crc_of(data: &[u8]) -> u32 {
+        let mut crc = crate::checksum::Crc32::new();
+        crc.update(data);
+        crc.finish()
+    }
 }
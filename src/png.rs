@@ -0,0 +1,473 @@
+// PNG decoding (ISO/IEC 15948): chunks are walked with their CRCs checked, the IDAT data
+// is inflated a scanline at a time as it is needed, and the unfiltered pixels are expanded
+// to 8 bits per channel. `encode` (in write.rs) writes them.
+
+#[cfg(feature = "alloc")]
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::result::Result;
+
+use crate::checksum::Crc32;
+use crate::inflate::{InflateError, Inflater, StreamState};
+
+#[cfg(feature = "alloc")]
+mod write;
+
+#[cfg(feature = "alloc")]
+pub use write::{encode, EncodeOptions, Filter, FilterStrategy, RawImage};
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+// Passes as (first column, first row, column step, row step): the whole image at once, or
+// the seven passes of Adam7
+const NOT_INTERLACED: [(usize, usize, usize, usize); 1] = [(0, 0, 1, 1)];
+const ADAM7: [(usize, usize, usize, usize); 7] = [(0, 0, 8, 8), (4, 0, 8, 8), (0, 4, 4, 8), (2, 0, 4, 4), (0, 2, 2, 4), (1, 0, 2, 2), (0, 1, 1, 2)];
+
+/// How pixels are stored in the image data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorType {
+    /// Luminance, 1 to 16 bits
+    Gray,
+    /// Red, green, blue, 8 or 16 bits each
+    Rgb,
+    /// Index into the palette, 1 to 8 bits
+    Indexed,
+    /// Luminance and alpha, 8 or 16 bits each
+    GrayAlpha,
+    /// Red, green, blue and alpha, 8 or 16 bits each
+    Rgba,
+}
+
+impl ColorType {
+    fn from_u8(color_type: u8) -> Option<Self> {
+        match color_type {
+            0 => Some(ColorType::Gray),
+            2 => Some(ColorType::Rgb),
+            3 => Some(ColorType::Indexed),
+            4 => Some(ColorType::GrayAlpha),
+            6 => Some(ColorType::Rgba),
+            _ => None,
+        }
+    }
+
+    /// The color type number stored in IHDR.
+    pub fn to_u8(self) -> u8 {
+        match self {
+            ColorType::Gray => 0,
+            ColorType::Rgb => 2,
+            ColorType::Indexed => 3,
+            ColorType::GrayAlpha => 4,
+            ColorType::Rgba => 6,
+        }
+    }
+
+    /// Samples per pixel.
+    pub fn channels(self) -> usize {
+        match self {
+            ColorType::Gray | ColorType::Indexed => 1,
+            ColorType::GrayAlpha => 2,
+            ColorType::Rgb => 3,
+            ColorType::Rgba => 4,
+        }
+    }
+
+    fn allows_depth(self, depth: u8) -> bool {
+        match self {
+            ColorType::Gray => matches!(depth, 1 | 2 | 4 | 8 | 16),
+            ColorType::Indexed => matches!(depth, 1 | 2 | 4 | 8),
+            _ => matches!(depth, 8 | 16),
+        }
+    }
+}
+
+/// Layout of decoded pixels: 8 bits per channel, rows top to bottom with no padding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Gray,
+    GrayAlpha,
+    Rgb,
+    Rgba,
+}
+
+impl PixelFormat {
+    /// Bytes per pixel.
+    pub fn channels(self) -> usize {
+        match self {
+            PixelFormat::Gray => 1,
+            PixelFormat::GrayAlpha => 2,
+            PixelFormat::Rgb => 3,
+            PixelFormat::Rgba => 4,
+        }
+    }
+}
+
+/// What an image holds and what it decodes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PngInfo {
+    pub width: u32,
+    pub height: u32,
+    /// Bits per sample (per palette index for `Indexed`)
+    pub bit_depth: u8,
+    pub color_type: ColorType,
+    /// Adam7 interlacing
+    pub interlaced: bool,
+    /// Format of the decoded pixels: palettes are expanded to RGB, 16-bit samples cut to
+    /// their high byte, lower depths scaled up, and a tRNS chunk adds an alpha channel
+    pub format: PixelFormat,
+}
+
+impl PngInfo {
+    /// Size of the decoded image in bytes.
+    pub fn output_len(&self) -> usize {
+        self.width as usize * self.height as usize * self.format.channels()
+    }
+
+    /// Working memory `decode_into` needs: two scanlines.
+    pub fn scratch_len(&self) -> usize {
+        2 * (self.row_bytes(self.width as usize) + 1)
+    }
+
+    // Bytes of a stored scanline `width` pixels wide, without the filter byte; `parse` has
+    // checked this cannot overflow for the full width
+    fn row_bytes(&self, width: usize) -> usize {
+        (width * self.color_type.channels() * self.bit_depth as usize).div_ceil(8)
+    }
+}
+
+/// A decoded image.
+#[cfg(feature = "alloc")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+    pub pixels: Vec<u8>,
+}
+
+// What precedes the image data
+struct Parsed<'a> {
+    info: PngInfo,
+    palette: &'a [u8],
+    transparency: Option<&'a [u8]>,
+    // offset of the first IDAT chunk
+    image_data: usize,
+}
+
+struct Chunk<'a> {
+    kind: [u8; 4],
+    data: &'a [u8],
+    // offset of the next chunk
+    end: usize,
+}
+
+/// Read the header of a PNG file, and the chunks before the image data, to learn the
+/// image size and the format it decodes to.
+pub fn read_info(png: &[u8]) -> Result<PngInfo, InflateError> {
+    Ok(parse(png)?.info)
+}
+
+/// Decode a PNG file into `out`, which must hold `output_len()` bytes, using `scratch` of
+/// `scratch_len()` bytes for scanlines (both from `read_info`). No allocation; the
+/// zlib `Inflater` takes about 45 KiB of stack. Fails with `OutputOverflow` if a buffer is
+/// too short.
+pub fn decode_into(png: &[u8], out: &mut [u8], scratch: &mut [u8]) -> Result<PngInfo, InflateError> {
+    let mut inflater = Inflater::new_zlib();
+    decode_with(png, out, scratch, &mut inflater)
+}
+
+/// Decode a PNG file.
+#[cfg(feature = "alloc")]
+pub fn decode(png: &[u8]) -> Result<Image, InflateError> {
+    let info = read_info(png)?;
+    let mut pixels = vec![0; info.output_len()];
+    let mut scratch = vec![0; info.scratch_len()];
+    let mut inflater = Box::new(Inflater::new_zlib());
+    decode_with(png, &mut pixels, &mut scratch, &mut inflater)?;
+    Ok(Image { width: info.width, height: info.height, format: info.format, pixels })
+}
+
+fn decode_with(png: &[u8], out: &mut [u8], scratch: &mut [u8], inflater: &mut Inflater) -> Result<PngInfo, InflateError> {
+    let Parsed { info, palette, transparency, image_data } = parse(png)?;
+    let out = out.get_mut(..info.output_len()).ok_or(InflateError::OutputOverflow)?;
+    let (width, height) = (info.width as usize, info.height as usize);
+    let max_len = info.row_bytes(width) + 1;
+    let (mut prev, mut cur) = scratch.get_mut(..2 * max_len).ok_or(InflateError::OutputOverflow)?.split_at_mut(max_len);
+    let bpp = (info.color_type.channels() * info.bit_depth as usize).div_ceil(8);
+    let stride = width * info.format.channels();
+
+    let mut data = ImageData { png, next: image_data, input: &[], inflater };
+    let passes: &[_] = if info.interlaced { &ADAM7 } else { &NOT_INTERLACED };
+    for &(x0, y0, dx, dy) in passes {
+        // Passes with no pixels in a small image are left out entirely
+        if x0 >= width || y0 >= height {
+            continue;
+        }
+        let pass_width = (width - x0).div_ceil(dx);
+        let len = info.row_bytes(pass_width) + 1;
+        // The first row of a pass is filtered against zeros
+        prev[..len].fill(0);
+        for y in (y0..height).step_by(dy) {
+            data.read_exact(&mut cur[..len])?;
+            unfilter(&mut cur[..len], &prev[..len], bpp)?;
+            expand_row(&info, &cur[1..len], pass_width, palette, transparency, &mut out[y * stride..(y + 1) * stride], x0, dx)?;
+            core::mem::swap(&mut prev, &mut cur);
+        }
+    }
+    data.finish()?;
+    Ok(info)
+}
+
+fn parse(png: &[u8]) -> Result<Parsed<'_>, InflateError> {
+    if png.get(..8) != Some(&SIGNATURE[..]) {
+        return Err(if png.len() < 8 { InflateError::InputTooShort } else { InflateError::InvalidHeader });
+    }
+    let header = read_chunk(png, 8)?;
+    if &header.kind != b"IHDR" || header.data.len() != 13 {
+        return Err(InflateError::InvalidHeader);
+    }
+    let h = header.data;
+    let width = u32::from_be_bytes([h[0], h[1], h[2], h[3]]);
+    let height = u32::from_be_bytes([h[4], h[5], h[6], h[7]]);
+    let bit_depth = h[8];
+    let color_type = ColorType::from_u8(h[9]).ok_or(InflateError::InvalidHeader)?;
+    if width == 0 || height == 0 || width > i32::MAX as u32 || height > i32::MAX as u32 || !color_type.allows_depth(bit_depth) || h[12] > 1 {
+        return Err(InflateError::InvalidHeader);
+    }
+    // Compression and filter methods other than DEFLATE and the five scanline filters
+    if h[10] != 0 || h[11] != 0 {
+        return Err(InflateError::Unsupported);
+    }
+
+    let mut palette: &[u8] = &[];
+    let mut transparency = None;
+    let mut pos = header.end;
+    loop {
+        let chunk = read_chunk(png, pos)?;
+        match &chunk.kind {
+            b"IDAT" => break,
+            b"PLTE" => {
+                if chunk.data.is_empty() || chunk.data.len() % 3 != 0 || chunk.data.len() > 3 * 256 {
+                    return Err(InflateError::InvalidHeader);
+                }
+                palette = chunk.data;
+            }
+            b"tRNS" => transparency = Some(chunk.data),
+            b"IEND" => return Err(InflateError::InputTooShort),
+            // Unknown critical chunks (upper case first letter) cannot be skipped
+            kind if kind[0] & 0x20 == 0 => return Err(InflateError::Unsupported),
+            _ => {}
+        }
+        pos = chunk.end;
+    }
+
+    // tRNS holds one gray or RGB value that is transparent, or an alpha per palette entry
+    let transparency = match (color_type, transparency) {
+        (ColorType::Indexed, _) if palette.is_empty() => return Err(InflateError::InvalidHeader),
+        (ColorType::Gray, Some(t)) if t.len() != 2 => return Err(InflateError::InvalidHeader),
+        (ColorType::Rgb, Some(t)) if t.len() != 6 => return Err(InflateError::InvalidHeader),
+        (ColorType::Indexed, Some(t)) if t.len() > palette.len() / 3 => return Err(InflateError::InvalidHeader),
+        (ColorType::GrayAlpha | ColorType::Rgba, _) => None,
+        (_, transparency) => transparency,
+    };
+    let format = match (color_type, transparency.is_some()) {
+        (ColorType::Gray, false) => PixelFormat::Gray,
+        (ColorType::Gray, true) | (ColorType::GrayAlpha, _) => PixelFormat::GrayAlpha,
+        (ColorType::Rgb | ColorType::Indexed, false) => PixelFormat::Rgb,
+        (ColorType::Rgb | ColorType::Indexed, true) | (ColorType::Rgba, _) => PixelFormat::Rgba,
+    };
+    let info = PngInfo { width, height, bit_depth, color_type, interlaced: h[12] == 1, format };
+    // Images whose pixels or scanline buffers would not fit in the address space
+    (width as usize).checked_mul(height as usize).and_then(|n| n.checked_mul(4)).ok_or(InflateError::Unsupported)?;
+    let row_bits = (width as usize).checked_mul(color_type.channels() * bit_depth as usize).ok_or(InflateError::Unsupported)?;
+    (row_bits.div_ceil(8) + 1).checked_mul(2).ok_or(InflateError::Unsupported)?;
+    Ok(Parsed { info, palette, transparency, image_data: pos })
+}
+
+// Read the chunk at `pos`: length, type, data, then the CRC-32 of type and data
+fn read_chunk(png: &[u8], pos: usize) -> Result<Chunk<'_>, InflateError> {
+    let header = png.get(pos..pos + 8).ok_or(InflateError::InputTooShort)?;
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+    // Lengths are limited to 2^31 - 1, which also keeps the sums below in range on 32-bit targets
+    if len > i32::MAX as u32 {
+        return Err(InflateError::InvalidHeader);
+    }
+    let kind = [header[4], header[5], header[6], header[7]];
+    let end = (len as usize).checked_add(12).and_then(|n| pos.checked_add(n)).ok_or(InflateError::InputTooShort)?;
+    let stored = png.get(end - 4..end).ok_or(InflateError::InputTooShort)?;
+    let expected = u32::from_be_bytes([stored[0], stored[1], stored[2], stored[3]]);
+    let mut crc = Crc32::new();
+    crc.update(&png[pos + 4..end - 4]);
+    let actual = crc.finish();
+    if expected != actual {
+        return Err(InflateError::ChecksumMismatch { expected, actual });
+    }
+    Ok(Chunk { kind, data: &png[pos + 8..end - 4], end })
+}
+
+// The zlib stream split over consecutive IDAT chunks, inflated as scanlines are needed
+struct ImageData<'a, 'i> {
+    png: &'a [u8],
+    // offset of the next chunk
+    next: usize,
+    // rest of the current chunk's data
+    input: &'a [u8],
+    inflater: &'i mut Inflater,
+}
+
+impl ImageData<'_, '_> {
+    fn next_chunk(&mut self) -> Result<(), InflateError> {
+        let chunk = read_chunk(self.png, self.next)?;
+        if &chunk.kind != b"IDAT" {
+            return Err(InflateError::InputTooShort);
+        }
+        self.input = chunk.data;
+        self.next = chunk.end;
+        Ok(())
+    }
+
+    // Fill `buf` with the next decompressed bytes
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), InflateError> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            let status = self.inflater.decompress(self.input, &mut buf[filled..])?;
+            self.input = &self.input[status.consumed..];
+            filled += status.produced;
+            match status.state {
+                StreamState::Done if filled < buf.len() => return Err(InflateError::InputTooShort),
+                StreamState::NeedsInput if filled < buf.len() => self.next_chunk()?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Run the stream to its end, so the Adler-32 is checked; data beyond the image is ignored
+    fn finish(&mut self) -> Result<(), InflateError> {
+        let mut rest = [0u8; 64];
+        loop {
+            let status = self.inflater.decompress(self.input, &mut rest)?;
+            self.input = &self.input[status.consumed..];
+            match status.state {
+                StreamState::Done => return Ok(()),
+                StreamState::NeedsInput => self.next_chunk()?,
+                StreamState::NeedsOutput => {}
+            }
+        }
+    }
+}
+
+// Undo the scanline filter named by the row's first byte; `bpp` is the distance in bytes
+// to the corresponding byte of the previous pixel
+fn unfilter(row: &mut [u8], prev: &[u8], bpp: usize) -> Result<(), InflateError> {
+    let (filter, row) = row.split_first_mut().ok_or(InflateError::BadBlockData)?;
+    let prev = &prev[1..];
+    match *filter {
+        0 => {}
+        // Sub
+        1 => {
+            for i in bpp..row.len() {
+                row[i] = row[i].wrapping_add(row[i - bpp]);
+            }
+        }
+        // Up
+        2 => {
+            for (x, &b) in row.iter_mut().zip(prev) {
+                *x = x.wrapping_add(b);
+            }
+        }
+        // Average
+        3 => {
+            for i in 0..row.len() {
+                let a = if i >= bpp { row[i - bpp] } else { 0 };
+                row[i] = row[i].wrapping_add(((a as u16 + prev[i] as u16) / 2) as u8);
+            }
+        }
+        // Paeth
+        4 => {
+            for i in 0..row.len() {
+                let (a, c) = if i >= bpp { (row[i - bpp], prev[i - bpp]) } else { (0, 0) };
+                row[i] = row[i].wrapping_add(paeth(a, prev[i], c));
+            }
+        }
+        _ => return Err(InflateError::BadBlockData),
+    }
+    Ok(())
+}
+
+// Whichever of left, above and upper left is closest to left + above - upper left
+pub(crate) fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+// The `i`th sample of an unfiltered scanline; samples under 8 bits are packed from the
+// most significant bit
+fn sample(row: &[u8], i: usize, depth: u8) -> u16 {
+    match depth {
+        16 => u16::from_be_bytes([row[2 * i], row[2 * i + 1]]),
+        8 => row[i] as u16,
+        _ => {
+            let bit = i * depth as usize;
+            (row[bit / 8] >> (8 - depth as usize - bit % 8) & ((1u8 << depth) - 1)) as u16
+        }
+    }
+}
+
+// A sample as 8 bits: the high byte of 16-bit ones, lower depths scaled to the full range
+fn to_8bit(value: u16, depth: u8) -> u8 {
+    match depth {
+        16 => (value >> 8) as u8,
+        8 => value as u8,
+        _ => (value as u32 * 255 / ((1 << depth) - 1)) as u8,
+    }
+}
+
+// Expand an unfiltered scanline of `width` pixels into the output row, placing its pixels
+// from column `x0` every `dx` columns
+#[allow(clippy::too_many_arguments)]
+fn expand_row(info: &PngInfo, row: &[u8], width: usize, palette: &[u8], transparency: Option<&[u8]>, out: &mut [u8], x0: usize, dx: usize) -> Result<(), InflateError> {
+    let depth = info.bit_depth;
+    let channels = info.format.channels();
+    for i in 0..width {
+        let px = &mut out[(x0 + i * dx) * channels..][..channels];
+        match info.color_type {
+            ColorType::Gray => {
+                let v = sample(row, i, depth);
+                px[0] = to_8bit(v, depth);
+                if let Some(t) = transparency {
+                    px[1] = if v == u16::from_be_bytes([t[0], t[1]]) { 0 } else { 255 };
+                }
+            }
+            ColorType::Rgb => {
+                let rgb = [0, 1, 2].map(|c| sample(row, 3 * i + c, depth));
+                for (x, v) in px.iter_mut().zip(rgb) {
+                    *x = to_8bit(v, depth);
+                }
+                if let Some(t) = transparency {
+                    let key = [0, 1, 2].map(|c| u16::from_be_bytes([t[2 * c], t[2 * c + 1]]));
+                    px[3] = if rgb == key { 0 } else { 255 };
+                }
+            }
+            ColorType::Indexed => {
+                let index = sample(row, i, depth) as usize;
+                let color = palette.get(3 * index..3 * index + 3).ok_or(InflateError::BadBlockData)?;
+                px[..3].copy_from_slice(color);
+                if let Some(t) = transparency {
+                    px[3] = t.get(index).copied().unwrap_or(255);
+                }
+            }
+            ColorType::GrayAlpha | ColorType::Rgba => {
+                for (c, x) in px.iter_mut().enumerate() {
+                    *x = to_8bit(sample(row, channels * i + c, depth), depth);
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,647 @@
+// ZIP archive reading (PKWARE APPNOTE.TXT) over an archive held in memory, e.g. mapped
+// from flash, with entry data decoded by the raw DEFLATE decoder. ZIP64 archives and
+// entries followed by data descriptors are understood. `ZipWriter` (in write.rs) writes them.
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::result::Result;
+
+use crate::checksum::Crc32;
+use crate::inflate::output::{Output, SliceOutput};
+use crate::inflate::{inflate_blocks, InflateError, Inflater, StreamState};
+
+#[cfg(feature = "alloc")]
+mod write;
+
+#[cfg(feature = "std")]
+pub use write::IoSink;
+#[cfg(feature = "alloc")]
+pub use write::{FileOptions, Sink, ZipWriter};
+
+// Record signatures
+const LOCAL_HEADER_SIG: u32 = 0x0403_4b50;
+const CENTRAL_HEADER_SIG: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIR_SIG: u32 = 0x0605_4b50;
+const ZIP64_END_OF_CENTRAL_DIR_SIG: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR_SIG: u32 = 0x0706_4b50;
+const DATA_DESCRIPTOR_SIG: u32 = 0x0807_4b50;
+// Fixed-size parts of the records
+const LOCAL_HEADER_LEN: usize = 30;
+const CENTRAL_HEADER_LEN: usize = 46;
+const END_OF_CENTRAL_DIR_LEN: usize = 22;
+const ZIP64_LOCATOR_LEN: usize = 20;
+
+// General purpose flags
+const FLAG_ENCRYPTED: u16 = 0x0001;
+const FLAG_DATA_DESCRIPTOR: u16 = 0x0008;
+const FLAG_UTF8: u16 = 0x0800;
+
+// Extra field holding the 64-bit sizes and offset of ZIP64 entries
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+// Extra field holding Unix timestamps ("UT")
+const EXTENDED_TIMESTAMP_ID: u16 = 0x5455;
+// A 32-bit size or offset saying the real value is in the ZIP64 extra field
+const ZIP64_MARKER: u32 = u32::MAX;
+
+/// How an entry's data is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionMethod {
+    /// Method 0, no compression
+    Stored,
+    /// Method 8, raw DEFLATE
+    Deflated,
+    /// Any other method, which cannot be extracted
+    Other(u16),
+}
+
+impl CompressionMethod {
+    fn from_u16(method: u16) -> Self {
+        match method {
+            0 => CompressionMethod::Stored,
+            8 => CompressionMethod::Deflated,
+            other => CompressionMethod::Other(other),
+        }
+    }
+
+    /// The method number stored in the headers.
+    pub fn to_u16(self) -> u16 {
+        match self {
+            CompressionMethod::Stored => 0,
+            CompressionMethod::Deflated => 8,
+            CompressionMethod::Other(other) => other,
+        }
+    }
+}
+
+/// MS-DOS date and time as stored in the headers: local time, 2-second resolution,
+/// years 1980 to 2107.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DosDateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DosDateTime {
+    /// Split the packed date and time fields.
+    pub fn from_dos(date: u16, time: u16) -> Self {
+        DosDateTime {
+            year: 1980 + (date >> 9),
+            month: (date >> 5 & 0x0f) as u8,
+            day: (date & 0x1f) as u8,
+            hour: (time >> 11) as u8,
+            minute: (time >> 5 & 0x3f) as u8,
+            second: (time & 0x1f) as u8 * 2,
+        }
+    }
+
+    /// Pack into the `(date, time)` fields; seconds are rounded down to even.
+    pub fn to_dos(self) -> (u16, u16) {
+        let date = (self.year.saturating_sub(1980)) << 9 | (self.month as u16) << 5 | self.day as u16;
+        let time = (self.hour as u16) << 11 | (self.minute as u16) << 5 | (self.second / 2) as u16;
+        (date, time)
+    }
+}
+
+/// One entry of the central directory, or of a local header for `LocalEntries`, which
+/// have no comment, version made by or attributes. Variable-length fields borrow from the
+/// archive. Sizes and offset are the ZIP64 values where the entry has them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZipEntry<'a> {
+    /// Path within the archive, `/`-separated; UTF-8 if `is_utf8()`, else usually CP437
+    pub name: &'a [u8],
+    /// Extra field of the central directory record
+    pub extra: &'a [u8],
+    /// File comment
+    pub comment: &'a [u8],
+    /// Version made by: host system in the high byte, APPNOTE version in the low byte
+    pub version_made_by: u16,
+    /// General purpose bit flags
+    pub flags: u16,
+    pub method: CompressionMethod,
+    /// Last modification time
+    pub modified: DosDateTime,
+    /// CRC-32 of the uncompressed data
+    pub crc32: u32,
+    pub compressed_size: u64,
+    pub uncompressed_size: u64,
+    /// Host-dependent attributes; for Unix hosts the mode is in the high 16 bits
+    pub external_attributes: u32,
+    /// Offset of the entry's local header from the start of the archive
+    pub header_offset: u64,
+}
+
+impl<'a> ZipEntry<'a> {
+    /// True if the name (and comment) are flagged as UTF-8 (general purpose bit 11).
+    pub fn is_utf8(&self) -> bool {
+        self.flags & FLAG_UTF8 != 0
+    }
+
+    /// True if the entry is encrypted; such entries cannot be extracted.
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & FLAG_ENCRYPTED != 0
+    }
+
+    /// True if sizes and CRC were written after the data in a data descriptor (general
+    /// purpose bit 3), as streaming writers do.
+    pub fn has_data_descriptor(&self) -> bool {
+        self.flags & FLAG_DATA_DESCRIPTOR != 0
+    }
+
+    /// True for directory entries, whose names end in `/`.
+    pub fn is_dir(&self) -> bool {
+        self.name.last() == Some(&b'/')
+    }
+
+    /// The `(id, data)` fields of the central directory extra field.
+    pub fn extra_fields(&self) -> ExtraFields<'a> {
+        ExtraFields { data: self.extra }
+    }
+
+    /// Modification time in seconds since the Unix epoch from the extended timestamp
+    /// extra field, if present.
+    pub fn unix_mtime(&self) -> Option<u32> {
+        let (_, field) = self.extra_fields().find(|(id, _)| *id == EXTENDED_TIMESTAMP_ID)?;
+        // Flags byte, then the times it flags in order; modification time comes first
+        if field.first()? & 1 == 0 {
+            return None;
+        }
+        le32(field, 1).ok()
+    }
+}
+
+/// Iterator over the fields of an extra field, as `(header id, data)`. Stops at the first
+/// field that runs past the end.
+#[derive(Debug, Clone)]
+pub struct ExtraFields<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for ExtraFields<'a> {
+    type Item = (u16, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let id = le16(self.data, 0).ok()?;
+        let len = le16(self.data, 2).ok()? as usize;
+        let field = self.data.get(4..4 + len)?;
+        self.data = &self.data[4 + len..];
+        Some((id, field))
+    }
+}
+
+/// A ZIP archive in memory. Only the end of central directory record is read up front;
+/// entries are parsed as they are iterated.
+#[derive(Debug, Clone)]
+pub struct ZipArchive<'a> {
+    data: &'a [u8],
+    entries: u64,
+    central_dir: usize,
+    central_dir_end: usize,
+    comment: &'a [u8],
+}
+
+impl<'a> ZipArchive<'a> {
+    /// Locate the end of central directory record and the central directory of `data`,
+    /// following the ZIP64 locator if there is one. Archives split over several disks are
+    /// `Unsupported`.
+    pub fn new(data: &'a [u8]) -> Result<Self, InflateError> {
+        let eocd = find_end_of_central_dir(data)?;
+        let comment_len = le16(data, eocd + 20)? as usize;
+        let comment = data.get(eocd + END_OF_CENTRAL_DIR_LEN..eocd + END_OF_CENTRAL_DIR_LEN + comment_len).ok_or(InflateError::InputTooShort)?;
+
+        // Disk numbers, entries on this disk and in total, central directory size and offset
+        let locator = eocd.checked_sub(ZIP64_LOCATOR_LEN).filter(|&pos| matches!(le32(data, pos), Ok(ZIP64_LOCATOR_SIG)));
+        let (disks, entries_on_disk, entries, size, offset, records_start) = match locator {
+            Some(locator) => {
+                if le32(data, locator + 4)? != 0 || le32(data, locator + 16)? > 1 {
+                    return Err(InflateError::Unsupported);
+                }
+                let zip64 = to_usize(le64(data, locator + 8)?)?;
+                if zip64 > locator || le32(data, zip64)? != ZIP64_END_OF_CENTRAL_DIR_SIG {
+                    return Err(InflateError::InvalidHeader);
+                }
+                let disks = [le32(data, zip64 + 16)?, le32(data, zip64 + 20)?];
+                (disks, le64(data, zip64 + 24)?, le64(data, zip64 + 32)?, le64(data, zip64 + 40)?, le64(data, zip64 + 48)?, zip64)
+            }
+            None => {
+                let disks = [le16(data, eocd + 4)? as u32, le16(data, eocd + 6)? as u32];
+                (disks, le16(data, eocd + 8)? as u64, le16(data, eocd + 10)? as u64, le32(data, eocd + 12)? as u64, le32(data, eocd + 16)? as u64, eocd)
+            }
+        };
+        if disks != [0, 0] || entries_on_disk != entries {
+            return Err(InflateError::Unsupported);
+        }
+        let central_dir = to_usize(offset)?;
+        let central_dir_end = central_dir.checked_add(to_usize(size)?).ok_or(InflateError::InvalidHeader)?;
+        if central_dir_end > records_start {
+            return Err(InflateError::InvalidHeader);
+        }
+        Ok(ZipArchive { data, entries, central_dir, central_dir_end, comment })
+    }
+
+    /// Number of entries the end of central directory record declares.
+    pub fn len(&self) -> u64 {
+        self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    /// Archive comment.
+    pub fn comment(&self) -> &'a [u8] {
+        self.comment
+    }
+
+    /// Iterate over the central directory. A malformed record ends the iteration with
+    /// an error.
+    pub fn entries(&self) -> Entries<'a> {
+        Entries { data: self.data, pos: self.central_dir, end: self.central_dir_end, remaining: self.entries }
+    }
+
+    /// The entry called `name`, if any.
+    pub fn by_name(&self, name: &[u8]) -> Result<Option<ZipEntry<'a>>, InflateError> {
+        for entry in self.entries() {
+            let entry = entry?;
+            if entry.name == name {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The entry's data as stored, after its local header: raw DEFLATE for `Deflated`.
+    pub fn raw_data(&self, entry: &ZipEntry) -> Result<&'a [u8], InflateError> {
+        raw_data(self.data, entry)
+    }
+
+    /// Extract the entry into `out` without allocating, returning the number of bytes
+    /// written. The size and CRC-32 are checked against the central directory; fails with
+    /// `OutputOverflow` if `out` is shorter than the data.
+    pub fn extract_into(&self, entry: &ZipEntry, out: &mut [u8]) -> Result<usize, InflateError> {
+        let mut sink = SliceOutput::new(out);
+        extract_to(self.data, entry, &mut sink)?;
+        Ok(sink.written().len())
+    }
+
+    /// Extract the entry, checking its size and CRC-32 against the central directory.
+    #[cfg(feature = "alloc")]
+    pub fn extract(&self, entry: &ZipEntry) -> Result<Vec<u8>, InflateError> {
+        let mut out = Vec::new();
+        extract_to(self.data, entry, &mut out)?;
+        Ok(out)
+    }
+}
+
+// The entry's data, after the local header at its offset. The local name and extra field
+// may differ in length from the central directory's.
+fn raw_data<'a>(data: &'a [u8], entry: &ZipEntry) -> Result<&'a [u8], InflateError> {
+    let offset = to_usize(entry.header_offset)?;
+    let header = bytes(data, offset, LOCAL_HEADER_LEN)?;
+    if le32(header, 0)? != LOCAL_HEADER_SIG {
+        return Err(InflateError::InvalidHeader);
+    }
+    let name_len = le16(header, 26)? as usize;
+    let extra_len = le16(header, 28)? as usize;
+    let start = offset.checked_add(LOCAL_HEADER_LEN + name_len + extra_len).ok_or(InflateError::InputTooShort)?;
+    let end = start.checked_add(to_usize(entry.compressed_size)?).ok_or(InflateError::InputTooShort)?;
+    data.get(start..end).ok_or(InflateError::InputTooShort)
+}
+
+fn extract_to<O: Output>(data: &[u8], entry: &ZipEntry, out: &mut O) -> Result<(), InflateError> {
+    if entry.is_encrypted() {
+        return Err(InflateError::Unsupported);
+    }
+    let raw = raw_data(data, entry)?;
+    let mut crc = Crc32::new();
+    match entry.method {
+        CompressionMethod::Stored => {
+            for &b in raw {
+                out.push(b)?;
+            }
+            crc.update(raw);
+        }
+        CompressionMethod::Deflated => {
+            inflate_blocks(raw, out, |block| crc.update(block))?;
+        }
+        CompressionMethod::Other(_) => return Err(InflateError::Unsupported),
+    }
+    let actual = out.written().len() as u64;
+    if actual != entry.uncompressed_size {
+        return Err(InflateError::SizeMismatch { expected: entry.uncompressed_size, actual });
+    }
+    let actual = crc.finish();
+    if actual != entry.crc32 {
+        return Err(InflateError::ChecksumMismatch { expected: entry.crc32, actual });
+    }
+    Ok(())
+}
+
+/// Iterator over the central directory of a `ZipArchive`.
+#[derive(Debug, Clone)]
+pub struct Entries<'a> {
+    data: &'a [u8],
+    pos: usize,
+    end: usize,
+    remaining: u64,
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<ZipEntry<'a>, InflateError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let entry = parse_central_header(&self.data[..self.end], self.pos);
+        match &entry {
+            Ok((_, len)) => {
+                self.pos += len;
+                self.remaining -= 1;
+            }
+            // Nothing after a malformed record can be trusted
+            Err(_) => self.remaining = 0,
+        }
+        Some(entry.map(|(entry, _)| entry))
+    }
+}
+
+// Parse the central directory record at `pos`, returning it with its length
+fn parse_central_header(data: &[u8], pos: usize) -> Result<(ZipEntry<'_>, usize), InflateError> {
+    if le32(data, pos)? != CENTRAL_HEADER_SIG {
+        return Err(InflateError::InvalidHeader);
+    }
+    let header = bytes(data, pos, CENTRAL_HEADER_LEN)?;
+    let name_len = le16(header, 28)? as usize;
+    let extra_len = le16(header, 30)? as usize;
+    let comment_len = le16(header, 32)? as usize;
+    if le16(header, 34)? != 0 {
+        return Err(InflateError::Unsupported);
+    }
+    let name_start = pos + CENTRAL_HEADER_LEN;
+    let extra_start = name_start + name_len;
+    let comment_start = extra_start + extra_len;
+    let end = comment_start + comment_len;
+    if end > data.len() {
+        return Err(InflateError::InputTooShort);
+    }
+    let extra = &data[extra_start..comment_start];
+    let [uncompressed_size, compressed_size, header_offset] = zip64_values(extra, [le32(header, 24)?, le32(header, 20)?, le32(header, 42)?])?;
+    let entry = ZipEntry {
+        name: &data[name_start..extra_start],
+        extra,
+        comment: &data[comment_start..end],
+        version_made_by: le16(header, 4)?,
+        flags: le16(header, 8)?,
+        method: CompressionMethod::from_u16(le16(header, 10)?),
+        modified: DosDateTime::from_dos(le16(header, 14)?, le16(header, 12)?),
+        crc32: le32(header, 16)?,
+        compressed_size,
+        uncompressed_size,
+        external_attributes: le32(header, 38)?,
+        header_offset,
+    };
+    Ok((entry, end - pos))
+}
+
+// Widen the uncompressed size, compressed size and header offset, taking those stored as
+// `ZIP64_MARKER` from the ZIP64 extra field, which holds just those, in that order
+fn zip64_values<const N: usize>(extra: &[u8], values: [u32; N]) -> Result<[u64; N], InflateError> {
+    let mut wide = values.map(|v| v as u64);
+    if !values.contains(&ZIP64_MARKER) {
+        return Ok(wide);
+    }
+    let (_, field) = ExtraFields { data: extra }.find(|(id, _)| *id == ZIP64_EXTRA_ID).ok_or(InflateError::InvalidHeader)?;
+    let mut pos = 0;
+    for (value, wide) in values.iter().zip(&mut wide) {
+        if *value == ZIP64_MARKER {
+            *wide = le64(field, pos).map_err(|_| InflateError::InvalidHeader)?;
+            pos += 8;
+        }
+    }
+    Ok(wide)
+}
+
+/// Iterator over the entries of an archive by their local headers, from the start, without
+/// the central directory: for archives still arriving or whose end is missing. Entries
+/// written with a data descriptor are measured by decoding their DEFLATE data to the end of
+/// the final block (or, if stored, by finding the descriptor that matches the data), which
+/// takes an `Inflater` of about 45 KiB on the stack. Iteration stops at the central
+/// directory; a malformed header ends it with an error.
+#[derive(Debug, Clone)]
+pub struct LocalEntries<'a> {
+    data: &'a [u8],
+    pos: usize,
+    failed: bool,
+}
+
+impl<'a> LocalEntries<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        LocalEntries { data, pos: 0, failed: false }
+    }
+
+    /// The entry's data as stored.
+    pub fn raw_data(&self, entry: &ZipEntry) -> Result<&'a [u8], InflateError> {
+        raw_data(self.data, entry)
+    }
+
+    /// Extract the entry into `out` without allocating, as `ZipArchive::extract_into`.
+    pub fn extract_into(&self, entry: &ZipEntry, out: &mut [u8]) -> Result<usize, InflateError> {
+        let mut sink = SliceOutput::new(out);
+        extract_to(self.data, entry, &mut sink)?;
+        Ok(sink.written().len())
+    }
+
+    /// Extract the entry, checking its size and CRC-32.
+    #[cfg(feature = "alloc")]
+    pub fn extract(&self, entry: &ZipEntry) -> Result<Vec<u8>, InflateError> {
+        let mut out = Vec::new();
+        extract_to(self.data, entry, &mut out)?;
+        Ok(out)
+    }
+
+    // Parse the local header at `pos` and measure its data, returning the entry and the
+    // offset just past its data and descriptor
+    fn parse(&self, pos: usize) -> Result<(ZipEntry<'a>, usize), InflateError> {
+        let data = self.data;
+        let header = bytes(data, pos, LOCAL_HEADER_LEN)?;
+        let name_len = le16(header, 26)? as usize;
+        let extra_len = le16(header, 28)? as usize;
+        let name_start = pos + LOCAL_HEADER_LEN;
+        let extra_start = name_start + name_len;
+        let start = extra_start + extra_len;
+        if start > data.len() {
+            return Err(InflateError::InputTooShort);
+        }
+        let extra = &data[extra_start..start];
+        let [uncompressed_size, compressed_size] = zip64_values(extra, [le32(header, 22)?, le32(header, 18)?])?;
+        let mut entry = ZipEntry {
+            name: &data[name_start..extra_start],
+            extra,
+            comment: &[],
+            version_made_by: 0,
+            flags: le16(header, 6)?,
+            method: CompressionMethod::from_u16(le16(header, 8)?),
+            modified: DosDateTime::from_dos(le16(header, 12)?, le16(header, 10)?),
+            crc32: le32(header, 14)?,
+            compressed_size,
+            uncompressed_size,
+            external_attributes: 0,
+            header_offset: pos as u64,
+        };
+        if !entry.has_data_descriptor() {
+            let end = start.checked_add(to_usize(compressed_size)?).filter(|&end| end <= data.len()).ok_or(InflateError::InputTooShort)?;
+            return Ok((entry, end));
+        }
+
+        // Sizes were not known when the header was written; ZIP64 writers mark that with
+        // the extra field, and use 8-byte sizes in the descriptor
+        let wide = ExtraFields { data: extra }.any(|(id, _)| id == ZIP64_EXTRA_ID);
+        let descriptor = match entry.method {
+            CompressionMethod::Deflated if !entry.is_encrypted() => {
+                let len = deflate_stream_len(&data[start..])?;
+                parse_data_descriptor(data, start + len, len as u64, wide, None)
+            }
+            CompressionMethod::Stored if !entry.is_encrypted() => {
+                // The data may contain anything, so try each descriptor signature in turn
+                // until one's size and CRC describe the bytes before it
+                let mut crc = Crc32::new();
+                let mut checked = start;
+                let mut found = None;
+                while let Some(offset) = data[checked..].windows(4).position(|w| w == DATA_DESCRIPTOR_SIG.to_le_bytes()) {
+                    crc.update(&data[checked..checked + offset]);
+                    checked += offset;
+                    found = parse_data_descriptor(data, checked, (checked - start) as u64, wide, Some(crc.finish()));
+                    if found.is_some() {
+                        break;
+                    }
+                    crc.update(&data[checked..checked + 1]);
+                    checked += 1;
+                }
+                found
+            }
+            _ => return Err(InflateError::Unsupported),
+        };
+        let (crc32, compressed_size, uncompressed_size, end) = descriptor.ok_or(InflateError::InvalidHeader)?;
+        entry.crc32 = crc32;
+        entry.compressed_size = compressed_size;
+        entry.uncompressed_size = uncompressed_size;
+        Ok((entry, end))
+    }
+}
+
+impl<'a> Iterator for LocalEntries<'a> {
+    type Item = Result<ZipEntry<'a>, InflateError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.pos == self.data.len() {
+            return None;
+        }
+        match le32(self.data, self.pos) {
+            Ok(LOCAL_HEADER_SIG) => {}
+            // The central directory (or an empty archive's end record) follows the last entry
+            Ok(CENTRAL_HEADER_SIG | END_OF_CENTRAL_DIR_SIG) => return None,
+            _ => {
+                self.failed = true;
+                return Some(Err(InflateError::InvalidHeader));
+            }
+        }
+        match self.parse(self.pos) {
+            Ok((entry, end)) => {
+                self.pos = end;
+                Some(Ok(entry))
+            }
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+// Length of the raw DEFLATE stream at the start of `data`, up to the end of its final block
+fn deflate_stream_len(data: &[u8]) -> Result<usize, InflateError> {
+    let mut inflater = Inflater::new_raw();
+    let mut out = [0u8; 1024];
+    let mut pos = 0;
+    loop {
+        let status = inflater.decompress(&data[pos..], &mut out)?;
+        pos += status.consumed;
+        match status.state {
+            StreamState::Done => return Ok(pos),
+            StreamState::NeedsOutput => {}
+            StreamState::NeedsInput => return Err(InflateError::InputTooShort),
+        }
+    }
+}
+
+// The data descriptor at `pos` after `len` bytes of data: an optional signature, the CRC-32,
+// then the compressed and uncompressed sizes in 4 bytes, or 8 for ZIP64 entries. Writers
+// disagree on the signature and sometimes on the size width, so the layout whose compressed
+// size is `len` (and CRC `crc`, if known) is taken. Returns the CRC, sizes and the offset
+// past the descriptor.
+fn parse_data_descriptor(data: &[u8], pos: usize, len: u64, wide: bool, crc: Option<u32>) -> Option<(u32, u64, u64, usize)> {
+    let signed = matches!(le32(data, pos), Ok(DATA_DESCRIPTOR_SIG));
+    let starts = [pos + 4].into_iter().filter(|_| signed).chain([pos]);
+    for start in starts {
+        let stored_crc = le32(data, start).ok()?;
+        if crc.is_some_and(|crc| crc != stored_crc) {
+            continue;
+        }
+        for wide in [wide, !wide] {
+            let sizes = if wide {
+                le64(data, start + 4).and_then(|c| Ok((c, le64(data, start + 12)?, start + 20)))
+            } else {
+                le32(data, start + 4).and_then(|c| Ok((c as u64, le32(data, start + 8)? as u64, start + 12)))
+            };
+            if let Ok((compressed, uncompressed, end)) = sizes {
+                if compressed == len {
+                    return Some((stored_crc, compressed, uncompressed, end));
+                }
+            }
+        }
+    }
+    None
+}
+
+// The end of central directory record is the last thing in the archive, followed only by
+// the archive comment of up to 65535 bytes; search backwards for its signature
+fn find_end_of_central_dir(data: &[u8]) -> Result<usize, InflateError> {
+    let last = data.len().checked_sub(END_OF_CENTRAL_DIR_LEN).ok_or(InflateError::InputTooShort)?;
+    let first = last.saturating_sub(u16::MAX as usize);
+    (first..=last)
+        .rev()
+        .find(|&pos| {
+            matches!(le32(data, pos), Ok(END_OF_CENTRAL_DIR_SIG))
+                && pos + END_OF_CENTRAL_DIR_LEN + le16(data, pos + 20).unwrap_or(0) as usize <= data.len()
+        })
+        .ok_or(InflateError::InvalidHeader)
+}
+
+fn le64(data: &[u8], pos: usize) -> Result<u64, InflateError> {
+    let b = bytes(data, pos, 8)?;
+    Ok(u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+}
+
+// Offsets and sizes beyond the address space cannot lie within the archive
+fn to_usize(value: u64) -> Result<usize, InflateError> {
+    usize::try_from(value).map_err(|_| InflateError::InputTooShort)
+}
+
+fn le16(data: &[u8], pos: usize) -> Result<u16, InflateError> {
+    let b = bytes(data, pos, 2)?;
+    Ok(u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn le32(data: &[u8], pos: usize) -> Result<u32, InflateError> {
+    let b = bytes(data, pos, 4)?;
+    Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+// `len` bytes at `pos`; positions come from the archive, so the end may not be representable
+fn bytes(data: &[u8], pos: usize, len: usize) -> Result<&[u8], InflateError> {
+    pos.checked_add(len).and_then(|end| data.get(pos..end)).ok_or(InflateError::InputTooShort)
+}
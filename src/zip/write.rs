@@ -0,0 +1,401 @@
+// Streaming ZIP archive writer: local headers, deflated data and data descriptors go to the
+// sink as they are produced, and the central directory, kept in memory, goes at the end
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::Infallible;
+use core::result::Result;
+
+use super::{CompressionMethod, DosDateTime};
+use super::{CENTRAL_HEADER_SIG, DATA_DESCRIPTOR_SIG, END_OF_CENTRAL_DIR_SIG, LOCAL_HEADER_SIG, ZIP64_END_OF_CENTRAL_DIR_SIG, ZIP64_LOCATOR_SIG};
+use super::{FLAG_DATA_DESCRIPTOR, FLAG_UTF8, ZIP64_EXTRA_ID, ZIP64_MARKER};
+use crate::checksum::Crc32;
+use crate::deflate::{Deflater, Flush};
+use crate::inflate::StreamState;
+
+// Versions needed to extract: DEFLATE, and ZIP64 records
+const VERSION_DEFLATE: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+// Version made by: Unix host (so the mode in the external attributes counts), APPNOTE 6.3
+const VERSION_MADE_BY: u16 = 3 << 8 | 63;
+// Compressed output is passed to the sink in pieces of this size
+const CHUNK: usize = 4096;
+
+/// Destination of a `ZipWriter`'s output.
+pub trait Sink {
+    type Error;
+
+    /// Write all of `data`, or fail.
+    fn write_all(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+impl Sink for Vec<u8> {
+    type Error = Infallible;
+
+    fn write_all(&mut self, data: &[u8]) -> Result<(), Infallible> {
+        self.extend_from_slice(data);
+        Ok(())
+    }
+}
+
+impl<S: Sink + ?Sized> Sink for &mut S {
+    type Error = S::Error;
+
+    fn write_all(&mut self, data: &[u8]) -> Result<(), S::Error> {
+        (**self).write_all(data)
+    }
+}
+
+/// Adapter making any `std::io::Write` a `Sink` (feature "std").
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct IoSink<W>(pub W);
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write> Sink for IoSink<W> {
+    type Error = std::io::Error;
+
+    fn write_all(&mut self, data: &[u8]) -> Result<(), std::io::Error> {
+        self.0.write_all(data)
+    }
+}
+
+/// How `ZipWriter::start_file` stores an entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileOptions {
+    /// `Stored` or `Deflated`
+    pub method: CompressionMethod,
+    /// Compression level 0 ..= 9 for `Deflated`
+    pub level: u8,
+    /// Last modification time
+    pub modified: DosDateTime,
+    /// Unix file type and permissions (e.g. `0o100644`), stored in the external attributes
+    pub unix_mode: Option<u32>,
+    /// The entry may reach 4 GiB: its local header gets a ZIP64 extra field and its data
+    /// descriptor, if any, 8-byte sizes. Required for entries that large unless written by
+    /// `write_file` as `Stored`.
+    pub large_file: bool,
+}
+
+impl Default for FileOptions {
+    /// Deflated at level 6, dated 1980-01-01 00:00, no mode, under 4 GiB.
+    fn default() -> Self {
+        FileOptions {
+            method: CompressionMethod::Deflated,
+            level: 6,
+            modified: DosDateTime { year: 1980, month: 1, day: 1, hour: 0, minute: 0, second: 0 },
+            unix_mode: None,
+            large_file: false,
+        }
+    }
+}
+
+// The sink and the number of bytes written to it, which is the offset of the next record
+struct Counted<W> {
+    sink: W,
+    written: u64,
+}
+
+impl<W: Sink> Counted<W> {
+    fn emit(&mut self, data: &[u8]) -> Result<(), W::Error> {
+        self.sink.write_all(data)?;
+        self.written += data.len() as u64;
+        Ok(())
+    }
+}
+
+// The entry being written
+struct Entry {
+    name: Vec<u8>,
+    flags: u16,
+    method: CompressionMethod,
+    dos: (u16, u16),
+    external_attributes: u32,
+    large: bool,
+    header_offset: u64,
+    crc: Crc32,
+    uncompressed: u64,
+    compressed: u64,
+    // Deflated entries are compressed as they are written; stored ones are passed through
+    deflater: Option<Box<Deflater>>,
+}
+
+impl Entry {
+    // Whether the local header has a ZIP64 extra field
+    fn local_zip64(&self) -> bool {
+        self.large || self.compressed >= ZIP64_MARKER as u64 || self.uncompressed >= ZIP64_MARKER as u64
+    }
+
+    // The local header, with the CRC-32 and sizes if they are known; entries with a data
+    // descriptor leave them zero
+    fn local_header(&self, crc: Option<u32>) -> Vec<u8> {
+        let (crc, compressed, uncompressed) = match crc {
+            Some(crc) => (crc, self.compressed, self.uncompressed),
+            None => (0, 0, 0),
+        };
+        let zip64 = self.local_zip64();
+        let (date, time) = self.dos;
+        let mut header = Vec::with_capacity(super::LOCAL_HEADER_LEN + self.name.len() + 20);
+        header.extend_from_slice(&LOCAL_HEADER_SIG.to_le_bytes());
+        for field in [if zip64 { VERSION_ZIP64 } else { VERSION_DEFLATE }, self.flags, self.method.to_u16(), time, date] {
+            header.extend_from_slice(&field.to_le_bytes());
+        }
+        header.extend_from_slice(&crc.to_le_bytes());
+        // With a ZIP64 extra field both sizes are in it, and marked here
+        for size in [compressed, uncompressed] {
+            header.extend_from_slice(&(if zip64 { ZIP64_MARKER } else { size as u32 }).to_le_bytes());
+        }
+        for field in [self.name.len() as u16, if zip64 { 20 } else { 0 }] {
+            header.extend_from_slice(&field.to_le_bytes());
+        }
+        header.extend_from_slice(&self.name);
+        if zip64 {
+            header.extend_from_slice(&ZIP64_EXTRA_ID.to_le_bytes());
+            header.extend_from_slice(&16u16.to_le_bytes());
+            header.extend_from_slice(&uncompressed.to_le_bytes());
+            header.extend_from_slice(&compressed.to_le_bytes());
+        }
+        header
+    }
+
+    fn has_data_descriptor(&self) -> bool {
+        self.flags & FLAG_DATA_DESCRIPTOR != 0
+    }
+
+    // A data descriptor only has room for 4 GiB without `large_file`
+    fn check_size(&self) {
+        let fits = self.compressed < ZIP64_MARKER as u64 && self.uncompressed < ZIP64_MARKER as u64;
+        assert!(self.large || !self.has_data_descriptor() || fits, "streamed ZIP entries of 4 GiB or more need FileOptions::large_file");
+    }
+}
+
+/// Writes a ZIP archive to a `Sink` front to back, never seeking: the CRC-32 and sizes of
+/// an entry written piecewise follow its data in a data descriptor (general purpose bit 3).
+/// Stored entries given whole to `write_file` have them in the local header instead, so
+/// readers going by local headers need not search their data for the descriptor. ZIP64
+/// records are added where sizes, offsets or the entry count outgrow the classic fields.
+/// Non-ASCII names are flagged as UTF-8.
+///
+/// Only the central directory is kept in memory, about 50 bytes plus the name per entry,
+/// and a `Deflater` while a deflated entry is open.
+pub struct ZipWriter<W: Sink> {
+    out: Counted<W>,
+    entry: Option<Entry>,
+    central_dir: Vec<u8>,
+    entries: u64,
+    comment: Vec<u8>,
+    buf: Vec<u8>,
+}
+
+impl<W: Sink> ZipWriter<W> {
+    pub fn new(sink: W) -> Self {
+        ZipWriter { out: Counted { sink, written: 0 }, entry: None, central_dir: Vec::new(), entries: 0, comment: Vec::new(), buf: vec![0; CHUNK] }
+    }
+
+    /// Set the archive comment, at most 65535 bytes.
+    pub fn set_comment(&mut self, comment: &[u8]) {
+        assert!(comment.len() <= u16::MAX as usize, "ZIP archive comment too long");
+        self.comment = comment.to_vec();
+    }
+
+    /// End the current entry, if any, and start a new one called `name` (`/`-separated; a
+    /// trailing `/` makes it a directory). Its data is given to `write`.
+    ///
+    /// # Panics
+    /// If `options.method` is neither `Stored` nor `Deflated`, or `name` is longer than
+    /// 65535 bytes.
+    pub fn start_file(&mut self, name: &str, options: &FileOptions) -> Result<(), W::Error> {
+        self.start_entry(name, options, None)
+    }
+
+    // Start an entry; a stored one whose `data` is all given is written out at once with
+    // its CRC-32 and sizes in the local header
+    fn start_entry(&mut self, name: &str, options: &FileOptions, data: Option<&[u8]>) -> Result<(), W::Error> {
+        assert!(matches!(options.method, CompressionMethod::Stored | CompressionMethod::Deflated), "ZipWriter writes stored or deflated entries");
+        assert!(name.len() <= u16::MAX as usize, "ZIP entry name too long");
+        self.finish_entry()?;
+
+        let deflated = options.method == CompressionMethod::Deflated;
+        let data = data.filter(|_| !deflated);
+        let flags = if data.is_some() { 0 } else { FLAG_DATA_DESCRIPTOR } | if name.is_ascii() { 0 } else { FLAG_UTF8 };
+        let mut entry = Entry {
+            name: name.as_bytes().to_vec(),
+            flags,
+            method: options.method,
+            dos: options.modified.to_dos(),
+            external_attributes: options.unix_mode.map_or(0, |mode| mode << 16),
+            large: options.large_file,
+            header_offset: self.out.written,
+            crc: Crc32::new(),
+            uncompressed: 0,
+            compressed: 0,
+            deflater: deflated.then(|| Box::new(Deflater::new_raw(options.level))),
+        };
+        match data {
+            Some(data) => {
+                entry.crc.update(data);
+                entry.uncompressed = data.len() as u64;
+                entry.compressed = data.len() as u64;
+                self.out.emit(&entry.local_header(Some(entry.crc.finish())))?;
+                self.out.emit(data)?;
+            }
+            None => self.out.emit(&entry.local_header(None))?,
+        }
+        self.entry = Some(entry);
+        Ok(())
+    }
+
+    /// Append `data` to the current entry.
+    ///
+    /// # Panics
+    /// If no entry has been started by `start_file`, or the entry reaches 4 GiB without
+    /// `FileOptions::large_file`.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), W::Error> {
+        let entry = self.entry.as_mut().filter(|entry| entry.has_data_descriptor()).expect("ZipWriter::write outside an entry from start_file");
+        entry.crc.update(data);
+        entry.uncompressed += data.len() as u64;
+        match &mut entry.deflater {
+            Some(deflater) => deflate(deflater, data, Flush::None, &mut self.buf, &mut self.out, &mut entry.compressed)?,
+            None => {
+                entry.compressed += data.len() as u64;
+                self.out.emit(data)?;
+            }
+        }
+        entry.check_size();
+        Ok(())
+    }
+
+    /// Add an entry with all its data at once, ending it. A stored entry gets its CRC-32
+    /// and sizes in the local header and no data descriptor.
+    ///
+    /// # Panics
+    /// As `start_file`, or if a deflated entry reaches 4 GiB without
+    /// `FileOptions::large_file`.
+    pub fn write_file(&mut self, name: &str, data: &[u8], options: &FileOptions) -> Result<(), W::Error> {
+        self.start_entry(name, options, Some(data))?;
+        if options.method == CompressionMethod::Deflated {
+            self.write(data)?;
+        }
+        self.finish_entry()
+    }
+
+    /// End the last entry, write the central directory and end records, and give back the
+    /// sink.
+    pub fn finish(mut self) -> Result<W, W::Error> {
+        self.finish_entry()?;
+        let offset = self.out.written;
+        let size = self.central_dir.len() as u64;
+        self.out.emit(&self.central_dir)?;
+
+        let mut end = Vec::new();
+        if self.entries >= u16::MAX as u64 || offset >= ZIP64_MARKER as u64 || size >= ZIP64_MARKER as u64 {
+            let record_offset = self.out.written;
+            end.extend_from_slice(&ZIP64_END_OF_CENTRAL_DIR_SIG.to_le_bytes());
+            // Size of the rest of the record
+            end.extend_from_slice(&44u64.to_le_bytes());
+            for field in [VERSION_MADE_BY, VERSION_ZIP64] {
+                end.extend_from_slice(&field.to_le_bytes());
+            }
+            // This disk and the central directory's
+            end.extend_from_slice(&[0; 8]);
+            for field in [self.entries, self.entries, size, offset] {
+                end.extend_from_slice(&field.to_le_bytes());
+            }
+            end.extend_from_slice(&ZIP64_LOCATOR_SIG.to_le_bytes());
+            end.extend_from_slice(&0u32.to_le_bytes());
+            end.extend_from_slice(&record_offset.to_le_bytes());
+            end.extend_from_slice(&1u32.to_le_bytes());
+        }
+        // Values too large for the classic record are saturated, pointing readers at ZIP64
+        let entries = self.entries.min(u16::MAX as u64) as u16;
+        end.extend_from_slice(&END_OF_CENTRAL_DIR_SIG.to_le_bytes());
+        for field in [0, 0, entries, entries] {
+            end.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in [size, offset] {
+            end.extend_from_slice(&(field.min(ZIP64_MARKER as u64) as u32).to_le_bytes());
+        }
+        end.extend_from_slice(&(self.comment.len() as u16).to_le_bytes());
+        end.extend_from_slice(&self.comment);
+        self.out.emit(&end)?;
+        Ok(self.out.sink)
+    }
+
+    // Flush the entry's compressed data, write its data descriptor if it has one and add
+    // its central directory record
+    fn finish_entry(&mut self) -> Result<(), W::Error> {
+        let Some(mut entry) = self.entry.take() else {
+            return Ok(());
+        };
+        let crc = entry.crc.finish();
+        if let Some(deflater) = &mut entry.deflater {
+            deflate(deflater, &[], Flush::Finish, &mut self.buf, &mut self.out, &mut entry.compressed)?;
+        }
+        if entry.has_data_descriptor() {
+            entry.check_size();
+            let mut descriptor = Vec::with_capacity(24);
+            descriptor.extend_from_slice(&DATA_DESCRIPTOR_SIG.to_le_bytes());
+            descriptor.extend_from_slice(&crc.to_le_bytes());
+            // 8-byte sizes go with the ZIP64 extra field in the local header (APPNOTE 4.3.9.2)
+            for size in [entry.compressed, entry.uncompressed] {
+                if entry.large {
+                    descriptor.extend_from_slice(&size.to_le_bytes());
+                } else {
+                    descriptor.extend_from_slice(&(size as u32).to_le_bytes());
+                }
+            }
+            self.out.emit(&descriptor)?;
+        }
+
+        // Values that do not fit in 32 bits go to the ZIP64 extra field, in this order
+        let mut zip64 = Vec::new();
+        let [uncompressed, compressed, offset] = [entry.uncompressed, entry.compressed, entry.header_offset].map(|value| {
+            if value < ZIP64_MARKER as u64 {
+                value as u32
+            } else {
+                zip64.extend_from_slice(&value.to_le_bytes());
+                ZIP64_MARKER
+            }
+        });
+        let version_needed = if zip64.is_empty() && !entry.local_zip64() { VERSION_DEFLATE } else { VERSION_ZIP64 };
+        let extra_len = if zip64.is_empty() { 0 } else { 4 + zip64.len() as u16 };
+        let (date, time) = entry.dos;
+        let cd = &mut self.central_dir;
+        cd.extend_from_slice(&CENTRAL_HEADER_SIG.to_le_bytes());
+        for field in [VERSION_MADE_BY, version_needed, entry.flags, entry.method.to_u16(), time, date] {
+            cd.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in [crc, compressed, uncompressed] {
+            cd.extend_from_slice(&field.to_le_bytes());
+        }
+        // Name and extra field lengths, no comment, disk 0, no internal attributes
+        for field in [entry.name.len() as u16, extra_len, 0, 0, 0] {
+            cd.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in [entry.external_attributes, offset] {
+            cd.extend_from_slice(&field.to_le_bytes());
+        }
+        cd.extend_from_slice(&entry.name);
+        if !zip64.is_empty() {
+            cd.extend_from_slice(&ZIP64_EXTRA_ID.to_le_bytes());
+            cd.extend_from_slice(&(zip64.len() as u16).to_le_bytes());
+            cd.extend_from_slice(&zip64);
+        }
+        self.entries += 1;
+        Ok(())
+    }
+}
+
+// Compress `input` with the given flush, passing the output on in `buf`-sized pieces
+fn deflate<W: Sink>(deflater: &mut Deflater, mut input: &[u8], flush: Flush, buf: &mut [u8], out: &mut Counted<W>, compressed: &mut u64) -> Result<(), W::Error> {
+    loop {
+        let status = deflater.compress(input, buf, flush);
+        input = &input[status.consumed..];
+        out.emit(&buf[..status.produced])?;
+        *compressed += status.produced as u64;
+        if status.state != StreamState::NeedsOutput {
+            return Ok(());
+        }
+    }
+}